@@ -1,6 +1,5 @@
-use addr2line::Context;
 use log::LevelFilter;
-use runwind::{CacheNative, MustNotAllocateDuringUnwind, Unwinder};
+use runwind::{CacheNative, CapturedBacktrace, MustNotAllocateDuringUnwind, Symbolizer, Unwinder};
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 
 fn main() {
@@ -17,19 +16,13 @@ fn main() {
 
     let mut cache = CacheNative::new();
     let unwinder = Unwinder::<MustNotAllocateDuringUnwind>::new();
-    let mut contexts = Vec::new();
-    for obj in runwind::get_objects() {
-        let context = Context::new(obj.obj_file()).unwrap();
-        contexts.push((obj.base_addr(), obj.text_svma(), context));
-    }
-    contexts.sort_by_key(|(base_addr, _, _)| *base_addr);
 
     a(|| {
         let mut iter = unwinder.iter_frames(&mut cache);
         let mut frame_addresses = Vec::new();
         loop {
             match iter.try_next() {
-                Ok(Some(addr)) => frame_addresses.push(addr),
+                Ok(Some(addr)) => frame_addresses.push(addr as u64),
                 Ok(None) => break,
                 Err(e) => {
                     println!("{e}");
@@ -37,41 +30,18 @@ fn main() {
                 }
             }
         }
-        for addr in frame_addresses.iter().skip(1) {
+        // Symbolicate as if the backtrace had been sent to another process.
+        let backtrace = CapturedBacktrace::new(frame_addresses);
+        let symbolizer = Symbolizer::new(&backtrace.modules, &[]);
+        for &addr in &backtrace.frames {
             println!("frame: 0x{:x}", addr);
-            let addr = *addr as u64;
-            let (svma, context) =
-                match contexts.binary_search_by_key(&addr, |(base_addr, _, _)| *base_addr as u64) {
-                    Ok(_) => {
-                        println!("address shouldn't be equal to base address!");
-                        return;
-                    }
-                    Err(idx) => {
-                        if idx == 0 {
-                            println!("no module is found");
-                            return;
-                        } else {
-                            let (base_addr, text_range, context) = &contexts[idx - 1];
-                            let svma = addr as usize - base_addr;
-                            if !text_range.contains(&svma) {
-                                println!("address 0x{:x} not in text section", addr);
-                                return;
-                            }
-                            (svma, context)
-                        }
-                    }
-                };
-            let mut frames = context.find_frames(svma as u64).unwrap();
-            loop {
-                match frames.next() {
-                    Ok(Some(frame)) => {
-                        println!("{:?}", frame.function.as_ref().map(|f| f.demangle()));
-                    }
-                    Ok(None) => break,
-                    Err(e) => {
-                        println!("{e}");
+            match symbolizer.resolve(addr) {
+                Some(resolved) => {
+                    for frame in resolved.frames {
+                        println!("{:?}", frame.function);
                     }
                 }
+                None => println!("no module is found"),
             }
         }
     });
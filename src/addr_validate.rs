@@ -1,50 +1,366 @@
-use std::{cell::RefCell, mem::size_of};
+use std::{
+    cell::RefCell,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Once,
+    },
+    time::{Duration, Instant},
+};
 
 use nix::{
     errno::Errno,
-    unistd::{close, read, write},
+    unistd::{close, read},
 };
 
+#[cfg(feature = "fault-handler")]
+mod fault;
+#[cfg(target_os = "linux")]
+mod maps;
+
 thread_local! {
-    static MEM_VALIDATE_PIPE: RefCell<[i32; 2]> = RefCell::new([-1, -1]);
+    static MEM_VALIDATE_PIPE: RefCell<Pipe> = const { RefCell::new(Pipe([-1, -1])) };
+    static PAGE_CACHE: RefCell<PageCache> = const { RefCell::new(PageCache::new()) };
 }
 
-pub fn validate(addr: *const libc::c_void) -> bool {
-    const CHECK_LENGTH: usize = 2 * size_of::<*const libc::c_void>() / size_of::<u8>();
+/// The read and write ends of the pipe used by `validate`, closed when the
+/// thread exits.
+struct Pipe([i32; 2]);
+
+impl Pipe {
+    fn open(&mut self) -> nix::Result<()> {
+        self.close();
+
+        let (read_fd, write_fd) = create_pipe()?;
+        self.0 = [read_fd, write_fd];
+
+        Ok(())
+    }
 
-    // read data in the pipe
-    let valid_read = MEM_VALIDATE_PIPE.with(|pipes| {
-        let pipes = pipes.borrow();
+    fn close(&mut self) {
+        // ignore the result
+        let _ = close(self.0[0]);
+        let _ = close(self.0[1]);
+        self.0 = [-1, -1];
+    }
+
+    /// Reads back the data written by the last probe, which fails if the pipe
+    /// is not open.
+    fn drain(&self) -> bool {
         loop {
-            let mut buf = [0u8; CHECK_LENGTH];
+            let mut buf = [0u8; PROBE_LENGTH];
 
-            match read(pipes[0], &mut buf) {
+            // nix returns the `errno` of a failed read, not its `-1` result.
+            match read(self.0[0], &mut buf) {
                 Ok(bytes) => break bytes > 0,
                 Err(_err @ Errno::EINTR) => continue,
                 Err(_err @ Errno::EAGAIN) => break true,
                 Err(_) => break false,
             }
         }
-    });
-
-    if !valid_read && open_pipe().is_err() {
-        return false;
     }
 
-    MEM_VALIDATE_PIPE.with(|pipes| {
-        let pipes = pipes.borrow();
+    fn probe(&self, addr: *const libc::c_void) -> bool {
         loop {
-            let buf = unsafe { std::slice::from_raw_parts(addr as *const u8, CHECK_LENGTH) };
-
-            match write(pipes[1], buf) {
-                Ok(bytes) => break bytes > 0,
+            // `addr` may point anywhere, so hand it to the kernel as a raw pointer
+            // instead of materializing a slice over it.
+            match Errno::result(unsafe { libc::write(self.0[1], addr, PROBE_LENGTH) }) {
+                // A short write means that the probe crossed into an unreadable
+                // page.
+                Ok(bytes) => break bytes as usize == PROBE_LENGTH,
                 Err(_err @ Errno::EINTR) => continue,
                 Err(_) => break false,
             }
         }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+const PAGE_CACHE_SIZE: usize = 16;
+
+/// The readable pages seen recently by this thread, most recently used first.
+struct PageCache {
+    pages: [usize; PAGE_CACHE_SIZE],
+    len: usize,
+    cleared_at: Option<Instant>,
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        PageCache {
+            pages: [0; PAGE_CACHE_SIZE],
+            len: 0,
+            cleared_at: None,
+        }
+    }
+
+    fn clear_if_expired(&mut self, ttl: Duration) {
+        let now = Instant::now();
+        if self
+            .cleared_at
+            .is_none_or(|cleared_at| now.duration_since(cleared_at) >= ttl)
+        {
+            self.len = 0;
+            self.cleared_at = Some(now);
+        }
+    }
+
+    fn contains(&mut self, page: usize) -> bool {
+        match self.pages[..self.len].iter().position(|p| *p == page) {
+            Some(idx) => {
+                self.pages[..=idx].rotate_right(1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, page: usize) {
+        self.len = (self.len + 1).min(PAGE_CACHE_SIZE);
+        self.pages[..self.len].rotate_right(1);
+        self.pages[0] = page;
+    }
+}
+
+/// The validation cache TTL in nanoseconds.
+static PAGE_CACHE_TTL: AtomicU64 = AtomicU64::new(0);
+
+/// Sets how long the readable pages found while reading the stack are
+/// remembered. The default, `Duration::ZERO`, disables the cache.
+///
+/// A longer TTL saves syscalls, but an address on a page that is unmapped
+/// within the TTL will be read anyway and crash the process, so only enable it
+/// if stacks and code are not unmapped while unwinding.
+pub fn set_validation_cache_ttl(ttl: Duration) {
+    PAGE_CACHE_TTL.store(ttl.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// How [`LocalMemory`](crate::LocalMemory) checks that an address is readable
+/// before reading it, see [`set_validation_strategy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum ValidationStrategy {
+    /// Writes the memory to a pipe of the thread, which fails for unreadable
+    /// memory. This takes two syscalls, `write` and `read`, per page.
+    #[default]
+    Pipe,
+    /// Reads the memory with `process_vm_readv` from the own process, which
+    /// takes one syscall per page and no file descriptors. This works in
+    /// sandboxes that forbid creating pipes, e.g. with seccomp, and is
+    /// allowed without ptrace permissions, since the process reads itself.
+    #[cfg(target_os = "linux")]
+    ProcessVmReadv,
+    /// Looks the memory up in the readable mappings listed in
+    /// `/proc/self/maps`, which is read again when an address is not found or
+    /// the list is older than the validation cache TTL, see
+    /// [`set_validation_cache_ttl`], but at most every 10 ms. Most addresses
+    /// take no syscall at all.
+    ///
+    /// Reading the list allocates and takes a lock, so this must not be used
+    /// to unwind in signal handlers. Memory unmapped after the list was read
+    /// is read anyway and crashes the process, so this is unsafe for code
+    /// paths that can race with `munmap`.
+    #[cfg(target_os = "linux")]
+    Maps,
+}
+
+static VALIDATION_STRATEGY: AtomicU8 = AtomicU8::new(ValidationStrategy::Pipe as u8);
+
+/// Sets how all threads check that memory is readable before reading the
+/// stack. The default, [`ValidationStrategy::Pipe`], needs the syscalls to
+/// create a pipe for each thread that unwinds.
+///
+/// This has no effect with the `fault-handler` feature, whose handler catches
+/// the faults instead.
+pub fn set_validation_strategy(strategy: ValidationStrategy) {
+    VALIDATION_STRATEGY.store(strategy as u8, Ordering::Relaxed);
+}
+
+fn validation_strategy() -> ValidationStrategy {
+    match VALIDATION_STRATEGY.load(Ordering::Relaxed) {
+        #[cfg(target_os = "linux")]
+        strategy if strategy == ValidationStrategy::ProcessVmReadv as u8 => {
+            ValidationStrategy::ProcessVmReadv
+        }
+        #[cfg(target_os = "linux")]
+        strategy if strategy == ValidationStrategy::Maps as u8 => ValidationStrategy::Maps,
+        _ => ValidationStrategy::Pipe,
+    }
+}
+
+fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            PAGE_SIZE.store(page_size, Ordering::Relaxed);
+            page_size
+        }
+        page_size => page_size,
+    }
+}
+
+/// Checks that `len` bytes starting at `start` are readable, probing one page
+/// at a time and skipping the pages recently found readable.
+pub fn validate_range(start: *const libc::c_void, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(end) = (start as usize).checked_add(len) else {
+        return false;
+    };
+    let ttl = Duration::from_nanos(PAGE_CACHE_TTL.load(Ordering::Relaxed));
+    let page_size = page_size();
+    let first_page = start as usize & !(page_size - 1);
+
+    PAGE_CACHE.with(|cache| {
+        // The cache is in use if a signal handler interrupted this thread in the
+        // middle of `validate_range`.
+        let mut cache = match cache.try_borrow_mut() {
+            Ok(cache) if !ttl.is_zero() => cache,
+            _ => {
+                return (first_page..end)
+                    .step_by(page_size)
+                    .all(|page| validate(page as _))
+            }
+        };
+        cache.clear_if_expired(ttl);
+        (first_page..end).step_by(page_size).all(|page| {
+            if cache.contains(page) {
+                return true;
+            }
+            let valid = validate(page as _);
+            if valid {
+                cache.insert(page);
+            }
+            valid
+        })
     })
 }
 
+/// Reads the word at `addr` if it is readable.
+pub fn read_u64(addr: usize) -> Option<u64> {
+    #[cfg(feature = "fault-handler")]
+    if fault::installed() {
+        return fault::read_u64(addr);
+    }
+    if validate_range(addr as _, size_of::<u64>()) {
+        Some(unsafe { (addr as *const u64).read_unaligned() })
+    } else {
+        None
+    }
+}
+
+/// Copies the `len` bytes at `start` if they are all readable. On Linux the
+/// kernel copies them with `process_vm_readv`, so memory unmapped meanwhile
+/// makes this fail instead of crashing.
+pub(crate) fn copy_memory(start: usize, len: usize) -> Option<Box<[u8]>> {
+    let mut copy = vec![0u8; len].into_boxed_slice();
+    #[cfg(target_os = "linux")]
+    match copy_with_process_vm_readv(start, &mut copy) {
+        Ok(()) => return Some(copy),
+        Err(Errno::EFAULT) => return None,
+        // e.g. forbidden by seccomp
+        Err(_) => {}
+    }
+    if !validate_range(start as _, len) {
+        return None;
+    }
+    unsafe { std::ptr::copy_nonoverlapping(start as *const u8, copy.as_mut_ptr(), len) };
+    Some(copy)
+}
+
+#[cfg(target_os = "linux")]
+fn copy_with_process_vm_readv(start: usize, copy: &mut [u8]) -> Result<(), Errno> {
+    let mut copied = 0;
+    // A partial read stops at an unreadable page, which then fails.
+    while copied < copy.len() {
+        let local = libc::iovec {
+            iov_base: copy[copied..].as_mut_ptr() as _,
+            iov_len: copy.len() - copied,
+        };
+        let remote = libc::iovec {
+            iov_base: (start + copied) as _,
+            iov_len: copy.len() - copied,
+        };
+        let res = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        match res {
+            -1 => return Err(Errno::last()),
+            0 => return Err(Errno::EFAULT),
+            _ => copied += res as usize,
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the 8 bytes at `addr` are readable, without the page cache.
+///
+/// Memory is mapped in whole pages, so probing the start of a page validates
+/// all of it.
+pub fn validate(addr: *const libc::c_void) -> bool {
+    #[cfg(feature = "fault-handler")]
+    if fault::installed() {
+        return fault::read_u64(addr as usize).is_some();
+    }
+
+    match validation_strategy() {
+        ValidationStrategy::Pipe => validate_with_pipe(addr),
+        #[cfg(target_os = "linux")]
+        ValidationStrategy::ProcessVmReadv => validate_with_process_vm_readv(addr),
+        #[cfg(target_os = "linux")]
+        ValidationStrategy::Maps => maps::validate(
+            addr as usize,
+            PROBE_LENGTH,
+            Duration::from_nanos(PAGE_CACHE_TTL.load(Ordering::Relaxed)),
+        ),
+    }
+}
+
+const PROBE_LENGTH: usize = size_of::<u64>();
+
+fn validate_with_pipe(addr: *const libc::c_void) -> bool {
+    // The pipe of the thread is gone once its thread-local has been destroyed,
+    // e.g. when unwinding in the destructor of another thread-local, and in use
+    // when a signal handler interrupted a validation. A pipe is then created
+    // just for this probe.
+    MEM_VALIDATE_PIPE
+        .try_with(|pipe| match pipe.try_borrow_mut() {
+            Ok(mut pipe) => {
+                if !pipe.drain() && open_pipe(&mut pipe).is_err() {
+                    return false;
+                }
+                pipe.probe(addr)
+            }
+            Err(_) => validate_with_new_pipe(addr),
+        })
+        .unwrap_or_else(|_| validate_with_new_pipe(addr))
+}
+
+fn validate_with_new_pipe(addr: *const libc::c_void) -> bool {
+    let mut pipe = Pipe([-1, -1]);
+    pipe.open().is_ok() && pipe.probe(addr)
+}
+
+/// Sets up the validation of the current thread, so that signal handlers that
+/// unwind later on this thread don't allocate to do so: the pipe, whose
+/// thread-local destructor is registered on first use, and the handler that
+/// resets it after `fork`.
+#[cfg(all(feature = "sampler", target_os = "linux"))]
+pub(crate) fn init_thread() {
+    let probe = 0u64;
+    validate_with_pipe(&probe as *const u64 as *const libc::c_void);
+}
+
+#[cfg(target_os = "linux")]
+fn validate_with_process_vm_readv(addr: *const libc::c_void) -> bool {
+    copy_with_process_vm_readv(addr as usize, &mut [0u8; PROBE_LENGTH]).is_ok()
+}
+
 #[inline]
 #[cfg(target_os = "linux")]
 fn create_pipe() -> nix::Result<(i32, i32)> {
@@ -77,21 +393,27 @@ fn create_pipe() -> nix::Result<(i32, i32)> {
     Ok((read_fd, write_fd))
 }
 
-fn open_pipe() -> nix::Result<()> {
-    MEM_VALIDATE_PIPE.with(|pipes| {
-        let mut pipes = pipes.borrow_mut();
-
-        // ignore the result
-        let _ = close(pipes[0]);
-        let _ = close(pipes[1]);
-
-        let (read_fd, write_fd) = create_pipe()?;
+/// Runs in the child after `fork`. The pipe of the thread that forked is
+/// shared with the parent, whose probes would then be read by the child and
+/// vice versa, so the child closes its copies and creates its own pipe when
+/// it validates an address.
+extern "C" fn reset_pipe_after_fork() {
+    let _ = MEM_VALIDATE_PIPE.try_with(|pipes| {
+        // Borrowed if `fork` was called from a signal handler that interrupted
+        // `validate`, which will then recreate the pipe on its next failure.
+        if let Ok(mut pipes) = pipes.try_borrow_mut() {
+            pipes.close();
+        }
+    });
+}
 
-        pipes[0] = read_fd;
-        pipes[1] = write_fd;
+fn open_pipe(pipe: &mut Pipe) -> nix::Result<()> {
+    static AT_FORK: Once = Once::new();
+    AT_FORK.call_once(|| unsafe {
+        libc::pthread_atfork(None, None, Some(reset_pipe_after_fork));
+    });
 
-        Ok(())
-    })
+    pipe.open()
 }
 
 #[cfg(test)]
@@ -119,4 +441,265 @@ mod test {
         assert!(!validate(std::ptr::null::<libc::c_void>()));
         assert!(!validate(-1_i32 as usize as *const libc::c_void))
     }
+
+    #[test]
+    fn validate_heap_range() {
+        let vec = vec![0u8; 3 * page_size()];
+
+        assert!(validate_range(vec.as_ptr() as _, vec.len()));
+        // Served from the page cache this time if it is enabled.
+        assert!(validate_range(vec.as_ptr() as _, vec.len()));
+        assert!(!validate_range(std::ptr::null(), 8));
+        assert!(!validate_range(vec.as_ptr() as _, usize::MAX));
+    }
+
+    #[test]
+    fn validate_page_end() {
+        let page_size = page_size();
+        let pages = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                2 * page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(pages, libc::MAP_FAILED);
+        // Turn the second page into a guard page.
+        let guard = pages as usize + page_size;
+        assert_eq!(
+            unsafe { libc::mprotect(guard as _, page_size, libc::PROT_NONE) },
+            0
+        );
+
+        assert!(validate((guard - 8) as _));
+        assert!(!validate((guard - 4) as _));
+        assert!(!validate(guard as _));
+        assert!(validate_range((guard - 8) as _, 8));
+        assert!(!validate_range((guard - 1) as _, 2));
+        assert_eq!(read_u64(guard - 8), Some(0));
+        assert_eq!(read_u64(guard - 4), None);
+
+        unsafe { libc::munmap(pages, 2 * page_size) };
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn validate_process_vm_readv() {
+        let vec = vec![0u64; 1000];
+        assert!(vec
+            .iter()
+            .all(|i| validate_with_process_vm_readv(i as *const _ as _)));
+        assert!(!validate_with_process_vm_readv(std::ptr::null()));
+        assert!(!validate_with_process_vm_readv(usize::MAX as _));
+
+        set_validation_strategy(ValidationStrategy::ProcessVmReadv);
+        assert_eq!(validation_strategy(), ValidationStrategy::ProcessVmReadv);
+        assert!(validate(vec.as_ptr() as _));
+        set_validation_strategy(ValidationStrategy::Pipe);
+        assert_eq!(validation_strategy(), ValidationStrategy::Pipe);
+    }
+
+    /// Returns the addresses to probe in `/proc/self/maps`, each with whether
+    /// the 8 bytes at it are readable: both ends of each mapping and the start
+    /// of the gap after it.
+    #[cfg(target_os = "linux")]
+    fn probes_from_maps() -> Vec<(usize, bool)> {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let mut mappings: Vec<_> = maps
+            .lines()
+            .map(|line| {
+                let mut fields = line.split_whitespace();
+                let (start, end) = fields.next().unwrap().split_once('-').unwrap();
+                let start = usize::from_str_radix(start, 16).unwrap();
+                let end = usize::from_str_radix(end, 16).unwrap();
+                let readable = fields.next().unwrap().starts_with('r');
+                (start..end, readable, line.contains("[vvar"))
+            })
+            .collect();
+        mappings.sort_by_key(|(range, ..)| range.start);
+        let mut probes = Vec::new();
+        for (i, (range, readable, vvar)) in mappings.iter().enumerate() {
+            // The kernel may refuse to copy from the pages shared with the
+            // vDSO, which are readable in user space.
+            if !vvar {
+                probes.push((range.start, *readable));
+                probes.push((range.end - PROBE_LENGTH, *readable));
+            }
+            let next_start = mappings
+                .get(i + 1)
+                .map_or(usize::MAX, |(next, ..)| next.start);
+            if range.end < next_start {
+                probes.push((range.end, false));
+            }
+        }
+        probes
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn validate_agrees_with_maps() {
+        let before = probes_from_maps();
+        let results: Vec<_> = before
+            .iter()
+            .map(|&(addr, _)| {
+                let addr = addr as *const libc::c_void;
+                [
+                    validate(addr),
+                    validate_with_pipe(addr),
+                    validate_with_process_vm_readv(addr),
+                ]
+            })
+            .collect();
+        let after = probes_from_maps();
+        let mut checked = 0;
+        for (&(addr, readable), results) in before.iter().zip(&results) {
+            // Other tests map and unmap memory meanwhile.
+            if !after.contains(&(addr, readable)) {
+                continue;
+            }
+            assert_eq!(results, &[readable; 3], "{addr:#x}");
+            checked += 1;
+        }
+        assert!(checked > before.len() / 2);
+    }
+
+    #[test]
+    fn copy_memory_page_end() {
+        let page_size = page_size();
+        let pages = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                2 * page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(pages, libc::MAP_FAILED);
+        let guard = pages as usize + page_size;
+        unsafe { *(pages as *mut u8) = 42 };
+        assert_eq!(
+            unsafe { libc::mprotect(guard as _, page_size, libc::PROT_NONE) },
+            0
+        );
+
+        let copy = copy_memory(pages as usize, page_size).unwrap();
+        assert_eq!(copy.len(), page_size);
+        assert_eq!(copy[0], 42);
+        assert!(copy_memory(pages as usize, page_size + 1).is_none());
+        assert!(copy_memory(guard, 8).is_none());
+
+        unsafe { libc::munmap(pages, 2 * page_size) };
+    }
+
+    #[test]
+    fn pipe_reused() {
+        MEM_VALIDATE_PIPE
+            .with(|pipes| open_pipe(&mut pipes.borrow_mut()))
+            .unwrap();
+        let fds = MEM_VALIDATE_PIPE.with(|pipes| pipes.borrow().0);
+        let i = 0;
+        // The pipe is empty, so the first read fails with EAGAIN, which must
+        // not recreate the pipe.
+        assert!(validate_with_pipe(&i as *const _ as _));
+        assert!(validate_with_pipe(&i as *const _ as _));
+        assert!(!validate_with_pipe(std::ptr::null()));
+        assert_eq!(MEM_VALIDATE_PIPE.with(|pipes| pipes.borrow().0), fds);
+    }
+
+    #[test]
+    fn pipe_after_fork() {
+        let i = 0;
+        assert!(validate_with_pipe(&i as *const _ as _));
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            // Only async-signal-safe calls are allowed here.
+            let reset = MEM_VALIDATE_PIPE.with(|pipes| pipes.borrow().0) == [-1, -1];
+            let valid = validate_with_pipe(&i as *const _ as _);
+            unsafe { libc::_exit(if reset && valid { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        // The parent keeps its pipe.
+        assert_ne!(MEM_VALIDATE_PIPE.with(|pipes| pipes.borrow().0), [-1, -1]);
+    }
+
+    #[test]
+    fn pipe_closed_on_thread_exit() {
+        fn inode(fd: i32) -> Option<libc::ino_t> {
+            let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+            (unsafe { libc::fstat(fd, stat.as_mut_ptr()) } == 0)
+                .then(|| unsafe { stat.assume_init() }.st_ino)
+        }
+
+        let (fds, inodes) = std::thread::spawn(|| {
+            let i = 0;
+            assert!(validate_with_pipe(&i as *const _ as _));
+            let fds = MEM_VALIDATE_PIPE.with(|pipes| pipes.borrow().0);
+            (fds, fds.map(inode))
+        })
+        .join()
+        .unwrap();
+        assert!(inodes.iter().all(Option::is_some));
+        // The numbers may have been reused by other tests, but not for the
+        // same pipe.
+        assert_ne!(fds.map(inode), inodes);
+    }
+
+    #[test]
+    fn pipe_after_thread_local_destroyed() {
+        use std::sync::atomic::AtomicBool;
+
+        static DESTROYED: AtomicBool = AtomicBool::new(false);
+        static VALID: AtomicBool = AtomicBool::new(false);
+
+        struct ValidateOnDrop;
+
+        impl Drop for ValidateOnDrop {
+            fn drop(&mut self) {
+                let i = 0;
+                DESTROYED.store(
+                    MEM_VALIDATE_PIPE.try_with(|_| ()).is_err(),
+                    Ordering::Relaxed,
+                );
+                VALID.store(validate_with_pipe(&i as *const _ as _), Ordering::Relaxed);
+            }
+        }
+
+        thread_local! {
+            static LATE: ValidateOnDrop = const { ValidateOnDrop };
+        }
+
+        std::thread::spawn(|| {
+            // Destructors run in the reverse order of first use.
+            LATE.with(|_| ());
+            let i = 0;
+            assert!(validate_with_pipe(&i as *const _ as _));
+        })
+        .join()
+        .unwrap();
+        assert!(DESTROYED.load(Ordering::Relaxed));
+        assert!(VALID.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn page_cache_lru() {
+        let mut cache = PageCache::new();
+        for page in 0..PAGE_CACHE_SIZE + 1 {
+            cache.insert(page);
+        }
+        // The oldest page was evicted.
+        assert!(!cache.contains(0));
+        assert!(cache.contains(1));
+        cache.insert(PAGE_CACHE_SIZE + 1);
+        // 1 was used more recently than 2.
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+    }
 }
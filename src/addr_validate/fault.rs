@@ -0,0 +1,181 @@
+//! Validates reads by catching the faults they raise.
+//!
+//! Reads go through `probe_read`, whose only faulting instruction is its first
+//! one. A process-wide SIGSEGV/SIGBUS handler recognizes faults at that address
+//! and resumes at `probe_read_fault` instead, which returns `false` to the
+//! caller of `probe_read`. Other faults are passed on to the handlers that were
+//! installed before.
+//!
+//! The handler keeps no state, so probing is safe on any thread and from nested
+//! signal handlers, with two caveats:
+//! - SIGSEGV and SIGBUS must not be blocked while probing, e.g. through the
+//!   `sa_mask` of the signal handler doing the unwinding. A fault while they
+//!   are blocked kills the process.
+//! - Handlers installed later for SIGSEGV or SIGBUS must chain to this one,
+//!   otherwise probes that fault crash the process.
+
+use std::{
+    arch::naked_asm,
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use libc::{c_int, c_void, sigaction, siginfo_t, SIGBUS, SIGSEGV};
+
+const UNINSTALLED: u8 = 0;
+const INSTALLING: u8 = 1;
+const INSTALLED: u8 = 2;
+const FAILED: u8 = 3;
+
+static STATE: AtomicU8 = AtomicU8::new(UNINSTALLED);
+
+/// The handlers replaced by ours, written once before `STATE` becomes
+/// `INSTALLED`.
+struct OldActions(UnsafeCell<[MaybeUninit<sigaction>; 2]>);
+
+unsafe impl Sync for OldActions {}
+
+static OLD_ACTIONS: OldActions = OldActions(UnsafeCell::new([MaybeUninit::uninit(); 2]));
+
+/// Installs the fault handler on first use and returns whether it is in place.
+///
+/// Returns `false` while another thread is installing it, so callers must fall
+/// back to another way of validating.
+pub fn installed() -> bool {
+    match STATE.compare_exchange(
+        UNINSTALLED,
+        INSTALLING,
+        Ordering::Acquire,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => {
+            let installed = unsafe { install() };
+            STATE.store(
+                if installed { INSTALLED } else { FAILED },
+                Ordering::Release,
+            );
+            installed
+        }
+        Err(state) => state == INSTALLED,
+    }
+}
+
+unsafe fn install() -> bool {
+    let mut action: sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler as *const () as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+    let old_actions = &mut *OLD_ACTIONS.0.get();
+    for (sig, old_action) in [SIGSEGV, SIGBUS].into_iter().zip(old_actions) {
+        if libc::sigaction(sig, &action, old_action.as_mut_ptr()) != 0 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Reads the word at `addr`, or returns `None` if it is not readable. The
+/// handler must be [`installed`].
+pub fn read_u64(addr: usize) -> Option<u64> {
+    let mut value = 0;
+    if unsafe { probe_read(addr, &mut value) } {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Copies the word at `addr` to `out` and returns `true`.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn probe_read(addr: usize, out: *mut u64) -> bool {
+    naked_asm!("mov rax, [rdi]", "mov [rsi], rax", "mov eax, 1", "ret")
+}
+
+/// Returns `false` from `probe_read` when its read faults.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+unsafe extern "C" fn probe_read_fault() -> bool {
+    naked_asm!("xor eax, eax", "ret")
+}
+
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+unsafe extern "C" fn probe_read(addr: usize, out: *mut u64) -> bool {
+    naked_asm!("ldr x2, [x0]", "str x2, [x1]", "mov w0, #1", "ret")
+}
+
+#[cfg(target_arch = "aarch64")]
+#[unsafe(naked)]
+unsafe extern "C" fn probe_read_fault() -> bool {
+    naked_asm!("mov w0, #0", "ret")
+}
+
+unsafe extern "C" fn handler(sig: c_int, info: *mut siginfo_t, ucontext: *mut c_void) {
+    let pc = pc_mut(&mut *(ucontext as *mut libc::ucontext_t));
+    if *pc == probe_read as *const () as usize as _ {
+        *pc = probe_read_fault as *const () as usize as _;
+        return;
+    }
+
+    let old_action = (*OLD_ACTIONS.0.get())[(sig == SIGBUS) as usize].assume_init_ref();
+    match old_action.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // Faulting again with the old disposition terminates the process.
+            libc::sigaction(sig, old_action, std::ptr::null_mut());
+        }
+        f if old_action.sa_flags & libc::SA_SIGINFO != 0 => {
+            let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = std::mem::transmute(f);
+            f(sig, info, ucontext);
+        }
+        f => {
+            let f: extern "C" fn(c_int) = std::mem::transmute(f);
+            f(sig);
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn pc_mut(ucontext: &mut libc::ucontext_t) -> &mut i64 {
+    &mut ucontext.uc_mcontext.gregs[libc::REG_RIP as usize]
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn pc_mut(ucontext: &mut libc::ucontext_t) -> &mut u64 {
+    &mut ucontext.uc_mcontext.pc
+}
+
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+unsafe fn pc_mut(ucontext: &mut libc::ucontext_t) -> &mut u64 {
+    &mut (*ucontext.uc_mcontext).__ss.__rip
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+unsafe fn pc_mut(ucontext: &mut libc::ucontext_t) -> &mut u64 {
+    &mut (*ucontext.uc_mcontext).__ss.__pc
+}
+
+#[cfg(all(target_os = "freebsd", target_arch = "x86_64"))]
+unsafe fn pc_mut(ucontext: &mut libc::ucontext_t) -> &mut i64 {
+    &mut ucontext.uc_mcontext.mc_rip
+}
+
+#[cfg(all(target_os = "freebsd", target_arch = "aarch64"))]
+unsafe fn pc_mut(ucontext: &mut libc::ucontext_t) -> &mut i64 {
+    &mut ucontext.uc_mcontext.mc_gpregs.gp_elr
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read() {
+        assert!(installed());
+        let word = 0x1234_5678_9abc_def0u64;
+        assert_eq!(read_u64(&word as *const u64 as usize), Some(word));
+        assert_eq!(read_u64(0), None);
+        assert_eq!(read_u64(usize::MAX - 7), None);
+    }
+}
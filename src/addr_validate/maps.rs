@@ -0,0 +1,152 @@
+//! Validates reads by looking the addresses up in the readable mappings listed
+//! in `/proc/self/maps`, read once and shared by all threads.
+//!
+//! An address that is not found may be in a mapping created since the list was
+//! read, so the list is read again before failing, at most once per
+//! [`MIN_REFRESH_INTERVAL`], so that probing garbage addresses doesn't read the
+//! file each time. Mappings removed since then are only noticed once the list
+//! is older than the validation cache TTL, see
+//! [`set_validation_cache_ttl`](crate::set_validation_cache_ttl), or than
+//! [`MIN_REFRESH_INTERVAL`] if that is longer.
+//!
+//! Memory unmapped while the list is considered fresh is read anyway and
+//! crashes the process, so this is unsafe for code paths that can race with
+//! `munmap`, e.g. unwinding the stacks of exiting threads.
+
+use std::{
+    ops::Range,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+/// The minimum age of the list before it is read again.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_millis(10);
+
+static MAPS: RwLock<Maps> = RwLock::new(Maps {
+    regions: Vec::new(),
+    read_at: None,
+});
+
+struct Maps {
+    /// The readable regions, sorted, with adjacent mappings merged.
+    regions: Vec<Range<usize>>,
+    read_at: Option<Instant>,
+}
+
+impl Maps {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        self.read_at.is_some_and(|read_at| read_at.elapsed() < ttl)
+    }
+
+    fn contains(&self, range: &Range<usize>) -> bool {
+        let idx = self
+            .regions
+            .partition_point(|region| region.end <= range.start);
+        self.regions
+            .get(idx)
+            .is_some_and(|region| region.start <= range.start && range.end <= region.end)
+    }
+
+    fn refresh(&mut self) {
+        self.regions.clear();
+        self.read_at = Some(Instant::now());
+        let Ok(maps) = std::fs::read_to_string("/proc/self/maps") else {
+            return;
+        };
+        for region in maps.lines().filter_map(parse_readable) {
+            match self.regions.last_mut() {
+                Some(last) if last.end == region.start => last.end = region.end,
+                _ => self.regions.push(region),
+            }
+        }
+    }
+}
+
+/// Parses the range of a line of `/proc/<pid>/maps` if the mapping is readable.
+fn parse_readable(line: &str) -> Option<Range<usize>> {
+    // start-end perms offset dev inode [name]
+    let mut fields = line.split(' ');
+    let (start, end) = fields.next()?.split_once('-')?;
+    if !fields.next()?.starts_with('r') {
+        return None;
+    }
+    Some(usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?)
+}
+
+/// Checks that `len` bytes at `addr` are in a readable mapping.
+pub fn validate(addr: usize, len: usize, ttl: Duration) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let range = addr..end;
+    let ttl = ttl.max(MIN_REFRESH_INTERVAL);
+    {
+        let maps = MAPS.read().unwrap_or_else(|e| e.into_inner());
+        if maps.is_fresh(ttl) && maps.contains(&range) {
+            return true;
+        }
+        if maps.is_fresh(MIN_REFRESH_INTERVAL) {
+            return false;
+        }
+    }
+    let mut maps = MAPS.write().unwrap_or_else(|e| e.into_inner());
+    // Another thread may have read the list meanwhile.
+    if !maps.is_fresh(MIN_REFRESH_INTERVAL) {
+        maps.refresh();
+    }
+    maps.contains(&range)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_readable("7f0000000000-7f0000001000 r-xp 00000000 08:01 42 /usr/lib/libc.so.6"),
+            Some(0x7f0000000000..0x7f0000001000)
+        );
+        assert_eq!(parse_readable("1000-2000 ---p 00000000 00:00 0"), None);
+        assert_eq!(parse_readable("1000-2000 -w-p 00000000 00:00 0"), None);
+    }
+
+    #[test]
+    fn validate_maps() {
+        let ttl = Duration::from_secs(1);
+        let vec = vec![0u64; 1000];
+        assert!(vec.iter().all(|i| validate(i as *const _ as usize, 8, ttl)));
+        assert!(!validate(0, 8, ttl));
+        assert!(!validate(usize::MAX - 4, 8, ttl));
+
+        // A new mapping with a guard page, which is not in the list yet.
+        let page_size = super::super::page_size();
+        let pages = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                2 * page_size,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(pages, libc::MAP_FAILED);
+        std::thread::sleep(MIN_REFRESH_INTERVAL);
+        let guard = pages as usize + page_size;
+        assert_eq!(
+            unsafe { libc::mprotect(guard as _, page_size, libc::PROT_NONE) },
+            0
+        );
+        assert!(validate(guard - 8, 8, ttl));
+        assert!(!validate(guard - 4, 8, ttl));
+        assert!(!validate(guard, 8, ttl));
+
+        // Another miss right away doesn't read the list again.
+        let read_at = MAPS.read().unwrap().read_at;
+        assert!(!validate(0, 8, ttl));
+        assert_eq!(MAPS.read().unwrap().read_at, read_at);
+
+        unsafe { libc::munmap(pages, 2 * page_size) };
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    path::PathBuf,
+};
+
+use crate::object::find_object_for_addr;
+
+/// Up to `N` return addresses stored inline, innermost first, see
+/// [`Unwinder::capture_into`](crate::Unwinder::capture_into).
+///
+/// Unlike the `Vec` returned by [`Unwinder::capture`](crate::Unwinder::capture),
+/// this never allocates, so it can be captured in signal handlers and kept on
+/// the stack or in a preallocated buffer.
+#[derive(Clone, Copy)]
+pub struct Backtrace<const N: usize> {
+    frames: [usize; N],
+    len: usize,
+}
+
+impl<const N: usize> Backtrace<N> {
+    /// Creates an empty backtrace.
+    pub const fn new() -> Self {
+        Backtrace {
+            frames: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Fills the frames with `fill`, which returns how many it wrote, like
+    /// [`Unwinder::fill_backtrace`](crate::Unwinder::fill_backtrace).
+    pub(crate) fn fill(fill: impl FnOnce(&mut [usize]) -> usize) -> Self {
+        let mut backtrace = Self::new();
+        backtrace.len = fill(&mut backtrace.frames);
+        backtrace
+    }
+
+    /// Whether all `N` frames are used, i.e. the backtrace may be truncated.
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Returns a hash of the return addresses, e.g. to count identical stacks
+    /// in a map of `stack_id -> count` without keeping each stack. It does not
+    /// allocate, so it can be computed in a signal handler.
+    ///
+    /// The hash is the same for the same addresses in any process and with any
+    /// Rust version, but libraries, and executables built as PIE, are loaded at
+    /// random addresses (ASLR), so the same stack has different ids in
+    /// different runs. Use [`Backtrace::symbolic_stack_id`] to compare stacks
+    /// across runs.
+    pub fn stack_id(&self) -> u64 {
+        self.iter().fold(FNV_OFFSET_BASIS, |hash, addr| {
+            fnv1a(hash, &addr.to_le_bytes())
+        })
+    }
+
+    /// Like [`Backtrace::stack_id`], but hashes the names of the functions
+    /// instead of the addresses, including inlined functions, so that it is
+    /// stable across runs of the same build. Frames without symbols are hashed
+    /// by their object and offset in it.
+    ///
+    /// This symbolicates every frame, so it is much slower and must not be
+    /// used in signal handlers.
+    #[cfg(feature = "symbolize")]
+    pub fn symbolic_stack_id(&self) -> u64 {
+        self.iter().fold(FNV_OFFSET_BASIS, |hash, &addr| {
+            let frames = crate::symbolize::symbolicate(addr);
+            if frames.iter().any(|frame| frame.function.is_some()) {
+                frames
+                    .iter()
+                    .filter_map(|frame| frame.function.as_deref())
+                    .fold(hash, |hash, function| {
+                        // Separate the names, so that "ab" "c" differs from "a" "bc".
+                        fnv1a(fnv1a(hash, function.as_bytes()), &[0])
+                    })
+            } else {
+                match find_object_for_addr(addr.saturating_sub(1)) {
+                    Some(obj) => {
+                        let offset = (addr - obj.base_addr()) as u64;
+                        let hash = fnv1a(hash, obj.name().unwrap_or_default().as_bytes());
+                        fnv1a(fnv1a(hash, &[0]), &offset.to_le_bytes())
+                    }
+                    None => fnv1a(hash, &(addr as u64).to_le_bytes()),
+                }
+            }
+        })
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// The 64-bit FNV-1a hash, which is simple, fast and fixed, unlike the hasher
+/// of `std`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+impl<const N: usize> Default for Backtrace<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for Backtrace<N> {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+}
+
+impl<const N: usize> fmt::Debug for Backtrace<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<const N: usize, const M: usize> PartialEq<Backtrace<M>> for Backtrace<N> {
+    fn eq(&self, other: &Backtrace<M>) -> bool {
+        **self == **other
+    }
+}
+
+impl<const N: usize> Eq for Backtrace<N> {}
+
+impl<const N: usize> Hash for Backtrace<N> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<const N: usize> IntoIterator for Backtrace<N> {
+    type Item = usize;
+    type IntoIter = std::iter::Take<std::array::IntoIter<usize, N>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames.into_iter().take(self.len)
+    }
+}
+
+impl<'a, const N: usize> IntoIterator for &'a Backtrace<N> {
+    type Item = &'a usize;
+    type IntoIter = std::slice::Iter<'a, usize>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A backtrace together with the objects its frames are in, so that it can be
+/// symbolicated later or in another process.
+///
+/// With the `serde` feature, this implements `Serialize` and `Deserialize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CapturedBacktrace {
+    /// The return addresses, innermost first.
+    pub frames: Vec<u64>,
+    /// The objects containing the frames, each listed once.
+    pub modules: Vec<CapturedModule>,
+}
+
+/// An object of the process a [`CapturedBacktrace`] was taken in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CapturedModule {
+    pub path: PathBuf,
+    /// The address the object was loaded at, i.e. the AVMA minus the SVMA.
+    pub base_addr: u64,
+    /// The GNU build-id, to check that a file found for `path` is the same
+    /// object.
+    pub build_id: Option<Vec<u8>>,
+}
+
+impl CapturedBacktrace {
+    /// Records the objects of the current process containing `frames`.
+    pub fn new(frames: Vec<u64>) -> Self {
+        let mut modules: Vec<CapturedModule> = Vec::new();
+        for &addr in &frames {
+            // Return addresses may be just past the end of the text.
+            let obj = match find_object_for_addr(addr.saturating_sub(1) as usize) {
+                Some(obj) => obj,
+                None => continue,
+            };
+            let base_addr = obj.base_addr() as u64;
+            // The images of the dyld shared cache share their slide, and non-PIE
+            // executables and prelinked libraries may share the base address 0.
+            if modules
+                .iter()
+                .all(|module| module.base_addr != base_addr || module.path != obj.path())
+            {
+                modules.push(CapturedModule {
+                    path: obj.path().to_path_buf(),
+                    base_addr,
+                    build_id: obj.build_id(),
+                });
+            }
+        }
+        CapturedBacktrace { frames, modules }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MustNotAllocateDuringUnwind, Unwinder};
+
+    #[test]
+    fn stack_id() {
+        let unwinder = Unwinder::<MustNotAllocateDuringUnwind>::new();
+        let first = unwinder.capture_into::<64>();
+        let second = unwinder.capture_into::<64>();
+        // Only the return addresses into this function differ.
+        assert_eq!(first[1..], second[1..]);
+        assert_ne!(first.stack_id(), second.stack_id());
+        assert_eq!(Backtrace::<4>::new().stack_id(), FNV_OFFSET_BASIS);
+
+        #[cfg(feature = "symbolize")]
+        assert_eq!(first.symbolic_stack_id(), second.symbolic_stack_id());
+    }
+}
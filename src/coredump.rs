@@ -0,0 +1,404 @@
+//! Unwinding of the threads saved in an ELF core dump.
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io,
+    ops::Range,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use framehop::{AllocationPolicy, CacheNative, UnwindRegsNative};
+use memmap2::Mmap;
+use object::{
+    elf,
+    read::elf::{FileHeader, ProgramHeader},
+    Endianness,
+};
+
+use crate::object::Object;
+use crate::{LoadError, StackMemory, UnwindIterator, Unwinder, GREGS_LEN};
+
+/// The offset of `pr_pid` in `struct elf_prstatus`.
+const PR_PID_OFFSET: usize = 32;
+/// The offset of `pr_reg` in `struct elf_prstatus`, the same on x86_64 and
+/// aarch64.
+const PR_REG_OFFSET: usize = 112;
+
+/// A core dump of a process, with its threads, memory and mapped files.
+pub struct CoreDump {
+    mmap: Mmap,
+    /// Sorted by address.
+    segments: Vec<CoreSegment>,
+    threads: Vec<CoreThread>,
+    files: Vec<MappedFile>,
+}
+
+/// A `PT_LOAD` segment of the core, holding memory of the process.
+struct CoreSegment {
+    avma: u64,
+    mem_size: u64,
+    file_offset: usize,
+    /// The size of the data in the core. Memory past it was not dumped, e.g.
+    /// the code of mapped files.
+    file_size: usize,
+}
+
+/// A thread from an `NT_PRSTATUS` note.
+#[derive(Debug, Clone)]
+pub struct CoreThread {
+    pub tid: libc::pid_t,
+    /// The instruction pointer when the process crashed.
+    pub pc: u64,
+    pub regs: UnwindRegsNative,
+}
+
+/// A file mapping from the `NT_FILE` note.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedFile {
+    pub avma_range: Range<u64>,
+    /// The offset in the file of the start of the mapping.
+    pub file_offset: u64,
+    pub path: PathBuf,
+}
+
+impl CoreDump {
+    /// Maps and parses the core dump at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let mut core = CoreDump {
+            mmap,
+            segments: Vec::new(),
+            threads: Vec::new(),
+            files: Vec::new(),
+        };
+        core.parse()?;
+        Ok(core)
+    }
+
+    fn parse(&mut self) -> io::Result<()> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+        let data = &*self.mmap;
+        let header = elf::FileHeader64::<Endianness>::parse(data).map_err(invalid)?;
+        let endian = header.endian().map_err(invalid)?;
+        if header.e_type(endian) != elf::ET_CORE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an ELF core dump",
+            ));
+        }
+        for phdr in header.program_headers(endian, data).map_err(invalid)? {
+            match phdr.p_type(endian) {
+                elf::PT_LOAD => self.segments.push(CoreSegment {
+                    avma: phdr.p_vaddr(endian),
+                    mem_size: phdr.p_memsz(endian),
+                    file_offset: phdr.p_offset(endian) as usize,
+                    file_size: phdr.p_filesz(endian) as usize,
+                }),
+                elf::PT_NOTE => {
+                    let Some(mut notes) = phdr.notes(endian, data).map_err(invalid)? else {
+                        continue;
+                    };
+                    while let Some(note) = notes.next().map_err(invalid)? {
+                        if note.name() != b"CORE" {
+                            continue;
+                        }
+                        match note.n_type(endian) {
+                            elf::NT_PRSTATUS => self.threads.extend(parse_prstatus(note.desc())),
+                            elf::NT_FILE => {
+                                self.files = parse_nt_file(note.desc()).unwrap_or_default()
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.segments.sort_by_key(|segment| segment.avma);
+        Ok(())
+    }
+
+    /// Returns the threads, starting with the one that crashed.
+    pub fn threads(&self) -> &[CoreThread] {
+        &self.threads
+    }
+
+    /// Returns the files that were mapped in the process.
+    pub fn mapped_files(&self) -> &[MappedFile] {
+        &self.files
+    }
+
+    /// Returns the memory of the process saved in the core.
+    pub fn memory(&self) -> CoreMemory<'_> {
+        CoreMemory { core: self }
+    }
+
+    /// Creates an unwinder with the mapped files, loaded from the same paths
+    /// on this system. They must be the same files as in the crashed process.
+    ///
+    /// Also returns the files that could not be loaded, e.g. because they are
+    /// missing or are not object files.
+    pub fn unwinder<P>(&self) -> (Unwinder<P>, Vec<(PathBuf, LoadError)>)
+    where
+        P: AllocationPolicy<&'static [u8]>,
+    {
+        let mut objects = Vec::new();
+        let mut failures = Vec::new();
+        for file in self.files.iter().filter(|file| file.file_offset == 0) {
+            match Object::from_mapped_file(&file.path, file.avma_range.start as usize) {
+                Ok(obj) => objects.push(&*Box::leak(Box::new(obj))),
+                Err(e) => failures.push((file.path.clone(), e)),
+            }
+        }
+        (Unwinder::from_objects(&objects), failures)
+    }
+
+    /// Unwinds `thread`, reading its stack from the core.
+    pub fn iter_frames<'u, 'c, P>(
+        &self,
+        unwinder: &'u Unwinder<P>,
+        thread: &CoreThread,
+        cache: &'c mut CacheNative<&'static [u8], P>,
+    ) -> UnwindIterator<'u, 'c, P, CoreMemory<'_>>
+    where
+        P: AllocationPolicy<&'static [u8]>,
+    {
+        unwinder
+            .iter_frames_with_regs(thread.pc as usize, thread.regs, cache)
+            .with_memory(self.memory())
+    }
+}
+
+/// Reads the memory saved in a [`CoreDump`].
+#[derive(Clone, Copy)]
+pub struct CoreMemory<'a> {
+    core: &'a CoreDump,
+}
+
+impl StackMemory for CoreMemory<'_> {
+    fn read_u64(&mut self, addr: u64) -> Result<u64, ()> {
+        let addr = addr & !0b111;
+        let segments = &self.core.segments;
+        let i = segments.partition_point(|segment| segment.avma <= addr);
+        let segment = segments[..i].last().ok_or(())?;
+        let offset = addr - segment.avma;
+        if offset >= segment.mem_size || offset.saturating_add(8) > segment.file_size as u64 {
+            return Err(());
+        }
+        // The offsets come from the core file, which may be corrupt.
+        let start = segment.file_offset.checked_add(offset as usize).ok_or(())?;
+        let end = start.checked_add(8).ok_or(())?;
+        let bytes = self.core.mmap.get(start..end).ok_or(())?;
+        Ok(u64::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+fn parse_prstatus(desc: &[u8]) -> Option<CoreThread> {
+    let tid = desc.get(PR_PID_OFFSET..PR_PID_OFFSET + 4)?;
+    let regs = desc.get(PR_REG_OFFSET..PR_REG_OFFSET + 8 * GREGS_LEN)?;
+    let mut gregs = [0; GREGS_LEN];
+    for (reg, bytes) in gregs.iter_mut().zip(regs.chunks_exact(8)) {
+        *reg = u64::from_ne_bytes(bytes.try_into().unwrap());
+    }
+    let (pc, regs) = crate::regs_from_gregs(&gregs);
+    Some(CoreThread {
+        tid: libc::pid_t::from_ne_bytes(tid.try_into().unwrap()),
+        pc,
+        regs,
+    })
+}
+
+/// Parses the count and page size, the `(start, end, page offset)` of each
+/// mapping, then their paths.
+fn parse_nt_file(desc: &[u8]) -> Option<Vec<MappedFile>> {
+    let mut words = desc
+        .chunks_exact(8)
+        .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()));
+    let count = words.next()? as usize;
+    let page_size = words.next()?;
+    let names_start = count.checked_mul(24)?.checked_add(16)?;
+    let names = desc.get(names_start..)?.split(|b| *b == 0);
+    let mut files = Vec::new();
+    for name in names.take(count) {
+        let (start, end, page_offset) = (words.next()?, words.next()?, words.next()?);
+        files.push(MappedFile {
+            avma_range: start..end,
+            file_offset: page_offset.checked_mul(page_size)?,
+            path: PathBuf::from(OsStr::from_bytes(name)),
+        });
+    }
+    Some(files)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{MayAllocateDuringUnwind, UnwindRegsNative};
+
+    /// Appends a note with the name `CORE`.
+    fn push_note(out: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+        out.extend_from_slice(&5u32.to_ne_bytes());
+        out.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&n_type.to_ne_bytes());
+        out.extend_from_slice(b"CORE\0\0\0\0");
+        out.extend_from_slice(desc);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    fn gregs(pc: u64, regs: &UnwindRegsNative) -> [u64; GREGS_LEN] {
+        let mut gregs = [0; GREGS_LEN];
+        #[cfg(target_arch = "x86_64")]
+        {
+            (gregs[16], gregs[19], gregs[4]) = (pc, regs.sp(), regs.bp());
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            (gregs[32], gregs[31], gregs[29], gregs[30]) = (pc, regs.sp(), regs.fp(), regs.lr());
+        }
+        gregs
+    }
+
+    /// Writes a core with the current thread and its stack, in the layout of
+    /// the kernel, and unwinds the thread as it is.
+    #[inline(never)]
+    fn dump(path: &Path) -> Vec<usize> {
+        let (pc, regs) = crate::unwinder::current_regs();
+        let sp = regs.sp() as usize;
+        let stack_end = crate::current_thread_stack().unwrap().end;
+        let stack = unsafe { std::slice::from_raw_parts(sp as *const u8, stack_end - sp) }.to_vec();
+
+        let mut notes = Vec::new();
+        let mut prstatus = vec![0; PR_REG_OFFSET + 8 * GREGS_LEN + 8];
+        let tid = unsafe { libc::gettid() };
+        prstatus[PR_PID_OFFSET..PR_PID_OFFSET + 4].copy_from_slice(&tid.to_ne_bytes());
+        for (i, reg) in gregs(pc, &regs).iter().enumerate() {
+            let start = PR_REG_OFFSET + 8 * i;
+            prstatus[start..start + 8].copy_from_slice(&reg.to_ne_bytes());
+        }
+        push_note(&mut notes, elf::NT_PRSTATUS, &prstatus);
+
+        // The file mappings from /proc/self/maps, with a page size of 1.
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let mut mappings = Vec::new();
+        let mut names = Vec::new();
+        for line in maps.lines() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let Some(path) = fields.get(5).filter(|path| path.starts_with('/')) else {
+                continue;
+            };
+            let (start, end) = fields[0].split_once('-').unwrap();
+            for value in [start, end, fields[2]] {
+                mappings.extend_from_slice(&u64::from_str_radix(value, 16).unwrap().to_ne_bytes());
+            }
+            names.extend_from_slice(path.as_bytes());
+            names.push(0);
+        }
+        let mut nt_file = Vec::new();
+        nt_file.extend_from_slice(&(mappings.len() as u64 / 24).to_ne_bytes());
+        nt_file.extend_from_slice(&1u64.to_ne_bytes());
+        nt_file.extend_from_slice(&mappings);
+        nt_file.extend_from_slice(&names);
+        push_note(&mut notes, elf::NT_FILE, &nt_file);
+
+        let notes_offset = 64 + 2 * 56;
+        let stack_offset = notes_offset + notes.len();
+        let mut core = Vec::new();
+        core.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        core.extend_from_slice(&elf::ET_CORE.to_ne_bytes());
+        #[cfg(target_arch = "x86_64")]
+        core.extend_from_slice(&elf::EM_X86_64.to_ne_bytes());
+        #[cfg(target_arch = "aarch64")]
+        core.extend_from_slice(&elf::EM_AARCH64.to_ne_bytes());
+        core.extend_from_slice(&1u32.to_ne_bytes());
+        // e_entry, e_phoff, e_shoff, e_flags
+        core.extend_from_slice(&0u64.to_ne_bytes());
+        core.extend_from_slice(&64u64.to_ne_bytes());
+        core.extend_from_slice(&0u64.to_ne_bytes());
+        core.extend_from_slice(&0u32.to_ne_bytes());
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        for value in [64u16, 56, 2, 64, 0, 0] {
+            core.extend_from_slice(&value.to_ne_bytes());
+        }
+        let mut push_phdr = |p_type: u32, offset: usize, vaddr: usize, size: usize, align: u64| {
+            core.extend_from_slice(&p_type.to_ne_bytes());
+            core.extend_from_slice(&elf::PF_R.to_ne_bytes());
+            for value in [offset, vaddr, 0, size, size] {
+                core.extend_from_slice(&(value as u64).to_ne_bytes());
+            }
+            core.extend_from_slice(&align.to_ne_bytes());
+        };
+        push_phdr(elf::PT_NOTE, notes_offset, 0, notes.len(), 4);
+        push_phdr(elf::PT_LOAD, stack_offset, sp, stack.len(), 1);
+        core.extend_from_slice(&notes);
+        core.extend_from_slice(&stack);
+        File::create(path).unwrap().write_all(&core).unwrap();
+
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames_with_regs(pc as usize, regs, &mut cache);
+        let mut frames = Vec::new();
+        while let Ok(Some(addr)) = iter.try_next() {
+            frames.push(addr);
+        }
+        frames
+    }
+
+    #[test]
+    fn unwind_core() {
+        let path = std::env::temp_dir().join(format!("runwind-{}.core", std::process::id()));
+        let expected = dump(&path);
+        let core = CoreDump::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let core = core.unwrap();
+
+        assert_eq!(core.threads().len(), 1);
+        assert_eq!(core.threads()[0].tid, unsafe { libc::gettid() });
+        let exe = std::env::current_exe().unwrap();
+        assert!(core.mapped_files().iter().any(|file| file.path == exe));
+
+        let (unwinder, _) = core.unwinder::<MayAllocateDuringUnwind>();
+        assert!(unwinder
+            .modules()
+            .any(|module| module.name == exe.to_string_lossy()));
+        let mut cache = CacheNative::new();
+        let mut iter = core.iter_frames(&unwinder, &core.threads()[0], &mut cache);
+        let mut frames = Vec::new();
+        while let Ok(Some(addr)) = iter.try_next() {
+            frames.push(addr);
+        }
+        assert!(expected.len() > 1);
+        assert_eq!(frames, expected);
+
+        // Only the stack was dumped.
+        let mut memory = core.memory();
+        let sp = core.threads()[0].regs.sp();
+        assert!(memory.read_u64(sp).is_ok());
+        assert_eq!(memory.read_u64(sp - 8), Err(()));
+        assert_eq!(memory.read_u64(core.threads()[0].pc), Err(()));
+    }
+
+    #[test]
+    fn corrupt_segment_offset() {
+        let core = CoreDump {
+            mmap: memmap2::MmapMut::map_anon(64)
+                .unwrap()
+                .make_read_only()
+                .unwrap(),
+            segments: vec![CoreSegment {
+                avma: 0x1000,
+                mem_size: 0x1000,
+                file_offset: usize::MAX - 4,
+                file_size: 0x1000,
+            }],
+            threads: Vec::new(),
+            files: Vec::new(),
+        };
+        assert_eq!(core.memory().read_u64(0x1000), Err(()));
+        assert_eq!(core.memory().read_u64(0x1ff8), Err(()));
+    }
+}
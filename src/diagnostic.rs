@@ -0,0 +1,90 @@
+//! Warnings about objects that could not be loaded or parsed, sent to the `log`
+//! facade unless a handler is set with [`set_diagnostic_handler`].
+
+use std::{fmt, sync::RwLock};
+
+use log::Level;
+
+/// A message that would otherwise be logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub level: Level,
+    /// The module that emitted the message, the target of the log record.
+    pub target: &'static str,
+    pub message: String,
+}
+
+static HANDLER: RwLock<Option<fn(Diagnostic)>> = RwLock::new(None);
+
+/// Sends the diagnostics of all threads to `handler` instead of logging them,
+/// e.g. to collect the failures to load objects, or to drop them with
+/// `|_| {}`. `None` sends them to the `log` facade again.
+///
+/// The handler may be called while the dynamic loader's lock is held, so it
+/// must not load libraries or unwind with a new [`Unwinder`](crate::Unwinder).
+pub fn set_diagnostic_handler(handler: Option<fn(Diagnostic)>) {
+    *HANDLER.write().unwrap_or_else(|e| e.into_inner()) = handler;
+}
+
+pub(crate) fn emit(level: Level, target: &'static str, args: fmt::Arguments<'_>) {
+    let handler = *HANDLER.read().unwrap_or_else(|e| e.into_inner());
+    match handler {
+        Some(handler) => handler(Diagnostic {
+            level,
+            target,
+            message: args.to_string(),
+        }),
+        None => log::log!(target: target, level, "{args}"),
+    }
+}
+
+/// Like `log::warn!`, but sent to the diagnostic handler if one is set.
+macro_rules! warning {
+    ($($arg:tt)+) => {
+        $crate::diagnostic::emit(log::Level::Warn, module_path!(), format_args!($($arg)+))
+    };
+}
+
+/// Like `log::info!`, but sent to the diagnostic handler if one is set.
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::diagnostic::emit(log::Level::Info, module_path!(), format_args!($($arg)+))
+    };
+}
+
+pub(crate) use info;
+pub(crate) use warning;
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    static DIAGNOSTICS: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+
+    #[test]
+    fn handler() {
+        set_diagnostic_handler(Some(|diagnostic| {
+            DIAGNOSTICS.lock().unwrap().push(diagnostic)
+        }));
+        warning!("Failed to load {:?}", "libfoo.so");
+        set_diagnostic_handler(None);
+        warning!("Failed to load {:?}", "libbar.so");
+
+        let diagnostics = DIAGNOSTICS.lock().unwrap();
+        // Other tests may emit diagnostics meanwhile.
+        let ours: Vec<_> = diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.target == module_path!())
+            .collect();
+        assert_eq!(
+            ours,
+            [&Diagnostic {
+                level: Level::Warn,
+                target: "runwind::diagnostic::test",
+                message: "Failed to load \"libfoo.so\"".to_string(),
+            }]
+        );
+    }
+}
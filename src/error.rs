@@ -0,0 +1,108 @@
+use std::{fmt, io, path::PathBuf, sync::Arc};
+
+/// An error that stopped unwinding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnwindError {
+    /// framehop could not unwind the frame.
+    Framehop(framehop::Error),
+    /// The stack word at this address is outside of the bounds given to
+    /// [`UnwindIterator::with_stack_bounds`](crate::UnwindIterator::with_stack_bounds).
+    StackOutOfBounds(u64),
+    /// The stack word at this address could not be read, because it is not
+    /// mapped or the [`StackMemory`](crate::StackMemory) failed.
+    StackReadFailed(u64),
+    /// Unwinding returned to this address with the same stack pointer as a
+    /// recent frame, so it would loop forever on a corrupt stack.
+    Cycle(u64),
+    /// The stack pointer of the caller, this value, is not above the one of
+    /// the callee, which a corrupt frame would cause. See
+    /// [`UnwindIterator::allow_stack_switches`](crate::UnwindIterator::allow_stack_switches).
+    StackPointerNotAdvancing(u64),
+}
+
+impl fmt::Display for UnwindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnwindError::Framehop(e) => e.fmt(f),
+            UnwindError::StackOutOfBounds(addr) => {
+                write!(f, "Stack read at {addr:#x} is out of the stack bounds")
+            }
+            UnwindError::StackReadFailed(addr) => write!(f, "Could not read stack at {addr:#x}"),
+            UnwindError::Cycle(addr) => {
+                write!(f, "Unwinding returned to the frame at {addr:#x} again")
+            }
+            UnwindError::StackPointerNotAdvancing(sp) => {
+                write!(
+                    f,
+                    "Stack pointer {sp:#x} of the caller is not above the callee's"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for UnwindError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UnwindError::Framehop(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<framehop::Error> for UnwindError {
+    fn from(e: framehop::Error) -> Self {
+        UnwindError::Framehop(e)
+    }
+}
+
+/// Why an object was not fully loaded. See
+/// [`get_objects_report`](crate::get_objects_report).
+#[derive(Debug, Clone)]
+pub enum LoadError {
+    /// The file could not be opened or mapped. The object is still unwound
+    /// with the unwind information found in memory, see
+    /// [`UnwindSource`](crate::UnwindSource).
+    Map(Arc<io::Error>),
+    /// The file is not a supported object file. The object is still unwound
+    /// with the unwind information found in memory.
+    Parse(object::Error),
+    /// The text of the object overlaps that of the object at this path, so it
+    /// was skipped.
+    Overlap(PathBuf),
+    /// The file has no executable segment, so there is nothing to unwind.
+    NoText,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Map(e) => write!(f, "Could not map the file: {e}"),
+            LoadError::Parse(e) => write!(f, "Could not parse the file: {e}"),
+            LoadError::Overlap(path) => write!(f, "The text overlaps with {path:?}"),
+            LoadError::NoText => f.write_str("No executable segment"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Map(e) => Some(&**e),
+            LoadError::Parse(e) => Some(e),
+            LoadError::Overlap(_) | LoadError::NoText => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Map(Arc::new(e))
+    }
+}
+
+impl From<object::Error> for LoadError {
+    fn from(e: object::Error) -> Self {
+        LoadError::Parse(e)
+    }
+}
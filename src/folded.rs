@@ -0,0 +1,111 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+};
+
+/// Returns the stack of return addresses `frames`, innermost first, as a line
+/// of folded stacks: the function names from the outermost to the innermost
+/// one, separated by `;`, as read by `flamegraph.pl` and inferno.
+///
+/// Inlined functions get their own entries. Addresses without symbols are
+/// written in hex.
+pub fn fold_backtrace(frames: &[usize]) -> String {
+    let mut names = Vec::new();
+    for &addr in frames.iter().rev() {
+        names.extend(frame_names(addr));
+    }
+    names.join(";")
+}
+
+/// The names of the functions at `addr`, outermost first.
+fn frame_names(addr: usize) -> Vec<String> {
+    let frames = crate::symbolize::symbolicate(addr);
+    if frames.iter().all(|frame| frame.function.is_none()) {
+        return vec![format!("{addr:#x}")];
+    }
+    frames
+        .into_iter()
+        .rev()
+        .filter_map(|frame| frame.function)
+        // `;` separates the frames, but may appear in Rust types like `[u8; 4]`.
+        .map(|function| function.replace(';', ","))
+        .collect()
+}
+
+/// Counts identical stacks, to be written out in the folded stacks format
+/// once, e.g. at the end of profiling. See [`fold_backtrace`].
+#[derive(Debug, Default)]
+pub struct FoldedStacks {
+    /// The count of each folded stack, sorted so the output is stable.
+    stacks: BTreeMap<String, u64>,
+    /// The names of the addresses looked up so far.
+    names: HashMap<usize, Vec<String>>,
+}
+
+impl FoldedStacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds one occurrence of the stack `frames`, innermost first.
+    pub fn add(&mut self, frames: &[usize]) {
+        let mut names = Vec::new();
+        for &addr in frames.iter().rev() {
+            names.extend_from_slice(self.names.entry(addr).or_insert_with(|| frame_names(addr)));
+        }
+        *self.stacks.entry(names.join(";")).or_default() += 1;
+    }
+
+    /// Returns the folded stacks and their counts.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.stacks
+            .iter()
+            .map(|(stack, count)| (stack.as_str(), *count))
+    }
+
+    /// Writes one `stack count` line per distinct stack.
+    pub fn write_to(&self, mut out: impl io::Write) -> io::Result<()> {
+        write!(out, "{self}")
+    }
+}
+
+impl fmt::Display for FoldedStacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (stack, count) in self.iter() {
+            writeln!(f, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MayAllocateDuringUnwind, Unwinder};
+
+    #[test]
+    fn folded_stacks() {
+        #[inline(never)]
+        fn capture() -> Vec<usize> {
+            Unwinder::<MayAllocateDuringUnwind>::new().capture()
+        }
+
+        let frames = capture();
+        let line = fold_backtrace(&frames);
+        let innermost = line.rsplit(';').next().unwrap();
+        assert!(innermost.ends_with("folded_stacks::capture"), "{innermost}");
+        assert!(line.contains("folded::test::folded_stacks;"), "{line}");
+
+        let mut stacks = FoldedStacks::new();
+        stacks.add(&frames);
+        stacks.add(&frames);
+        stacks.add(&[1]);
+        assert_eq!(
+            stacks.iter().collect::<Vec<_>>(),
+            [("0x1", 1), (line.as_str(), 2)]
+        );
+        let mut out = Vec::new();
+        stacks.write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), stacks.to_string());
+    }
+}
@@ -0,0 +1,484 @@
+//! Code generated at runtime, e.g. by JIT compilers, which is not part of any
+//! loaded object. Its unwind information has to be registered with
+//! [`Unwinder::add_jit_region`](crate::Unwinder::add_jit_region), and its
+//! symbols can be read from the files runtimes write for perf, see
+//! [`JitSymbols`].
+
+use std::{fs, io, ops::Range, path::Path};
+
+/// How to unwind the frames of a region of generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitUnwindInfo {
+    /// The generated functions keep a frame pointer chain, like the code
+    /// compiled with `-C force-frame-pointers=yes`.
+    FramePointer,
+    /// DWARF CFI in the `.eh_frame` format, as emitted by JIT compilers for
+    /// `__register_frame`. The section is read where it lies in memory, so
+    /// absolute and `pcrel` pointers in it must be actual addresses.
+    EhFrame(&'static [u8]),
+    /// At every instruction of the region, the return address is at
+    /// `sp + size` and the caller's stack pointer is right above it, as in
+    /// code that allocates a frame of `size` bytes on entry after a `call`.
+    /// The frame pointer is left as it is.
+    FixedFrame { size: u64 },
+}
+
+/// An executable mapping of the process that is not the text of a loaded
+/// object, see [`find_jit_regions`].
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitRegion {
+    pub avma: Range<usize>,
+    /// Whether the mapping is also writable. Runtimes enforcing W^X only make
+    /// code writable while emitting it, or map it twice.
+    pub writable: bool,
+    /// The path or name of the mapping in `/proc/self/maps`, e.g.
+    /// `[anon:v8]` or `/memfd:jit (deleted)`, if it has one.
+    pub name: Option<String>,
+}
+
+/// Returns the executable mappings of the current process that don't belong to
+/// any object from [`get_objects`](crate::get_objects), which usually hold
+/// generated code.
+#[cfg(target_os = "linux")]
+pub fn find_jit_regions() -> io::Result<Vec<JitRegion>> {
+    let maps = std::fs::read_to_string("/proc/self/maps")?;
+    Ok(maps
+        .lines()
+        .filter_map(parse_maps_line)
+        .filter(|region| crate::object::find_object_for_addr(region.avma.start).is_none())
+        .collect())
+}
+
+/// Parses an executable mapping from a line of `/proc/<pid>/maps`.
+#[cfg(target_os = "linux")]
+fn parse_maps_line(line: &str) -> Option<JitRegion> {
+    // start-end perms offset dev inode [name]
+    let mut fields = line.splitn(6, ' ');
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?.as_bytes();
+    if perms.get(2) != Some(&b'x') {
+        return None;
+    }
+    let name = fields
+        .nth(3)
+        .map(str::trim_start)
+        .filter(|name| !name.is_empty());
+    // The kernel's own code, which is not generated.
+    if matches!(name, Some("[vdso]" | "[vsyscall]" | "[uprobes]")) {
+        return None;
+    }
+    Some(JitRegion {
+        avma: usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?,
+        writable: perms.get(1) == Some(&b'w'),
+        name: name.map(str::to_string),
+    })
+}
+
+/// The names of generated functions, read from the files JIT compilers write
+/// for `perf`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JitSymbols {
+    /// Sorted by address and not overlapping.
+    symbols: Vec<JitSymbol>,
+}
+
+/// A generated function, see [`JitSymbols`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitSymbol {
+    pub avma: Range<usize>,
+    pub name: String,
+}
+
+/// The magic number at the start of a jitdump file, in its byte order.
+const JITDUMP_MAGIC: u32 = 0x4a69_5444;
+/// The record of a function whose code was emitted.
+const JIT_CODE_LOAD: u32 = 0;
+/// The record of a function whose code was moved.
+const JIT_CODE_MOVE: u32 = 1;
+
+impl JitSymbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the perf map of the current process, `/tmp/perf-<pid>.map`.
+    pub fn for_current_process() -> io::Result<Self> {
+        let mut symbols = Self::new();
+        symbols.add_perf_map(format!("/tmp/perf-{}.map", std::process::id()))?;
+        Ok(symbols)
+    }
+
+    /// Adds the functions of a perf map, with a `START SIZE name` line per
+    /// function and the addresses in hex. Invalid lines are skipped.
+    pub fn add_perf_map(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let map = fs::read_to_string(path)?;
+        for line in map.lines() {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(start), Some(size), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let parse = |hex: &str| usize::from_str_radix(hex.trim_start_matches("0x"), 16).ok();
+            if let (Some(start), Some(size)) = (parse(start), parse(size)) {
+                if let Some(end) = start.checked_add(size) {
+                    self.add(start..end, name.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the functions of a jitdump file, usually named `jit-<pid>.dump`,
+    /// from its code load and code move records. Fails if the file is not a
+    /// jitdump file of the native byte order.
+    pub fn add_jitdump(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid jitdump file");
+        let u32_at = |offset: usize| -> Option<u32> {
+            Some(u32::from_ne_bytes(
+                data.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+        let u64_at = |offset: usize| -> Option<u64> {
+            Some(u64::from_ne_bytes(
+                data.get(offset..offset + 8)?.try_into().ok()?,
+            ))
+        };
+        if u32_at(0) != Some(JITDUMP_MAGIC) {
+            return Err(invalid());
+        }
+        // The header starts with its magic, version and size.
+        let mut offset = u32_at(8).ok_or_else(invalid)? as usize;
+        // Each record starts with its id, size and timestamp. A record cut off
+        // by a crash of the runtime ends the file.
+        while let (Some(id), Some(size)) = (u32_at(offset), u32_at(offset + 4)) {
+            let body = offset + 16;
+            match id {
+                JIT_CODE_LOAD => {
+                    // pid, tid, vma, code_addr, code_size, code_index, name
+                    let (Some(addr), Some(size)) = (u64_at(body + 16), u64_at(body + 24)) else {
+                        break;
+                    };
+                    let name = data.get(body + 40..).and_then(|name| {
+                        let len = name.iter().position(|&b| b == 0)?;
+                        Some(String::from_utf8_lossy(&name[..len]).into_owned())
+                    });
+                    if let (Some(name), Some(end)) = (name, addr.checked_add(size)) {
+                        self.add(addr as usize..end as usize, name);
+                    }
+                }
+                JIT_CODE_MOVE => {
+                    // pid, tid, vma, old_code_addr, new_code_addr, code_size
+                    let (Some(old), Some(new), Some(size)) =
+                        (u64_at(body + 16), u64_at(body + 24), u64_at(body + 32))
+                    else {
+                        break;
+                    };
+                    let name = self.lookup(old as usize).map(str::to_string);
+                    if let (Some(name), Some(end)) = (name, new.checked_add(size)) {
+                        self.add(new as usize..end as usize, name);
+                    }
+                }
+                _ => {}
+            }
+            if size < 16 {
+                break;
+            }
+            offset += size as usize;
+        }
+        Ok(())
+    }
+
+    /// Adds a function, replacing those it overlaps, whose code was freed
+    /// before `avma` was reused.
+    pub fn add(&mut self, avma: Range<usize>, name: String) {
+        if avma.is_empty() {
+            return;
+        }
+        self.symbols
+            .retain(|symbol| symbol.avma.end <= avma.start || avma.end <= symbol.avma.start);
+        let i = self
+            .symbols
+            .partition_point(|symbol| symbol.avma.start < avma.start);
+        self.symbols.insert(i, JitSymbol { avma, name });
+    }
+
+    /// Returns the name of the function containing `avma`.
+    pub fn lookup(&self, avma: usize) -> Option<&str> {
+        let i = self
+            .symbols
+            .partition_point(|symbol| symbol.avma.start <= avma);
+        let symbol = &self.symbols[i.checked_sub(1)?];
+        symbol.avma.contains(&avma).then_some(symbol.name.as_str())
+    }
+
+    /// Returns the functions sorted by address.
+    pub fn iter(&self) -> impl Iterator<Item = &JitSymbol> {
+        self.symbols.iter()
+    }
+}
+
+/// The symbols consulted by symbolication for addresses outside of objects.
+#[cfg(feature = "symbolize")]
+static JIT_SYMBOLS: std::sync::RwLock<Option<JitSymbols>> = std::sync::RwLock::new(None);
+
+/// Sets the names of generated functions, used by
+/// [`Unwinder::symbolicate`](crate::Unwinder::symbolicate) and the other
+/// symbolication functions for addresses outside of the loaded objects.
+/// Replaces the symbols set before, so call it again after the runtime
+/// appended to its files.
+#[cfg(feature = "symbolize")]
+pub fn set_jit_symbols(symbols: JitSymbols) {
+    *JIT_SYMBOLS.write().unwrap_or_else(|e| e.into_inner()) = Some(symbols);
+}
+
+/// Looks up `avma` in the symbols set with [`set_jit_symbols`].
+#[cfg(feature = "symbolize")]
+pub(crate) fn lookup_jit_symbol(avma: usize) -> Option<String> {
+    let symbols = JIT_SYMBOLS.read().unwrap_or_else(|e| e.into_inner());
+    Some(symbols.as_ref()?.lookup(avma)?.to_string())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_line() {
+        assert_eq!(
+            parse_maps_line("7f0000000000-7f0000001000 rwxp 00000000 00:00 0 "),
+            Some(JitRegion {
+                avma: 0x7f0000000000..0x7f0000001000,
+                writable: true,
+                name: None,
+            })
+        );
+        assert_eq!(
+            parse_maps_line("1000-2000 r-xs 00000000 00:01 42     /memfd:jit (deleted)")
+                .unwrap()
+                .name
+                .as_deref(),
+            Some("/memfd:jit (deleted)")
+        );
+        assert_eq!(parse_maps_line("1000-2000 rw-p 00000000 00:00 0"), None);
+        assert_eq!(
+            parse_maps_line("ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0 [vsyscall]"),
+            None
+        );
+    }
+
+    /// `sub rsp, 8; mov rax, rdi; mov rdi, rsi; call rax; add rsp, 8; ret`,
+    /// which calls its first argument with its second one without a frame
+    /// pointer.
+    #[cfg(target_arch = "x86_64")]
+    const TRAMPOLINE: &[u8] = &[
+        0x48, 0x83, 0xec, 0x08, 0x48, 0x89, 0xf8, 0x48, 0x89, 0xf7, 0xff, 0xd0, 0x48, 0x83, 0xc4,
+        0x08, 0xc3,
+    ];
+
+    /// The `.eh_frame` of [`TRAMPOLINE`] at `code`.
+    #[cfg(target_arch = "x86_64")]
+    fn trampoline_eh_frame(code: usize) -> Vec<u8> {
+        // Version 1, no augmentation, code alignment 1, data alignment -8,
+        // return address in r16. The CFA is rsp + 8 and the return address is
+        // at CFA - 8.
+        let cie = [1, 0, 1, 0x78, 16, 0x0c, 7, 8, 0x90, 1, 0, 0, 0, 0, 0, 0];
+        let mut eh_frame = Vec::new();
+        eh_frame.extend_from_slice(&(4 + cie.len() as u32).to_ne_bytes());
+        eh_frame.extend_from_slice(&0u32.to_ne_bytes());
+        eh_frame.extend_from_slice(&cie);
+
+        let fde_start = eh_frame.len();
+        eh_frame.extend_from_slice(&0u32.to_ne_bytes());
+        // The distance back to the CIE.
+        eh_frame.extend_from_slice(&(eh_frame.len() as u32).to_ne_bytes());
+        eh_frame.extend_from_slice(&(code as u64).to_ne_bytes());
+        eh_frame.extend_from_slice(&(TRAMPOLINE.len() as u64).to_ne_bytes());
+        // After `sub rsp, 8` the CFA is rsp + 16, after `add rsp, 8` rsp + 8.
+        eh_frame.extend_from_slice(&[0x44, 0x0e, 16, 0x4c, 0x0e, 8, 0, 0]);
+        let fde_len = (eh_frame.len() - fde_start - 4) as u32;
+        eh_frame[fde_start..fde_start + 4].copy_from_slice(&fde_len.to_ne_bytes());
+        // The terminator.
+        eh_frame.extend_from_slice(&0u32.to_ne_bytes());
+        eh_frame
+    }
+
+    /// Calls [`TRAMPOLINE`] in a new JIT region registered with the unwind
+    /// information returned by `unwind_info` for its address, and checks that
+    /// the backtrace taken in the callee continues through it into the caller.
+    #[cfg(target_arch = "x86_64")]
+    fn unwind_trampoline(unwind_info: impl FnOnce(usize) -> JitUnwindInfo) {
+        use crate::{MayAllocateDuringUnwind, Unwinder};
+
+        struct Capture<'a> {
+            unwinder: &'a Unwinder<MayAllocateDuringUnwind>,
+            frames: Vec<usize>,
+        }
+
+        extern "C" fn capture(capture: &mut Capture) {
+            capture.frames = capture.unwinder.capture();
+        }
+
+        let page = unsafe {
+            let page = libc::mmap(
+                std::ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(page, libc::MAP_FAILED);
+            std::ptr::copy_nonoverlapping(TRAMPOLINE.as_ptr(), page as *mut u8, TRAMPOLINE.len());
+            assert_eq!(
+                libc::mprotect(page, 4096, libc::PROT_READ | libc::PROT_EXEC),
+                0
+            );
+            page as usize
+        };
+        let region = find_jit_regions()
+            .unwrap()
+            .into_iter()
+            .find(|region| region.avma.contains(&page))
+            .unwrap();
+        assert!(!region.writable);
+
+        let mut unwinder = Unwinder::new();
+        unwinder.add_jit_region(page..page + 4096, unwind_info(page));
+        assert!(unwinder
+            .modules()
+            .any(|module| module.avma_range.start == page as u64));
+
+        let trampoline: extern "C" fn(extern "C" fn(&mut Capture), &mut Capture) =
+            unsafe { std::mem::transmute(page) };
+        let mut out = Capture {
+            unwinder: &unwinder,
+            frames: Vec::new(),
+        };
+        trampoline(capture, &mut out);
+
+        // The return address after `call rax`, then the caller.
+        let i = out
+            .frames
+            .iter()
+            .position(|&addr| addr == page + 12)
+            .unwrap();
+        let caller = out.frames[i + 1];
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(
+            crate::object::find_object_for_addr(caller).unwrap().path(),
+            exe
+        );
+
+        unwinder.remove_module(page as u64);
+        unsafe { libc::munmap(page as *mut libc::c_void, 4096) };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn unwind_jit_region() {
+        unwind_trampoline(|page| {
+            JitUnwindInfo::EhFrame(Box::leak(trampoline_eh_frame(page).into_boxed_slice()))
+        });
+    }
+
+    #[test]
+    fn perf_map() {
+        let path = std::env::temp_dir().join(format!("runwind-{}.map", std::process::id()));
+        std::fs::write(
+            &path,
+            "1000 100 foo\n0x2000 0x10 Lbar::baz(int, int)\ninvalid\n1080 10 qux\n\
+             ffffffffffffffff 10 overflow\n",
+        )
+        .unwrap();
+        let mut symbols = JitSymbols::new();
+        symbols.add_perf_map(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // `qux` replaced `foo`.
+        assert_eq!(symbols.lookup(0x1000), None);
+        assert_eq!(symbols.lookup(0x1085), Some("qux"));
+        assert_eq!(symbols.lookup(0x200f), Some("Lbar::baz(int, int)"));
+        assert_eq!(symbols.lookup(0x2010), None);
+        assert_eq!(symbols.iter().count(), 2);
+    }
+
+    #[test]
+    fn jitdump() {
+        let record = |id: u32, body: &[u8]| {
+            let mut record = Vec::new();
+            record.extend_from_slice(&id.to_ne_bytes());
+            record.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+            record.extend_from_slice(&0u64.to_ne_bytes());
+            record.extend_from_slice(body);
+            record
+        };
+        let words = |pid_tid: bool, words: &[u64]| {
+            let mut body = Vec::new();
+            if pid_tid {
+                body.extend_from_slice(&[0; 8]);
+            }
+            for word in words {
+                body.extend_from_slice(&word.to_ne_bytes());
+            }
+            body
+        };
+
+        let mut data = Vec::new();
+        for word in [JITDUMP_MAGIC, 1, 40, 62, 0, 1] {
+            data.extend_from_slice(&word.to_ne_bytes());
+        }
+        data.extend_from_slice(&[0; 16]);
+        // vma, code_addr, code_size, code_index, name, code
+        let mut load = words(true, &[0x1000, 0x1000, 0x20, 0]);
+        load.extend_from_slice(b"jitted\0");
+        load.extend_from_slice(&[0xc3; 0x20]);
+        data.extend(record(JIT_CODE_LOAD, &load));
+        // Debug info, skipped.
+        data.extend(record(2, &words(false, &[0x1000, 0])));
+        // Code whose end overflows, skipped.
+        let mut overflow = words(true, &[0, u64::MAX - 1, 0x20, 1]);
+        overflow.extend_from_slice(b"overflow\0");
+        data.extend(record(JIT_CODE_LOAD, &overflow));
+        let moved = words(true, &[0, 0x1000, u64::MAX, 0x20, 0]);
+        data.extend(record(JIT_CODE_MOVE, &moved));
+        // vma, old_code_addr, new_code_addr, code_size, code_index
+        let moved = words(true, &[0x3000, 0x1000, 0x3000, 0x20, 0]);
+        data.extend(record(JIT_CODE_MOVE, &moved));
+        // A record cut off.
+        data.extend_from_slice(&JIT_CODE_LOAD.to_ne_bytes());
+
+        let path = std::env::temp_dir().join(format!("jit-{}.dump", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let mut symbols = JitSymbols::new();
+        symbols.add_jitdump(&path).unwrap();
+        std::fs::write(&path, b"not a jitdump").unwrap();
+        assert!(JitSymbols::new().add_jitdump(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(symbols.lookup(0x101f), Some("jitted"));
+        assert_eq!(symbols.lookup(0x3000), Some("jitted"));
+        assert_eq!(symbols.lookup(0x1020), None);
+    }
+
+    #[cfg(feature = "symbolize")]
+    #[test]
+    fn symbolicate_jit() {
+        let mut symbols = JitSymbols::new();
+        symbols.add(0x10000..0x10100, "jitted".to_string());
+        set_jit_symbols(symbols);
+        let frames = crate::Unwinder::<crate::MayAllocateDuringUnwind>::symbolicate(0x10080);
+        assert_eq!(frames[0].function.as_deref(), Some("jitted"));
+        // Return addresses are looked up before the call.
+        assert!(crate::Unwinder::<crate::MayAllocateDuringUnwind>::symbolicate(0x10000).is_empty());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn fixed_frame() {
+        // The frame of `sub rsp, 8` around the call.
+        unwind_trampoline(|_| JitUnwindInfo::FixedFrame { size: 8 });
+    }
+}
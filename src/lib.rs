@@ -1,9 +1,71 @@
+// framehop only unwinds these architectures.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("runwind only supports x86_64 and aarch64");
+
+// Objects are only discovered through the loaders of these systems.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+compile_error!("runwind only supports Linux, macOS and FreeBSD");
+
 mod addr_validate;
+mod capture;
+#[cfg(target_os = "linux")]
+pub mod coredump;
+mod diagnostic;
+mod error;
+#[cfg(feature = "symbolize")]
+mod folded;
+pub mod jit;
+mod memory;
 mod object;
+#[cfg(target_os = "linux")]
+mod ptrace;
+#[cfg(target_os = "linux")]
+mod regs;
+pub mod ring;
+#[cfg(all(feature = "sampler", target_os = "linux"))]
+pub mod sampler;
+mod stack;
+#[cfg(feature = "symbolize")]
+mod symbolize;
+#[cfg(feature = "async")]
+mod symbolize_async;
 mod unwinder;
 
-pub use crate::object::get_objects;
-pub use crate::unwinder::{UnwindIterator, Unwinder};
+pub use crate::addr_validate::{
+    set_validation_cache_ttl, set_validation_strategy, ValidationStrategy,
+};
+pub use crate::capture::{Backtrace, CapturedBacktrace, CapturedModule};
+pub use crate::diagnostic::{set_diagnostic_handler, Diagnostic};
+pub use crate::error::{LoadError, UnwindError};
+#[cfg(feature = "symbolize")]
+pub use crate::folded::{fold_backtrace, FoldedStacks};
+#[cfg(target_os = "linux")]
+pub use crate::memory::ProcessMemory;
+pub use crate::memory::{LocalMemory, StackMemory};
+#[cfg(target_os = "macos")]
+pub use crate::object::set_dsym_lookup_commands;
+pub use crate::object::{
+    find_object_for_addr, get_objects, get_objects_report, refresh_objects, set_parallel_loading,
+    set_safe_mode, ObjectChanges, ObjectsReport, TextSegment, UnwindSource,
+};
+#[cfg(target_os = "linux")]
+pub use crate::ptrace::StoppedThread;
+#[cfg(target_os = "linux")]
+pub use crate::regs::{regs_from_gregs, regs_from_user_regs, GREGS_LEN};
+pub use crate::stack::current_thread_stack;
+#[cfg(feature = "symbolize")]
+pub use crate::symbolize::{
+    ContextCache, FrameInfo, InlinedFrame, InlinedFrameIterator, SymbolizedAddress, Symbolizer,
+};
+#[cfg(feature = "async")]
+pub use crate::symbolize_async::SymbolicateFuture;
+#[cfg(feature = "debug-frames")]
+pub use crate::unwinder::DebugFrame;
+pub use crate::unwinder::{
+    AnyUnwinder, Frame, FramePointerIterator, ModuleInfo, ResolvedFrame, ThreadCachePolicy,
+    UnwindIterator, Unwinder, UnwinderStats, DEFAULT_TERMINATORS,
+};
 pub use framehop::{
-    CacheNative, Error, MayAllocateDuringUnwind, MustNotAllocateDuringUnwind, UnwindRegsNative,
+    CacheNative, Error, FrameAddress, MayAllocateDuringUnwind, Module, ModuleSvmaInfo,
+    ModuleUnwindData, MustNotAllocateDuringUnwind, TextByteData, UnwindRegsNative,
 };
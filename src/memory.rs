@@ -0,0 +1,136 @@
+/// A source of stack words for the unwinder, set with
+/// [`UnwindIterator::with_memory`](crate::UnwindIterator::with_memory).
+///
+/// Closures taking the address implement it too, e.g. to read the saved stack
+/// of a suspended coroutine or green thread. Besides the stack, the code at the
+/// frame addresses is read to recognize signal trampolines, so addresses
+/// outside the stack should be read with [`LocalMemory`].
+///
+/// Implementations must be async-signal-safe if the unwinding happens in a
+/// signal handler.
+pub trait StackMemory {
+    /// Reads the 8-byte word at `addr` rounded down to a multiple of 8, or
+    /// fails if it is not readable.
+    ///
+    /// The unit error mirrors the stack reader callback of framehop.
+    #[allow(clippy::result_unit_err)]
+    fn read_u64(&mut self, addr: u64) -> Result<u64, ()>;
+}
+
+impl<F: FnMut(u64) -> Result<u64, ()>> StackMemory for F {
+    fn read_u64(&mut self, addr: u64) -> Result<u64, ()> {
+        self(addr)
+    }
+}
+
+/// Lets the reader be chosen at runtime, e.g. between the current process, a
+/// stopped thread or a core dump, without an iterator type for each.
+impl StackMemory for &mut dyn StackMemory {
+    fn read_u64(&mut self, addr: u64) -> Result<u64, ()> {
+        (**self).read_u64(addr)
+    }
+}
+
+/// Reads the memory of the current process after checking each page with a
+/// write to a pipe, so invalid addresses fail instead of crashing. Sandboxes
+/// without pipes can use another check, see
+/// [`set_validation_strategy`](crate::set_validation_strategy).
+///
+/// This costs two syscalls per page, or per page that is not in the per-thread
+/// validation cache if it is enabled with
+/// [`set_validation_cache_ttl`](crate::set_validation_cache_ttl).
+///
+/// With the `fault-handler` feature, reads are attempted directly and faults
+/// are caught by a SIGSEGV/SIGBUS handler instead, which costs no syscalls.
+/// SIGSEGV and SIGBUS must then not be blocked while unwinding, including in
+/// the `sa_mask` of a signal handler that unwinds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalMemory;
+
+impl StackMemory for LocalMemory {
+    fn read_u64(&mut self, addr: u64) -> Result<u64, ()> {
+        let aligned_addr = addr & !0b111;
+        crate::addr_validate::read_u64(aligned_addr as usize).ok_or(())
+    }
+}
+
+/// Reads the memory of a process with `process_vm_readv`, which takes a single
+/// syscall per word and also works for other processes.
+///
+/// Reading another process requires the same permissions as attaching to it
+/// with ptrace.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessMemory {
+    pid: libc::pid_t,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcessMemory {
+    pub fn new(pid: libc::pid_t) -> Self {
+        ProcessMemory { pid }
+    }
+
+    /// Reads the memory of the current process.
+    pub fn current() -> Self {
+        Self::new(std::process::id() as libc::pid_t)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl StackMemory for ProcessMemory {
+    fn read_u64(&mut self, addr: u64) -> Result<u64, ()> {
+        use std::io::IoSliceMut;
+
+        use nix::{
+            sys::uio::{process_vm_readv, RemoteIoVec},
+            unistd::Pid,
+        };
+
+        let aligned_addr = addr & !0b111;
+        let mut buf = [0u8; 8];
+        let remote = RemoteIoVec {
+            base: aligned_addr as usize,
+            len: buf.len(),
+        };
+        match process_vm_readv(
+            Pid::from_raw(self.pid),
+            &mut [IoSliceMut::new(&mut buf)],
+            &[remote],
+        ) {
+            Ok(8) => Ok(u64::from_ne_bytes(buf)),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_u64() {
+        let word = 0x1234_5678_9abc_def0u64;
+        let addr = &word as *const u64 as u64;
+        assert_eq!(LocalMemory.read_u64(addr), Ok(word));
+        assert_eq!(LocalMemory.read_u64(0), Err(()));
+        #[cfg(target_os = "linux")]
+        {
+            let mut memory = ProcessMemory::current();
+            assert_eq!(memory.read_u64(addr), Ok(word));
+            assert_eq!(memory.read_u64(0), Err(()));
+        }
+    }
+
+    #[test]
+    fn dyn_memory() {
+        let word = 0x1234_5678_9abc_def0u64;
+        let addr = &word as *const u64 as u64;
+        let mut local = LocalMemory;
+        let mut zeros = |_| Ok(0);
+        for (use_local, expected) in [(true, word), (false, 0)] {
+            let mut memory: &mut dyn StackMemory = if use_local { &mut local } else { &mut zeros };
+            assert_eq!(StackMemory::read_u64(&mut memory, addr), Ok(expected));
+        }
+    }
+}
@@ -1,43 +1,379 @@
 use std::{
+    borrow::Cow,
     fmt::{self, Debug},
+    num::NonZeroUsize,
     ops::Range,
-    path::PathBuf,
+    path::{Path, PathBuf},
     slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
 };
 
 use framehop::{Module, ModuleSvmaInfo, ModuleUnwindData, TextByteData};
 use libc::c_void;
-use object::{Object as _, ObjectSection};
+use object::{BinaryFormat, Object as _, ObjectSection, ObjectSegment, SegmentFlags};
+use once_cell::sync::{Lazy, OnceCell};
 
+use crate::diagnostic::warning;
+use crate::{LoadError, ModuleInfo};
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub use dl_iterate_phdr::ObjectMmap;
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
-pub use dl_iterate_phdr::{get_objects, ObjectMmap};
-#[cfg(any(target_os = "macos"))]
-pub use macos::{get_objects, ObjectMmap};
+use dl_iterate_phdr::{find_phdrs, load_debug_file, load_object};
+#[cfg(target_os = "macos")]
+use macos::{find_phdrs, load_debug_file, load_object};
+#[cfg(target_os = "macos")]
+pub use macos::{set_dsym_lookup_commands, ObjectMmap};
 
 #[cfg(any(target_os = "linux", target_os = "freebsd"))]
 mod dl_iterate_phdr;
-#[cfg(any(target_os = "macos"))]
+#[cfg(target_os = "macos")]
 mod macos;
 
+/// Objects are never freed because the modules built from them borrow their
+/// data for 'static.
+static OBJECTS: Lazy<RwLock<ObjectsReport>> = Lazy::new(|| RwLock::new(find_objects(&[])));
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Makes the modules built from now on copy the memory they need from the
+/// process, after checking that it is readable, instead of reading it in
+/// place: the text of each object, and the unwind sections of the objects
+/// whose file can't be mapped. An object unmapped meanwhile, e.g. by a
+/// `dlclose` racing with the discovery, then loses its unwind information
+/// instead of crashing the process. `.eh_frame` is always checked when it is
+/// searched for in memory.
+///
+/// This costs as much memory as the text of all objects, often tens of
+/// megabytes, and the time to copy it when an unwinder is created, or when
+/// a module of [`Unwinder::new_lazy`](crate::Unwinder::new_lazy) is first
+/// needed.
+pub fn set_safe_mode(enabled: bool) {
+    SAFE_MODE.store(enabled, Ordering::Relaxed);
+}
+
+static PARALLEL_LOADING: AtomicBool = AtomicBool::new(false);
+
+/// Makes discoveries load the objects they find on as many threads as there
+/// are CPUs, since mapping and parsing the files dominates the discovery of a
+/// process with many libraries. By default, they are loaded on the thread that
+/// discovers them.
+///
+/// The threads are spawned by the first discovery, on the first use of the
+/// objects, e.g. by [`Unwinder::new`](crate::Unwinder::new), and by
+/// [`refresh_objects`]. The [diagnostics](crate::set_diagnostic_handler) of
+/// loading are then reported on them.
+pub fn set_parallel_loading(enabled: bool) {
+    PARALLEL_LOADING.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns the objects loaded in the process as of the last discovery, sorted
+/// by the start of their text, which never overlap.
+pub fn get_objects() -> Arc<[&'static Object]> {
+    OBJECTS.read().unwrap().objects.clone()
+}
+
+/// The objects found by the last discovery, and the problems met loading them.
+#[derive(Debug, Clone)]
+pub struct ObjectsReport {
+    /// The same as [`get_objects`].
+    pub objects: Arc<[&'static Object]>,
+    /// The paths of the objects that were skipped or are only partially
+    /// loaded, with the reason.
+    pub failures: Arc<[(PathBuf, LoadError)]>,
+}
+
+/// Like [`get_objects`], but also returns the objects that failed to load, e.g.
+/// for health checks. The failures are otherwise only logged.
+pub fn get_objects_report() -> ObjectsReport {
+    OBJECTS.read().unwrap().clone()
+}
+
+/// The paths of the objects that appeared or disappeared in a call to
+/// [`refresh_objects`].
+#[derive(Debug, Default)]
+pub struct ObjectChanges {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Discovers the loaded objects again, e.g. after `dlopen` or `dlclose`, and
+/// replaces the list returned by [`get_objects`].
+///
+/// Objects that are still loaded are reused instead of being parsed again,
+/// unless their file changed, e.g. a library reloaded at the same address after
+/// being rebuilt.
+///
+/// The objects that disappear are never freed, with their mapping and file
+/// descriptor, since unwinders and modules may still borrow them: each
+/// `dlopen`/`dlclose` cycle of a library followed by a refresh leaks it once.
+pub fn refresh_objects() -> ObjectChanges {
+    let mut report = OBJECTS.write().unwrap();
+    let new_report = find_objects(&report.objects);
+    let (objects, new_objects) = (&report.objects, &new_report.objects);
+    let contains = |objects: &[&'static Object], obj: &'static Object| {
+        objects.iter().any(|o| std::ptr::eq(*o, obj))
+    };
+    let changes = ObjectChanges {
+        added: new_objects
+            .iter()
+            .filter(|obj| !contains(objects, obj))
+            .map(|obj| obj.phdr.path.clone())
+            .collect(),
+        removed: objects
+            .iter()
+            .filter(|obj| !contains(new_objects, obj))
+            .map(|obj| obj.phdr.path.clone())
+            .collect(),
+    };
+    *report = new_report;
+    changes
+}
+
+/// Returns the object whose text contains `addr`, or `None` if `addr` is below
+/// the first object, in a gap between objects or above the last one.
+///
+/// Return addresses may be just past the end of the call instruction, so look
+/// up `addr - 1` for frames other than the first, like
+/// [`FrameAddress::address_for_lookup`](crate::FrameAddress::address_for_lookup).
+pub fn find_object_for_addr(addr: usize) -> Option<&'static Object> {
+    let objects = get_objects();
+    // The first object whose text ends after `addr` is the only one that may
+    // contain it.
+    let i = objects.partition_point(|obj| obj.text_avma().end <= addr);
+    objects
+        .get(i)
+        .filter(|obj| obj.text_avma().contains(&addr))
+        .copied()
+}
+
+/// Loads the objects of `phdrs` in order on up to `threads` threads, see
+/// [`set_parallel_loading`].
+fn load_objects(phdrs: Vec<ObjectPhdr>, threads: usize) -> Vec<Object> {
+    let count = phdrs.len();
+    let pending = Mutex::new(phdrs.into_iter().enumerate());
+    let loaded = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<Object>>>());
+    let work = || loop {
+        let Some((i, phdr)) = pending.lock().unwrap().next() else {
+            break;
+        };
+        let obj = load_object(phdr);
+        loaded.lock().unwrap()[i] = Some(obj);
+    };
+    thread::scope(|scope| {
+        for _ in 1..threads.min(count) {
+            let spawned = thread::Builder::new()
+                .name("runwind-load".to_string())
+                .spawn_scoped(scope, work);
+            if spawned.is_err() {
+                break;
+            }
+        }
+        // This thread loads objects too, all of them if no thread could be
+        // spawned.
+        work();
+    });
+    loaded
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+fn find_objects(known: &[&'static Object]) -> ObjectsReport {
+    let (phdrs, mut failures) = dedup_phdrs(find_phdrs());
+    let mut new_phdrs = Vec::new();
+    let known: Vec<Option<&'static Object>> = phdrs
+        .into_iter()
+        .map(|phdr| {
+            let known = known.iter().find(|obj| is_reusable(obj, &phdr));
+            if known.is_none() {
+                new_phdrs.push(phdr);
+            }
+            known.copied()
+        })
+        .collect();
+    let threads = match PARALLEL_LOADING.load(Ordering::Relaxed) {
+        true => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        false => 1,
+    };
+    let mut loaded = load_objects(new_phdrs, threads).into_iter();
+    let mut objects: Vec<&'static Object> = known
+        .into_iter()
+        .map(|known| known.unwrap_or_else(|| Box::leak(Box::new(loaded.next().unwrap()))))
+        .collect();
+    objects.sort_by_key(|obj| obj.text_avma().start);
+    failures.extend(objects.iter().filter_map(|obj| {
+        let error = obj.load_error.clone()?;
+        Some((obj.phdr.path.clone(), error))
+    }));
+    ObjectsReport {
+        objects: objects.into(),
+        failures: failures.into(),
+    }
+}
+
+/// Whether `obj` is the object of `phdr`, loaded from the same file at the same
+/// address, so it doesn't need to be loaded again.
+fn is_reusable(obj: &Object, phdr: &ObjectPhdr) -> bool {
+    obj.phdr.base_addr == phdr.base_addr
+        && obj.phdr.path == phdr.path
+        && obj.file_id == FileId::of(&phdr.path)
+}
+
+/// Identifies the file an object was loaded from, to tell a file replaced at the
+/// same path apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+    mtime: (i64, i64),
+}
+
+impl FileId {
+    /// Returns `None` for objects without a file, e.g. the vDSO.
+    fn of(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileId {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        })
+    }
+}
+
+/// Drops the objects reported more than once, e.g. when the same file is opened
+/// through different paths, and those overlapping an earlier object, which
+/// framehop can't tell apart. The latter are returned as failures.
+fn dedup_phdrs(phdrs: Vec<ObjectPhdr>) -> (Vec<ObjectPhdr>, Vec<(PathBuf, LoadError)>) {
+    let mut deduped: Vec<ObjectPhdr> = Vec::with_capacity(phdrs.len());
+    let mut failures = Vec::new();
+    for phdr in phdrs {
+        let text = phdr.text_avma();
+        match deduped.iter().find(|other| {
+            let other_text = other.text_avma();
+            text.start < other_text.end && other_text.start < text.end
+        }) {
+            Some(other) if other.base_addr == phdr.base_addr && other.text_avma() == text => {}
+            Some(other) => {
+                warning!(
+                    "Text of {:?} overlaps with {:?}, ignoring it",
+                    phdr.path,
+                    other.path
+                );
+                failures.push((phdr.path, LoadError::Overlap(other.path.clone())));
+            }
+            None => deduped.push(phdr),
+        }
+    }
+    (deduped, failures)
+}
+
 pub struct ObjectPhdr {
     base_addr: usize,
+    base_svma: usize,
     path: PathBuf,
+    /// Whether this is the executable of the process rather than a library.
+    is_main_executable: bool,
+    /// Spans all of `text_segments`.
     text: Segment,
+    /// The executable segments.
+    text_segments: Vec<Segment>,
+    /// All `PT_LOAD` segments, which bound `.eh_frame` in memory.
+    load_segments: Vec<Segment>,
+    eh_frame_hdr: Option<Segment>,
+    /// The sections of a Mach-O image by name, as found in its load commands
+    /// in memory, for images whose file can't be mapped, e.g. those in the
+    /// dyld shared cache.
+    sections: Vec<(String, Segment)>,
+    /// `PT_NOTE` segments
+    notes: Vec<Segment>,
+    /// The AVMA of the GOT from the `DT_PLTGOT` entry of the dynamic segment,
+    /// for objects whose file can't be mapped.
+    got: Option<usize>,
+}
+
+impl ObjectPhdr {
+    fn text_avma(&self) -> Range<usize> {
+        let start = self.base_addr + self.text.p_vaddr;
+        start..(start + self.text.p_memsz)
+    }
+
+    /// Finds a section in `sections`. Like `object`, ELF names starting with
+    /// "." also match the Mach-O names starting with "__".
+    fn section(&self, name: &str) -> Option<&Segment> {
+        let macho_name = name.strip_prefix('.').map(|name| format!("__{name}"));
+        self.sections
+            .iter()
+            .find(|(section_name, _)| {
+                section_name == name || Some(section_name) == macho_name.as_ref()
+            })
+            .map(|(_, section)| section)
+    }
 }
 
 impl Debug for ObjectPhdr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ObjectPhdr")
             .field("base_addr", &(self.base_addr as *const c_void))
+            .field("base_svma", &(self.base_svma as *const c_void))
             .field("path", &self.path)
+            .field("is_main_executable", &self.is_main_executable)
             .field("text", &self.text)
+            .field("text_segments", &self.text_segments)
+            .field("load_segments", &self.load_segments)
+            .field("eh_frame_hdr", &self.eh_frame_hdr)
+            .field("sections", &self.sections)
+            .field("notes", &self.notes)
+            .field("got", &self.got.map(|got| got as *const c_void))
             .finish()
     }
 }
 
+#[derive(Clone)]
 pub struct Segment {
     p_vaddr: usize,
     p_memsz: usize,
+    /// The `PF_*` permissions of an ELF program header.
+    p_flags: u32,
+}
+
+/// An executable segment.
+const PF_X: u32 = 1;
+/// A writable segment.
+const PF_W: u32 = 2;
+/// A readable segment.
+const PF_R: u32 = 4;
+
+/// Converts the `VM_PROT_*` protection of a Mach-O segment to ELF flags.
+fn elf_flags_from_vm_prot(prot: u32) -> u32 {
+    use object::macho::{VM_PROT_EXECUTE, VM_PROT_READ, VM_PROT_WRITE};
+
+    [
+        (VM_PROT_READ, PF_R),
+        (VM_PROT_WRITE, PF_W),
+        (VM_PROT_EXECUTE, PF_X),
+    ]
+    .into_iter()
+    .filter(|(vm_prot, _)| prot & vm_prot != 0)
+    .fold(0, |flags, (_, pf)| flags | pf)
+}
+
+/// An executable segment of an object, see [`Object::text_segments`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextSegment {
+    pub avma: Range<usize>,
+    pub readable: bool,
+    /// Writable code is unusual outside of JIT compilers and self-modifying
+    /// code.
+    pub writable: bool,
 }
 
 impl Debug for Segment {
@@ -45,89 +381,1331 @@ impl Debug for Segment {
         f.debug_struct("Segment")
             .field("p_vaddr", &(self.p_vaddr as *const c_void))
             .field("p_memsz", &self.p_memsz)
+            .field("p_flags", &self.p_flags)
             .finish()
     }
 }
 
+pub enum UnwindData {
+    /// The object file is mapped from disk and parsed.
+    Mmap(Box<ObjectMmap>),
+    /// Only the `.eh_frame_hdr` and `.eh_frame` found in the process memory.
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    EhFrame(EhFrameData),
+    /// Only the `__unwind_info` and `__eh_frame` found in the process memory.
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    CompactUnwind(CompactUnwindData),
+    /// No unwind information was found. framehop can still unwind through the
+    /// object with frame pointers.
+    None,
+}
+
+impl Debug for UnwindData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnwindData::Mmap(_) => f.write_str("Mmap"),
+            UnwindData::EhFrame(data) => f.debug_tuple("EhFrame").field(data).finish(),
+            UnwindData::CompactUnwind(data) => f.debug_tuple("CompactUnwind").field(data).finish(),
+            UnwindData::None => f.write_str("None"),
+        }
+    }
+}
+
+/// Where the unwind information of an object from [`get_objects`] comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnwindSource {
+    /// The object file was mapped and parsed, so all of its sections are
+    /// available, including `.debug_frame` and the symbol tables.
+    Mmap,
+    /// The file could not be mapped, e.g. because it was deleted, so only the
+    /// `.eh_frame_hdr` and `.eh_frame` found in the process memory are used.
+    /// There are no symbols and no `.debug_frame`.
+    EhFrame,
+    /// On macOS, the file could not be mapped, e.g. because the image is in the
+    /// dyld shared cache, so only the `__unwind_info` and `__eh_frame` found in
+    /// the process memory are used, with the sections listed by the load
+    /// commands of the image in memory. There are no symbols.
+    CompactUnwindInfo,
+    /// Neither worked, so only the text range of the object is known and it is
+    /// unwound with frame pointers.
+    None,
+}
+
+/// The AVMA ranges of the unwind sections of an object in the process memory.
+pub struct EhFrameData {
+    eh_frame_hdr: Range<usize>,
+    eh_frame: Range<usize>,
+}
+
+impl Debug for EhFrameData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EhFrameData")
+            .field(
+                "eh_frame_hdr",
+                &(self.eh_frame_hdr.start as *const c_void..self.eh_frame_hdr.end as *const c_void),
+            )
+            .field(
+                "eh_frame",
+                &(self.eh_frame.start as *const c_void..self.eh_frame.end as *const c_void),
+            )
+            .finish()
+    }
+}
+
+/// The AVMA ranges of the compact unwind sections of a Mach-O image in the
+/// process memory.
+pub struct CompactUnwindData {
+    unwind_info: Range<usize>,
+    eh_frame: Option<Range<usize>>,
+}
+
+impl Debug for CompactUnwindData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ptr_range =
+            |range: &Range<usize>| range.start as *const c_void..range.end as *const c_void;
+        f.debug_struct("CompactUnwindData")
+            .field("unwind_info", &ptr_range(&self.unwind_info))
+            .field("eh_frame", &self.eh_frame.as_ref().map(ptr_range))
+            .finish()
+    }
+}
+
+/// An object loaded in the process, with its unwind information.
+///
+/// Objects are `Send` and `Sync`: they are leaked and their mappings are
+/// read-only, so the data borrowed from them stays valid and unchanged.
 pub struct Object {
     phdr: ObjectPhdr,
+    unwind_data: UnwindData,
+    /// `.debug_frame`, decompressed if needed. Only loaded if there is no
+    /// `.eh_frame`.
+    debug_frame: OnceCell<Option<Cow<'static, [u8]>>>,
+    debug_file: OnceCell<Option<DebugFile>>,
+    /// Why the file could not be mapped, if it couldn't.
+    load_error: Option<LoadError>,
+    /// The readable ones of [`Object::memory_ranges`], copied in safe mode.
+    memory_copies: OnceCell<Vec<MemoryCopy>>,
+    file_id: Option<FileId>,
+}
+
+/// A range of the process memory and its bytes.
+type MemoryCopy = (Range<usize>, Box<[u8]>);
+
+/// A separate file holding the debug info of a stripped object.
+pub struct DebugFile {
+    path: PathBuf,
     mmap: ObjectMmap,
 }
 
 impl Object {
-    fn section_range(&self, section_name: &str) -> Option<Range<u64>> {
-        self.mmap
-            .obj_file
-            .section_by_name(section_name)
-            .and_then(|s| s.file_range())
-            .map(|(start, end)| start..(start + end))
+    fn new(phdr: ObjectPhdr, unwind_data: UnwindData) -> Self {
+        Object {
+            file_id: FileId::of(&phdr.path),
+            phdr,
+            unwind_data,
+            debug_frame: OnceCell::new(),
+            debug_file: OnceCell::new(),
+            load_error: None,
+            memory_copies: OnceCell::new(),
+        }
     }
 
-    fn range_data(&self, range: &Range<u64>) -> &[u8] {
-        let (start, end) = (range.start as usize, range.end as usize);
-        &self.mmap.mmap[start..end]
+    /// Loads the object file at `path` as if it was loaded with the load bias
+    /// `base_addr`, instead of discovering it in the process.
+    pub(crate) fn from_file(path: &Path, base_addr: usize) -> Result<Self, LoadError> {
+        let mmap = ObjectMmap::new(path)?;
+        let mut text_segments = Vec::new();
+        let mut load_segments = Vec::new();
+        for segment in mmap.obj_file.segments() {
+            let p_flags = match segment.flags() {
+                SegmentFlags::Elf { p_flags } => p_flags,
+                SegmentFlags::MachO { initprot, .. } => elf_flags_from_vm_prot(initprot),
+                _ => 0,
+            };
+            let segment = Segment {
+                p_vaddr: segment.address() as usize,
+                p_memsz: segment.size() as usize,
+                p_flags,
+            };
+            if p_flags & PF_X != 0 {
+                text_segments.push(segment.clone());
+            }
+            load_segments.push(segment);
+        }
+        let start = text_segments.iter().map(|s| s.p_vaddr).min();
+        let end = text_segments.iter().map(|s| s.p_vaddr + s.p_memsz).max();
+        let Some((start, end)) = start.zip(end) else {
+            return Err(LoadError::NoText);
+        };
+        let phdr = ObjectPhdr {
+            base_addr,
+            // framehop expects the address of the Mach-O header, at the start
+            // of `__TEXT`, as the base.
+            base_svma: match mmap.obj_file.format() {
+                BinaryFormat::MachO => start,
+                _ => 0,
+            },
+            path: path.to_path_buf(),
+            is_main_executable: false,
+            text: Segment {
+                p_vaddr: start,
+                p_memsz: end - start,
+                p_flags: PF_X,
+            },
+            text_segments,
+            load_segments,
+            eh_frame_hdr: None,
+            sections: Vec::new(),
+            notes: Vec::new(),
+            got: None,
+        };
+        Ok(Object::new(phdr, UnwindData::Mmap(Box::new(mmap))))
+    }
+
+    /// Like [`Object::from_file`], with the load bias found from `map_start`,
+    /// the address of the mapping of the start of the file, as listed in
+    /// `/proc/<pid>/maps` or the `NT_FILE` note of a core dump.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn from_mapped_file(path: &Path, map_start: usize) -> Result<Self, LoadError> {
+        let mut obj = Self::from_file(path, 0)?;
+        // The lowest segment maps the start of the file, so the file offset 0
+        // is at `p_vaddr - p_offset` before adding the load bias.
+        let offset_zero_svma = obj
+            .obj_file()
+            .and_then(|obj_file| {
+                obj_file
+                    .segments()
+                    .map(|segment| (segment.address(), segment.file_range().0))
+                    .min()
+            })
+            .map_or(0, |(svma, offset)| svma.wrapping_sub(offset) as usize);
+        obj.phdr.base_addr = map_start.wrapping_sub(offset_zero_svma);
+        Ok(obj)
+    }
+
+    /// The text range may span gaps between executable segments that are not
+    /// mapped, so only the largest segment, holding `.text`, is read.
+    fn text_data_avma(&self) -> Option<Range<usize>> {
+        let segment = self
+            .phdr
+            .text_segments
+            .iter()
+            .max_by_key(|segment| segment.p_memsz)?;
+        let start = self.phdr.base_addr + segment.p_vaddr;
+        Some(start..start + segment.p_memsz)
+    }
+
+    /// The memory of the process read by [`Object::to_module`].
+    fn memory_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = self.text_data_avma().into_iter().collect();
+        match &self.unwind_data {
+            UnwindData::EhFrame(data) => {
+                ranges.extend([data.eh_frame_hdr.clone(), data.eh_frame.clone()]);
+            }
+            UnwindData::CompactUnwind(data) => {
+                ranges.push(data.unwind_info.clone());
+                ranges.extend(data.eh_frame.clone());
+            }
+            UnwindData::Mmap(_) | UnwindData::None => {}
+        }
+        ranges
+    }
+
+    /// Returns the memory of the process at `range`, one of
+    /// [`Object::memory_ranges`], or with `safe` its copy if it was readable.
+    /// See [`set_safe_mode`].
+    fn memory(&self, range: &Range<usize>, safe: bool) -> Option<&[u8]> {
+        if !safe {
+            return Some(unsafe { slice::from_raw_parts(range.start as *const u8, range.len()) });
+        }
+        let copies = self.memory_copies.get_or_init(|| {
+            self.memory_ranges()
+                .into_iter()
+                .filter_map(|range| {
+                    let Some(copy) = crate::addr_validate::copy_memory(range.start, range.len())
+                    else {
+                        warning!(
+                            "Memory of {:?} at {:#x} is not readable",
+                            self.phdr.path,
+                            range.start
+                        );
+                        return None;
+                    };
+                    Some((range, copy))
+                })
+                .collect()
+        });
+        copies
+            .iter()
+            .find(|(copied, _)| copied == range)
+            .map(|(_, copy)| &**copy)
+    }
+
+    /// Returns the SVMA range of a section. This is not its range in the file,
+    /// which is usually different for sections after the first segment.
+    fn section_svma(&self, section_name: &str) -> Option<Range<u64>> {
+        match self.obj_file() {
+            Some(obj_file) => {
+                let section = obj_file.section_by_name(section_name)?;
+                Some(section.address()..section.address() + section.size())
+            }
+            None => {
+                let section = self.phdr.section(section_name)?;
+                Some(section.p_vaddr as u64..(section.p_vaddr + section.p_memsz) as u64)
+            }
+        }
+    }
+
+    fn section_data<'a>(mmap: &'a ObjectMmap, section_name: &str) -> Option<&'a [u8]> {
+        let (start, size) = mmap.obj_file.section_by_name(section_name)?.file_range()?;
+        mmap.mmap.get(start as usize..(start + size) as usize)
+    }
+
+    /// Returns whether `.eh_frame_hdr` has an FDE for `svma`.
+    fn has_fde(&self, svma: u64) -> bool {
+        use gimli::{BaseAddresses, EhFrame, EhFrameHdr, LittleEndian, UnwindSection};
+
+        let mmap = match &self.unwind_data {
+            UnwindData::Mmap(mmap) => mmap,
+            _ => return false,
+        };
+        let (Some(hdr_svma), Some(eh_frame_svma)) = (
+            self.section_svma(".eh_frame_hdr"),
+            self.section_svma(".eh_frame"),
+        ) else {
+            return false;
+        };
+        let (Some(hdr_data), Some(eh_frame_data)) = (
+            Self::section_data(mmap, ".eh_frame_hdr"),
+            Self::section_data(mmap, ".eh_frame"),
+        ) else {
+            return false;
+        };
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(hdr_svma.start)
+            .set_eh_frame(eh_frame_svma.start);
+        let Ok(hdr) = EhFrameHdr::new(hdr_data, LittleEndian).parse(&bases, 8) else {
+            return false;
+        };
+        let eh_frame = EhFrame::new(eh_frame_data, LittleEndian);
+        hdr.table().is_some_and(|table| {
+            table
+                .fde_for_address(&eh_frame, &bases, svma, EhFrame::cie_from_offset)
+                .is_ok()
+        })
+    }
+
+    /// Returns the size of `.eh_frame` (`__eh_frame` in Mach-O), or 0 if the
+    /// object has none.
+    pub(crate) fn eh_frame_len(&self) -> usize {
+        match &self.unwind_data {
+            UnwindData::Mmap(_) => ["__eh_frame", ".eh_frame"]
+                .into_iter()
+                .find_map(|name| self.section_svma(name))
+                .map_or(0, |svma| (svma.end - svma.start) as usize),
+            UnwindData::EhFrame(data) => data.eh_frame.len(),
+            UnwindData::CompactUnwind(data) => data.eh_frame.as_ref().map_or(0, |r| r.len()),
+            UnwindData::None => 0,
+        }
+    }
+
+    /// Returns the number of FDEs in the search table of `.eh_frame_hdr`, or
+    /// 0 without one. Mach-O images have no such table.
+    pub(crate) fn fde_count(&self) -> usize {
+        use gimli::{BaseAddresses, EhFrameHdr, LittleEndian};
+
+        let (hdr_data, hdr_address) = match &self.unwind_data {
+            UnwindData::Mmap(mmap) => match (
+                Self::section_data(mmap, ".eh_frame_hdr"),
+                self.section_svma(".eh_frame_hdr"),
+            ) {
+                (Some(data), Some(svma)) => (Cow::Borrowed(data), svma.start),
+                _ => return 0,
+            },
+            UnwindData::EhFrame(data) => {
+                let range = &data.eh_frame_hdr;
+                // In safe mode, only the header is copied, not all the memory
+                // of the module.
+                let hdr_data = if SAFE_MODE.load(Ordering::Relaxed) {
+                    crate::addr_validate::copy_memory(range.start, range.len())
+                        .map(|copy| Cow::Owned(copy.into_vec()))
+                } else {
+                    self.memory(range, false).map(Cow::Borrowed)
+                };
+                match hdr_data {
+                    Some(hdr_data) => (hdr_data, range.start as u64),
+                    None => return 0,
+                }
+            }
+            _ => return 0,
+        };
+        let bases = BaseAddresses::default().set_eh_frame_hdr(hdr_address);
+        let Ok(hdr) = EhFrameHdr::new(&hdr_data, LittleEndian).parse(&bases, 8) else {
+            return 0;
+        };
+        let Some(table) = hdr.table() else {
+            return 0;
+        };
+        // Walking the table only decodes its pointers, not the FDEs.
+        let mut entries = table.iter(&bases);
+        let mut count = 0;
+        while let Ok(Some(_)) = entries.next() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Reads a byte of each page of the unwind sections, so that they are
+    /// resident before they are first needed, e.g. in a signal handler.
+    ///
+    /// Sections in the memory of the process are skipped if they are no longer
+    /// readable, e.g. because the object was unloaded. In safe mode, their
+    /// copies are read instead, which are what the unwinder reads too.
+    pub(crate) fn prefault_unwind_data(&self) {
+        let safe = SAFE_MODE.load(Ordering::Relaxed);
+        let in_memory = |range: &Range<usize>| {
+            if !safe && !crate::addr_validate::validate_range(range.start as _, range.len()) {
+                return None;
+            }
+            self.memory(range, safe)
+        };
+        let sections: Vec<&[u8]> = match &self.unwind_data {
+            UnwindData::Mmap(mmap) => ["__unwind_info", ".eh_frame_hdr", ".eh_frame"]
+                .into_iter()
+                .filter_map(|name| Self::section_data(mmap, name))
+                .chain(self.debug_frame.get().and_then(|data| data.as_deref()))
+                .collect(),
+            UnwindData::EhFrame(data) => [&data.eh_frame_hdr, &data.eh_frame]
+                .into_iter()
+                .filter_map(in_memory)
+                .collect(),
+            UnwindData::CompactUnwind(data) => [Some(&data.unwind_info), data.eh_frame.as_ref()]
+                .into_iter()
+                .flatten()
+                .filter_map(in_memory)
+                .collect(),
+            UnwindData::None => Vec::new(),
+        };
+        for section in sections {
+            for byte in section.iter().step_by(4096) {
+                unsafe { std::ptr::read_volatile(byte) };
+            }
+        }
+    }
+
+    /// Returns the AVMA ranges of the ELF PLT sections without unwind
+    /// information, whose entries jump to the target without touching the
+    /// stack.
+    pub(crate) fn stub_avmas(&self) -> Vec<Range<usize>> {
+        [".plt", ".plt.sec", ".plt.got"]
+            .into_iter()
+            .filter_map(|name| self.section_svma(name))
+            .filter(|svma| svma.start < svma.end && !self.has_fde(svma.start))
+            .map(|svma| {
+                self.phdr.base_addr + svma.start as usize..self.phdr.base_addr + svma.end as usize
+            })
+            .collect()
+    }
+
+    /// Returns the SVMA range of the GOT, whose start is the base of
+    /// `DW_EH_PE_datarel` pointers in `.eh_frame`. Like the unwinder of GCC, this
+    /// is `_GLOBAL_OFFSET_TABLE_`, the start of `.got.plt` that `DT_PLTGOT`
+    /// points to, or `.got` if there is no `.got.plt`. Only the start is known
+    /// for objects whose file can't be mapped.
+    fn got_svma(&self) -> Option<Range<u64>> {
+        match self.obj_file() {
+            Some(_) => self
+                .section_svma(".got.plt")
+                .or_else(|| self.section_svma(".got")),
+            None => {
+                let got = (self.phdr.got? - self.phdr.base_addr) as u64;
+                Some(got..got)
+            }
+        }
+    }
+
+    /// Returns the name and ranges of the module built by [`Object::to_module`].
+    pub fn module_info(&self) -> ModuleInfo {
+        let text_avma = self.phdr.text_avma();
+        ModuleInfo {
+            name: self.phdr.path.to_string_lossy().to_string(),
+            base_avma: (self.phdr.base_addr + self.phdr.base_svma) as u64,
+            avma_range: text_avma.start as u64..text_avma.end as u64,
+        }
     }
 
     pub fn to_module(&self) -> Module<&'_ [u8]> {
-        let name = self.phdr.path.to_string_lossy().to_string();
-        let base_avma = self.phdr.base_addr as u64;
-        let text_range = (self.phdr.base_addr + self.phdr.text.p_vaddr) as u64
-            ..(self.phdr.base_addr + self.phdr.text.p_vaddr + self.phdr.text.p_memsz) as u64;
-
-        let eh_frame_hdr = self.section_range(".eh_frame_hdr");
-        let eh_frame = self.section_range(".eh_frame");
-        let unwind_data = match (&eh_frame_hdr, &eh_frame) {
-            (Some(eh_frame_hdr), Some(eh_frame)) => ModuleUnwindData::EhFrameHdrAndEhFrame(
-                self.range_data(eh_frame_hdr),
-                self.range_data(eh_frame),
+        self.build_module(SAFE_MODE.load(Ordering::Relaxed))
+    }
+
+    /// Like [`Object::to_module`], copying the memory of the process if `safe`.
+    fn build_module(&self, safe: bool) -> Module<&'_ [u8]> {
+        let ModuleInfo {
+            name,
+            base_avma,
+            avma_range: text_range,
+        } = self.module_info();
+
+        let (eh_frame_hdr, eh_frame, got, unwind_data) = match &self.unwind_data {
+            UnwindData::Mmap(mmap) => {
+                let eh_frame_hdr = Self::section_data(mmap, ".eh_frame_hdr");
+                // Without unwind tables, `.eh_frame` only holds the zero terminator.
+                let eh_frame = Self::section_data(mmap, ".eh_frame").filter(|data| data.len() > 4);
+                let unwind_info = Self::section_data(mmap, "__unwind_info");
+                let unwind_data = match (unwind_info, eh_frame_hdr, eh_frame) {
+                    // Mach-O images describe most functions in `__unwind_info` and
+                    // refer to `__eh_frame` for the others.
+                    (Some(unwind_info), _, eh_frame) => {
+                        ModuleUnwindData::CompactUnwindInfoAndEhFrame(unwind_info, eh_frame)
+                    }
+                    // framehop binary searches the table of `.eh_frame_hdr` to find FDEs.
+                    (None, Some(eh_frame_hdr), Some(eh_frame)) => {
+                        ModuleUnwindData::EhFrameHdrAndEhFrame(eh_frame_hdr, eh_frame)
+                    }
+                    (None, None, Some(eh_frame)) => ModuleUnwindData::EhFrame(eh_frame),
+                    (None, _, None) => match self.debug_frame() {
+                        Some(debug_frame) => ModuleUnwindData::DebugFrame(debug_frame),
+                        None => ModuleUnwindData::None,
+                    },
+                };
+                (
+                    self.section_svma(".eh_frame_hdr"),
+                    self.section_svma(".eh_frame"),
+                    self.got_svma(),
+                    unwind_data,
+                )
+            }
+            UnwindData::EhFrame(data) => {
+                // The sections are read directly from the process address space. Their
+                // SVMAs are the AVMAs minus the load bias.
+                let svma = |range: &Range<usize>| {
+                    (range.start - self.phdr.base_addr) as u64
+                        ..(range.end - self.phdr.base_addr) as u64
+                };
+                (
+                    Some(svma(&data.eh_frame_hdr)),
+                    Some(svma(&data.eh_frame)),
+                    self.got_svma(),
+                    match (
+                        self.memory(&data.eh_frame_hdr, safe),
+                        self.memory(&data.eh_frame, safe),
+                    ) {
+                        (Some(eh_frame_hdr), Some(eh_frame)) => {
+                            ModuleUnwindData::EhFrameHdrAndEhFrame(eh_frame_hdr, eh_frame)
+                        }
+                        _ => ModuleUnwindData::None,
+                    },
+                )
+            }
+            UnwindData::CompactUnwind(data) => (
+                None,
+                self.section_svma(".eh_frame"),
+                None,
+                match self.memory(&data.unwind_info, safe) {
+                    Some(unwind_info) => ModuleUnwindData::CompactUnwindInfoAndEhFrame(
+                        unwind_info,
+                        data.eh_frame
+                            .as_ref()
+                            .and_then(|range| self.memory(range, safe)),
+                    ),
+                    None => ModuleUnwindData::None,
+                },
             ),
-            (None, Some(eh_frame)) => ModuleUnwindData::EhFrame(self.range_data(eh_frame)),
-            _ => ModuleUnwindData::None,
+            UnwindData::None => (None, None, None, ModuleUnwindData::None),
         };
 
-        let text_bytes = unsafe {
-            slice::from_raw_parts(
-                (self.phdr.base_addr + self.phdr.text.p_vaddr) as *const u8,
-                self.phdr.text.p_memsz,
-            )
-        };
-        let text_data = TextByteData::new(text_bytes, text_range.clone());
+        let text_data = self.text_data_avma().and_then(|avma| {
+            let bytes = self.memory(&avma, safe)?;
+            Some(TextByteData::new(bytes, avma.start as u64..avma.end as u64))
+        });
 
         Module::new(
             name,
             text_range,
             base_avma,
             ModuleSvmaInfo {
-                // FIXME: should be the vmaddr of the __TEXT segment for mach-O
-                base_svma: 0,
-                text: self.section_range(".text"),
+                base_svma: self.phdr.base_svma as u64,
+                text: self.section_svma(".text"),
                 text_env: None,
-                stubs: None,
-                stub_helper: None,
+                // With IBT, `.plt.sec` holds the stubs that are called and `.plt`
+                // the lazy binding code, like `__stub_helper` in Mach-O.
+                stubs: self
+                    .section_svma("__stubs")
+                    .or_else(|| self.section_svma(".plt.sec"))
+                    .or_else(|| self.section_svma(".plt")),
+                stub_helper: self.section_svma("__stub_helper").or_else(|| {
+                    self.section_svma(".plt.sec")
+                        .and_then(|_| self.section_svma(".plt"))
+                }),
                 eh_frame,
                 eh_frame_hdr,
-                got: self.section_range(".got"),
+                got,
             },
             unwind_data,
-            Some(text_data),
+            text_data,
         )
     }
 
-    pub fn obj_file(&self) -> &'_ object::File<'static, &'static [u8]> {
-        &*self.mmap.obj_file
+    /// Returns the `.debug_frame` section, which some objects have instead of
+    /// `.eh_frame`. Sections compressed with `SHF_COMPRESSED` or named
+    /// `.zdebug_frame` are decompressed the first time. If the object is
+    /// stripped, it is looked up in its separate debug file.
+    fn debug_frame(&self) -> Option<&[u8]> {
+        self.debug_frame
+            .get_or_init(|| {
+                let section = self
+                    .obj_file()?
+                    .section_by_name(".debug_frame")
+                    .or_else(|| self.debug_obj_file()?.section_by_name(".debug_frame"))?;
+                section
+                    .uncompressed_data()
+                    .map_err(|e| {
+                        warning!("Failed to read .debug_frame of {:?}: {e}", self.phdr.path)
+                    })
+                    .ok()
+            })
+            .as_deref()
+    }
+
+    /// Returns where the unwind information of the object comes from.
+    pub fn unwind_source(&self) -> UnwindSource {
+        match self.unwind_data {
+            UnwindData::Mmap(_) => UnwindSource::Mmap,
+            UnwindData::EhFrame(_) => UnwindSource::EhFrame,
+            UnwindData::CompactUnwind(_) => UnwindSource::CompactUnwindInfo,
+            UnwindData::None => UnwindSource::None,
+        }
+    }
+
+    /// Returns the parsed object file, or `None` if the file could not be mapped
+    /// and only the in-memory unwind information is available.
+    pub fn obj_file(&self) -> Option<&'_ object::File<'static, &'static [u8]>> {
+        match &self.unwind_data {
+            UnwindData::Mmap(mmap) => Some(&mmap.obj_file),
+            UnwindData::EhFrame(_) | UnwindData::CompactUnwind(_) | UnwindData::None => None,
+        }
+    }
+
+    fn debug_file(&self) -> Option<&DebugFile> {
+        self.debug_file
+            .get_or_init(|| load_debug_file(&self.phdr.path, self.obj_file()?))
+            .as_ref()
+    }
+
+    /// Returns the path of the separate debug file of the object, found through
+    /// its build-id or `.gnu_debuglink`. It is looked up on the first call.
+    pub fn debug_file_path(&self) -> Option<&Path> {
+        Some(&self.debug_file()?.path)
+    }
+
+    /// Returns the parsed separate debug file of the object. See
+    /// [`Object::debug_file_path`].
+    pub fn debug_obj_file(&self) -> Option<&'_ object::File<'static, &'static [u8]>> {
+        Some(&self.debug_file()?.mmap.obj_file)
     }
 
+    /// Returns the AVMA ranges of the functions named `names` in the symbol
+    /// table of the object, or its dynamic symbol table if it has none.
+    ///
+    /// Functions extend over their ELF symbol size. Symbol sizes are not
+    /// recorded in Mach-O, so there, and for ELF symbols of size 0, each
+    /// function is taken to extend to the next symbol. Names are given without
+    /// the leading underscore that Mach-O adds to C symbols.
+    pub(crate) fn symbol_avmas(&self, names: &[String]) -> Vec<Range<usize>> {
+        use object::{ObjectSymbol, SymbolKind};
+
+        let obj_file = match self.obj_file() {
+            Some(obj_file) => obj_file,
+            None => return Vec::new(),
+        };
+        let symbol_map = obj_file.symbol_map();
+        let sorted = symbol_map.symbols();
+        let next_symbol = |address: u64| {
+            let i = sorted.partition_point(|symbol| symbol.address() <= address);
+            sorted.get(i).map_or(address + 1, |next| next.address())
+        };
+        let symbols = match obj_file.symbols().next() {
+            Some(_) => obj_file.symbols(),
+            None => obj_file.dynamic_symbols(),
+        };
+        symbols
+            .filter(|symbol| symbol.is_definition() && symbol.kind() == SymbolKind::Text)
+            .filter(|symbol| {
+                let Ok(name) = symbol.name() else {
+                    return false;
+                };
+                #[cfg(target_os = "macos")]
+                let name = name.strip_prefix('_').unwrap_or(name);
+                names.iter().any(|n| n == name)
+            })
+            .map(|symbol| {
+                let end = match symbol.size() {
+                    0 => next_symbol(symbol.address()),
+                    size => symbol.address() + size,
+                };
+                self.phdr.base_addr + symbol.address() as usize..self.phdr.base_addr + end as usize
+            })
+            .collect()
+    }
+
+    /// Returns the GNU build-id of the object, read from the `.note.gnu.build-id`
+    /// section of the parsed object file, or from the `PT_NOTE` segments in memory
+    /// if the file could not be mapped. For ELF this is usually a 20-byte SHA1.
+    pub fn build_id(&self) -> Option<Vec<u8>> {
+        match self.obj_file() {
+            Some(obj_file) => obj_file.build_id().ok().flatten().map(|id| id.to_vec()),
+            None => unsafe {
+                find_build_id_in_memory(
+                    self.phdr.base_addr,
+                    &self.phdr.notes,
+                    SAFE_MODE.load(Ordering::Relaxed),
+                )
+            },
+        }
+    }
+
+    /// Returns the file name of the object, e.g. `libc.so.6`.
+    pub fn name(&self) -> Option<&str> {
+        self.phdr.path.file_name()?.to_str()
+    }
+
+    /// Returns the path the object was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.phdr.path
+    }
+
+    /// Returns whether the object is the executable of the process, as opposed
+    /// to a shared library or the vDSO. Objects loaded from files for another
+    /// process or a core dump are never the main executable.
+    pub fn is_main_executable(&self) -> bool {
+        self.phdr.is_main_executable
+    }
+
+    /// Returns the load bias of the object: the AVMA of an address minus its
+    /// SVMA, the address in the object file. Subtracting it from a return
+    /// address gives the address to look up in the symbol table or the debug
+    /// info. On macOS, this is the slide of the image.
     pub fn base_addr(&self) -> usize {
         self.phdr.base_addr
     }
 
+    /// Returns the slide of the image, the offset ASLR added to the addresses
+    /// in the Mach-O file. Same as [`Object::base_addr`].
+    #[cfg(target_os = "macos")]
+    pub fn slide(&self) -> usize {
+        self.phdr.base_addr
+    }
+
+    /// Returns the SVMA that framehop takes as the base of the object, see
+    /// `ModuleSvmaInfo::base_svma`: 0 for ELF objects, and the address of the
+    /// Mach-O header, at the start of `__TEXT`, for Mach-O images. The base
+    /// AVMA of the module, [`ModuleInfo::base_avma`], is
+    /// `base_addr() + base_svma()`.
+    pub fn base_svma(&self) -> usize {
+        self.phdr.base_svma
+    }
+
+    pub fn text_avma(&self) -> Range<usize> {
+        self.phdr.text_avma()
+    }
+
     pub fn text_svma(&self) -> Range<usize> {
         self.phdr.text.p_vaddr..(self.phdr.text.p_vaddr + self.phdr.text.p_memsz)
     }
+
+    /// Returns all executable segments with their permissions, including
+    /// writable ones. [`Object::text_avma`] spans them.
+    pub fn text_segments(&self) -> Vec<TextSegment> {
+        self.phdr
+            .text_segments
+            .iter()
+            .map(|segment| {
+                let start = self.phdr.base_addr + segment.p_vaddr;
+                TextSegment {
+                    avma: start..start + segment.p_memsz,
+                    readable: segment.p_flags & PF_R != 0,
+                    writable: segment.p_flags & PF_W != 0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The note type of a GNU build-id.
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Walks the `Elf64_Nhdr` entries of the loaded note segments looking for the
+/// GNU build-id. Returns `None` if a segment is not readable, e.g. because the
+/// object was unloaded. With `safe`, the segments are copied before they are
+/// read. See [`set_safe_mode`].
+///
+/// # Safety
+///
+/// Unless `safe`, the segments must not be unmapped while they are read.
+unsafe fn find_build_id_in_memory(
+    base_addr: usize,
+    notes: &[Segment],
+    safe: bool,
+) -> Option<Vec<u8>> {
+    // Names and descriptors are padded to 4 bytes.
+    fn align(n: usize) -> usize {
+        (n + 3) & !3
+    }
+
+    for note in notes {
+        let start = base_addr.checked_add(note.p_vaddr)?;
+        let data = if safe {
+            Cow::Owned(crate::addr_validate::copy_memory(start, note.p_memsz)?.into_vec())
+        } else if crate::addr_validate::validate_range(start as _, note.p_memsz) {
+            Cow::Borrowed(slice::from_raw_parts(start as *const u8, note.p_memsz))
+        } else {
+            return None;
+        };
+        let mut offset = 0;
+        // namesz, descsz and type are all 4 bytes.
+        while offset + 12 <= data.len() {
+            let read_u32 = |at: usize| u32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+            let (namesz, descsz, n_type) = (
+                read_u32(offset) as usize,
+                read_u32(offset + 4) as usize,
+                read_u32(offset + 8),
+            );
+            let name_start = offset + 12;
+            let desc_start = name_start + align(namesz);
+            let desc_end = desc_start + descsz;
+            if desc_end > data.len() {
+                break;
+            }
+            if n_type == NT_GNU_BUILD_ID && &data[name_start..name_start + namesz] == b"GNU\0" {
+                return Some(data[desc_start..desc_end].to_vec());
+            }
+            offset = desc_start + align(descsz);
+        }
+    }
+    None
 }
 
 impl Debug for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Object").field("phdr", &self.phdr).finish()
+        f.debug_struct("Object")
+            .field("phdr", &self.phdr)
+            .field("unwind_data", &self.unwind_data)
+            .finish()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_id() {
+        let this_fn = build_id as *const () as usize;
+        let objects = get_objects();
+        let obj = objects
+            .iter()
+            .find(|obj| obj.text_avma().contains(&this_fn))
+            .unwrap();
+        // Linkers on common distributions emit a SHA1 build-id by default.
+        assert_eq!(obj.build_id().unwrap().len(), 20);
+
+        let in_memory =
+            unsafe { find_build_id_in_memory(obj.phdr.base_addr, &obj.phdr.notes, false) };
+        assert_eq!(in_memory, obj.build_id());
+        let copied = unsafe { find_build_id_in_memory(obj.phdr.base_addr, &obj.phdr.notes, true) };
+        assert_eq!(copied, obj.build_id());
+    }
+
+    #[test]
+    fn unwind_source() {
+        let this_fn = unwind_source as *const () as usize;
+        let obj = find_object_for_addr(this_fn).unwrap();
+        assert_eq!(obj.unwind_source(), UnwindSource::Mmap);
+        assert!(obj.obj_file().is_some());
+    }
+
+    #[test]
+    fn base_addr() {
+        let this_fn = base_addr as *const () as usize;
+        let obj = find_object_for_addr(this_fn).unwrap();
+        assert_eq!(
+            obj.text_avma().start - obj.text_svma().start,
+            obj.base_addr()
+        );
+        assert_eq!(
+            obj.module_info().base_avma,
+            (obj.base_addr() + obj.base_svma()) as u64
+        );
+    }
+
+    #[test]
+    fn objects_sorted() {
+        let objects = get_objects();
+        assert!(objects
+            .windows(2)
+            .all(|pair| pair[0].text_avma().end <= pair[1].text_avma().start));
+        for obj in objects.iter() {
+            let text = obj.text_avma();
+            assert!(std::ptr::eq(
+                find_object_for_addr(text.start).unwrap(),
+                *obj
+            ));
+            assert!(std::ptr::eq(
+                find_object_for_addr(text.end - 1).unwrap(),
+                *obj
+            ));
+        }
+    }
+
+    #[test]
+    fn find_object_outside_text() {
+        let objects = get_objects();
+        let first = objects.first().unwrap().text_avma();
+        assert!(find_object_for_addr(first.start - 1).is_none());
+        assert!(find_object_for_addr(0).is_none());
+        let last = objects.last().unwrap().text_avma();
+        assert!(find_object_for_addr(last.end).is_none());
+        let gap = objects
+            .windows(2)
+            .map(|pair| pair[0].text_avma().end..pair[1].text_avma().start)
+            .find(|gap| !gap.is_empty())
+            .unwrap();
+        assert!(find_object_for_addr(gap.start).is_none());
+        assert!(find_object_for_addr(gap.end - 1).is_none());
+    }
+
+    #[test]
+    fn main_executable() {
+        let this_fn = main_executable as *const () as usize;
+        let obj = find_object_for_addr(this_fn).unwrap();
+        assert!(obj.is_main_executable());
+        assert_eq!(obj.path(), std::env::current_exe().unwrap());
+        let libc = find_object_for_addr(libc::getpid as *const () as usize).unwrap();
+        assert!(!libc.is_main_executable());
+        assert_eq!(
+            get_objects()
+                .iter()
+                .filter(|obj| obj.is_main_executable())
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn section_svma_and_data() {
+        use object::ObjectSection;
+
+        let this_fn = section_svma_and_data as *const () as usize;
+        let obj = find_object_for_addr(this_fn).unwrap();
+        let UnwindData::Mmap(mmap) = &obj.unwind_data else {
+            panic!("{:?} is not mapped", obj.path());
+        };
+        let mut offset_differs = false;
+        for name in [".eh_frame_hdr", ".eh_frame", ".got", ".data"] {
+            let section = mmap.obj_file.section_by_name(name).unwrap();
+            let (offset, _) = section.file_range().unwrap();
+            offset_differs |= offset != section.address();
+            // framehop gets the addresses of the sections and the bytes at
+            // their file offsets.
+            assert_eq!(
+                obj.section_svma(name),
+                Some(section.address()..section.address() + section.size())
+            );
+            assert_eq!(Object::section_data(mmap, name), section.data().ok());
+        }
+        // The sections after the first segment usually have an address past
+        // their file offset.
+        assert!(offset_differs);
+    }
+
+    #[test]
+    fn objects_report() {
+        let report = get_objects_report();
+        assert_eq!(report.objects.len(), get_objects().len());
+        let exe = std::env::current_exe().unwrap();
+        assert!(report.failures.iter().all(|(path, _)| *path != exe));
+    }
+
+    #[test]
+    fn load_in_parallel() {
+        let (phdrs, _) = dedup_phdrs(find_phdrs());
+        let paths: Vec<_> = phdrs.iter().map(|phdr| phdr.path.clone()).collect();
+        let objects = load_objects(phdrs, 3);
+        let loaded: Vec<_> = objects.iter().map(|obj| obj.path().to_path_buf()).collect();
+        assert_eq!(loaded, paths);
+        let exe = std::env::current_exe().unwrap();
+        let obj = objects.iter().find(|obj| obj.path() == exe).unwrap();
+        assert_eq!(obj.unwind_source(), UnwindSource::Mmap);
+    }
+
+    #[test]
+    fn sections_in_memory() {
+        let section = |p_vaddr| Segment {
+            p_vaddr,
+            p_memsz: 0x100,
+            p_flags: PF_R | PF_X,
+        };
+        let phdr = ObjectPhdr {
+            base_addr: 0x10000,
+            base_svma: 0x1000,
+            path: PathBuf::from("/usr/lib/libfoo.dylib"),
+            is_main_executable: false,
+            text: section(0x1000),
+            text_segments: Vec::new(),
+            load_segments: Vec::new(),
+            eh_frame_hdr: None,
+            sections: vec![
+                ("__text".to_string(), section(0x1000)),
+                ("__unwind_info".to_string(), section(0x1800)),
+                ("__eh_frame".to_string(), section(0x1900)),
+            ],
+            notes: Vec::new(),
+            got: None,
+        };
+        let obj = Object::new(phdr, UnwindData::None);
+        assert_eq!(obj.section_svma(".text"), Some(0x1000..0x1100));
+        assert_eq!(obj.section_svma("__unwind_info"), Some(0x1800..0x1900));
+        assert_eq!(obj.section_svma(".eh_frame"), Some(0x1900..0x1a00));
+        assert_eq!(obj.section_svma("__stubs"), None);
+    }
+
+    #[test]
+    fn safe_mode() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        let segment = Segment {
+            p_vaddr: page as usize,
+            p_memsz: page_size,
+            p_flags: PF_R | PF_X,
+        };
+        // An object unmapped before its module is built.
+        let unmapped = Object::new(
+            ObjectPhdr {
+                base_addr: 0,
+                base_svma: 0,
+                path: PathBuf::from("/lib/libunmapped.so"),
+                is_main_executable: false,
+                text: segment.clone(),
+                text_segments: vec![segment.clone()],
+                load_segments: Vec::new(),
+                eh_frame_hdr: None,
+                sections: Vec::new(),
+                notes: vec![segment],
+                got: None,
+            },
+            UnwindData::EhFrame(EhFrameData {
+                eh_frame_hdr: page as usize..page as usize + 16,
+                eh_frame: page as usize + 16..page as usize + 64,
+            }),
+        );
+
+        let this_fn = safe_mode as *const () as usize;
+        let obj = find_object_for_addr(this_fn).unwrap();
+        let text = obj.text_data_avma().unwrap();
+        // Not with `set_safe_mode`, which would make the unwinders of the
+        // tests running meanwhile copy the text of every object.
+        let copy = obj.memory(&text, true).unwrap();
+        assert!(unmapped
+            .memory(&unmapped.text_data_avma().unwrap(), true)
+            .is_none());
+        // Without the copies, this would read the unmapped page.
+        unmapped.build_module(true);
+        // Neither does prefaulting it, which is skipped outside safe mode.
+        unmapped.prefault_unwind_data();
+        for safe in [false, true] {
+            let notes = &unmapped.phdr.notes;
+            assert_eq!(unsafe { find_build_id_in_memory(0, notes, safe) }, None);
+        }
+        assert_ne!(copy.as_ptr() as usize, text.start);
+        assert_eq!(copy, obj.memory(&text, false).unwrap());
+
+        unsafe { libc::munmap(page, page_size) };
+    }
+
+    #[test]
+    fn dedup() {
+        let phdr = |base_addr, path: &str, p_vaddr| ObjectPhdr {
+            base_addr,
+            base_svma: 0,
+            path: PathBuf::from(path),
+            is_main_executable: false,
+            text: Segment {
+                p_vaddr,
+                p_memsz: 0x1000,
+                p_flags: PF_R | PF_X,
+            },
+            text_segments: Vec::new(),
+            load_segments: Vec::new(),
+            eh_frame_hdr: None,
+            sections: Vec::new(),
+            notes: Vec::new(),
+            got: None,
+        };
+        let (phdrs, failures) = dedup_phdrs(vec![
+            phdr(0x10000, "/lib/libfoo.so", 0x1000),
+            // The same object opened through a symlink.
+            phdr(0x10000, "/lib/libfoo.so.1", 0x1000),
+            // Overlaps with libfoo.so.
+            phdr(0x10800, "/lib/libbar.so", 0x1000),
+            phdr(0x20000, "/lib/libbaz.so", 0x1000),
+        ]);
+        let paths: Vec<_> = phdrs
+            .iter()
+            .map(|phdr| phdr.path.to_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["/lib/libfoo.so", "/lib/libbaz.so"]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, Path::new("/lib/libbar.so"));
+        assert!(
+            matches!(&failures[0].1, LoadError::Overlap(path) if path == Path::new("/lib/libfoo.so"))
+        );
+    }
+
+    #[test]
+    fn eh_frame_hdr_lookup() {
+        use gimli::{BaseAddresses, EhFrame, EhFrameHdr, LittleEndian, UnwindSection};
+
+        let this_fn = eh_frame_hdr_lookup as *const () as usize;
+        let obj = *get_objects()
+            .iter()
+            .find(|obj| obj.text_avma().contains(&this_fn))
+            .unwrap();
+        let mmap = match &obj.unwind_data {
+            UnwindData::Mmap(mmap) => mmap,
+            _ => panic!("test binary not mapped"),
+        };
+        // The section handed to framehop is the one loaded as PT_GNU_EH_FRAME.
+        let hdr_svma = obj.section_svma(".eh_frame_hdr").unwrap();
+        let eh_frame_svma = obj.section_svma(".eh_frame").unwrap();
+        assert_eq!(
+            hdr_svma.start as usize,
+            obj.phdr.eh_frame_hdr.as_ref().unwrap().p_vaddr
+        );
+
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(hdr_svma.start)
+            .set_eh_frame(eh_frame_svma.start)
+            .set_text(obj.section_svma(".text").unwrap().start);
+        let hdr_data = Object::section_data(mmap, ".eh_frame_hdr").unwrap();
+        let hdr = EhFrameHdr::new(hdr_data, LittleEndian)
+            .parse(&bases, 8)
+            .unwrap();
+        let eh_frame = EhFrame::new(
+            Object::section_data(mmap, ".eh_frame").unwrap(),
+            LittleEndian,
+        );
+        let svma = (this_fn - obj.base_addr()) as u64;
+        let fde = hdr
+            .table()
+            .unwrap()
+            .fde_for_address(&eh_frame, &bases, svma, EhFrame::cie_from_offset)
+            .unwrap();
+        assert!(fde.contains(svma));
+    }
+
+    #[test]
+    fn vdso() {
+        use object::ObjectSymbol;
+
+        let objects = get_objects();
+        let vdso = objects
+            .iter()
+            .find(|obj| obj.name().unwrap().starts_with("linux-vdso"))
+            .unwrap();
+        let obj_file = vdso.obj_file().unwrap();
+        // `__vdso_clock_gettime` on x86_64, `__kernel_clock_gettime` on aarch64.
+        assert!(obj_file.dynamic_symbols().any(|symbol| symbol
+            .name()
+            .is_ok_and(|name| name.ends_with("_clock_gettime"))));
+    }
+
+    #[test]
+    fn refresh_after_dlopen() {
+        refresh_objects();
+        let handle = unsafe { libc::dlopen(c"libm.so.6".as_ptr(), libc::RTLD_NOW) };
+        assert!(!handle.is_null());
+
+        let changes = refresh_objects();
+        assert!(changes.removed.is_empty());
+        assert!(changes
+            .added
+            .iter()
+            .chain(get_objects().iter().map(|obj| &obj.phdr.path))
+            .any(|path| path.ends_with("libm.so.6")));
+    }
+
+    /// Writes a shared object for x86_64 whose only unwind information is a
+    /// `.debug_frame` with one FDE for all of `.text`, at 0x100..0x200, where
+    /// the CFA is `rsp + 16` and the return address is below it.
+    #[cfg(target_arch = "x86_64")]
+    fn debug_frame_only_elf() -> Vec<u8> {
+        use gimli::write::{
+            Address, CallFrameInstruction, CommonInformationEntry, DebugFrame, EndianVec,
+            FrameDescriptionEntry, FrameTable,
+        };
+        use gimli::{Encoding, Format, X86_64};
+
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 1,
+            address_size: 8,
+        };
+        let mut cie = CommonInformationEntry::new(encoding, 1, -8, X86_64::RA);
+        cie.add_instruction(CallFrameInstruction::Cfa(X86_64::RSP, 8));
+        cie.add_instruction(CallFrameInstruction::Offset(X86_64::RA, -8));
+        let mut fde = FrameDescriptionEntry::new(Address::Constant(0x100), 0x100);
+        fde.add_instruction(0, CallFrameInstruction::CfaOffset(16));
+        let mut table = FrameTable::default();
+        let cie = table.add_cie(cie);
+        table.add_fde(cie, fde);
+        let mut debug_frame = DebugFrame::from(EndianVec::new(gimli::LittleEndian));
+        table.write_debug_frame(&mut debug_frame).unwrap();
+        let debug_frame = debug_frame.0.into_vec();
+
+        let shstrtab = b"\0.text\0.debug_frame\0.shstrtab\0";
+        let debug_frame_offset = 0x200;
+        let shstrtab_offset = debug_frame_offset + debug_frame.len();
+        let shoff = (shstrtab_offset + shstrtab.len()).next_multiple_of(8);
+
+        let mut elf = vec![0u8; shoff];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        // ELF64, little endian, a shared object for x86_64.
+        put(0, b"\x7fELF\x02\x01\x01");
+        put(16, &3u16.to_le_bytes());
+        put(18, &62u16.to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+        put(32, &64u64.to_le_bytes());
+        put(40, &(shoff as u64).to_le_bytes());
+        for (offset, value) in [(52, 64), (54, 56), (56, 1), (58, 64), (60, 4), (62, 3)] {
+            put(offset, &(value as u16).to_le_bytes());
+        }
+        // One readable and executable `PT_LOAD` mapping the headers and `.text`.
+        put(64, &1u32.to_le_bytes());
+        put(68, &5u32.to_le_bytes());
+        for (offset, value) in [(88, 0x200), (96, 0x200), (104, 0x1000)] {
+            put(offset, &(value as u64).to_le_bytes());
+        }
+        put(0x100, &[0x90; 0x100]);
+        put(debug_frame_offset, &debug_frame);
+        put(shstrtab_offset, shstrtab);
+
+        // The null section, then (name, type, flags, address, offset, size).
+        let sections = [
+            (1, 1, 6, 0x100, 0x100, 0x100),
+            (7, 1, 0, 0, debug_frame_offset, debug_frame.len()),
+            (20, 3, 0, 0, shstrtab_offset, shstrtab.len()),
+        ];
+        elf.resize(shoff + 64 * (sections.len() + 1), 0);
+        for (i, (name, kind, flags, addr, offset, size)) in sections.into_iter().enumerate() {
+            let shdr = shoff + 64 * (i + 1);
+            elf[shdr..shdr + 4].copy_from_slice(&(name as u32).to_le_bytes());
+            elf[shdr + 4..shdr + 8].copy_from_slice(&(kind as u32).to_le_bytes());
+            for (field, value) in [(8, flags), (16, addr), (24, offset), (32, size), (48, 1)] {
+                elf[shdr + field..shdr + field + 8].copy_from_slice(&(value as u64).to_le_bytes());
+            }
+        }
+        elf
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn unwind_with_debug_frame() {
+        use crate::{CacheNative, MayAllocateDuringUnwind, UnwindRegsNative, Unwinder};
+
+        let elf = debug_frame_only_elf();
+        let path =
+            std::env::temp_dir().join(format!("runwind-debug-frame-{}.so", std::process::id()));
+        std::fs::write(&path, &elf).unwrap();
+        // Load the object where its text is readable: over the bytes of the file.
+        let base = elf.as_ptr() as usize;
+        let obj: &'static Object = Box::leak(Box::new(Object::from_file(&path, base).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(obj.section_svma(".eh_frame").is_none());
+
+        const SP: u64 = 0x10000;
+        const RETURN_ADDRESS: u64 = 0x4242;
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::from_objects(&[obj]);
+        let mut cache = CacheNative::new();
+        let pc = base + 0x110;
+        // The frame pointer leads nowhere, so only `.debug_frame` finds the caller.
+        let regs = UnwindRegsNative::new(pc as u64, SP, 0);
+        let mut iter = unwinder
+            .iter_frames_with_regs(pc, regs, &mut cache)
+            .with_memory(|addr| match addr {
+                _ if addr == SP + 8 => Ok(RETURN_ADDRESS),
+                _ => Err(()),
+            });
+        assert_eq!(iter.try_next(), Ok(Some(RETURN_ADDRESS as usize)));
+    }
+
+    #[test]
+    fn symbol_size() {
+        use object::ObjectSymbol;
+
+        let objects = get_objects();
+        let libc = objects
+            .iter()
+            .find(|obj| obj.phdr.path.ends_with("libc.so.6"))
+            .unwrap();
+        let getpid = libc
+            .obj_file()
+            .unwrap()
+            .dynamic_symbols()
+            .find(|symbol| symbol.name() == Ok("getpid"))
+            .unwrap();
+        let start = libc.phdr.base_addr + getpid.address() as usize;
+        let getpid = start..start + getpid.size() as usize;
+        assert_eq!(libc.symbol_avmas(&["getpid".to_string()]), [getpid]);
+    }
+
+    #[test]
+    fn refresh_after_dlclose() {
+        let loaded = || {
+            get_objects()
+                .iter()
+                .any(|obj| obj.phdr.path.ends_with("libz.so.1"))
+        };
+        let handle = unsafe { libc::dlopen(c"libz.so.1".as_ptr(), libc::RTLD_NOW) };
+        assert!(!handle.is_null());
+        refresh_objects();
+        assert!(loaded());
+
+        assert_eq!(unsafe { libc::dlclose(handle) }, 0);
+        refresh_objects();
+        assert!(!loaded());
+    }
+
+    #[test]
+    fn not_reused_after_file_changed() {
+        let libc = get_objects()
+            .iter()
+            .find(|obj| obj.phdr.path.ends_with("libc.so.6"))
+            .unwrap()
+            .phdr
+            .path
+            .clone();
+        let path = std::env::temp_dir().join(format!("runwind-reuse-{}.so", std::process::id()));
+        std::fs::copy(libc, &path).unwrap();
+        let obj = Object::from_file(&path, 0).unwrap();
+        assert!(is_reusable(&obj, &obj.phdr));
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        let mtime = file.metadata().unwrap().modified().unwrap();
+        file.set_modified(mtime + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert!(!is_reusable(&obj, &obj.phdr));
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,41 +1,174 @@
 use std::{
     env,
-    ffi::{CStr, OsString},
+    ffi::{CStr, OsStr, OsString},
     fs::File,
-    mem::ManuallyDrop,
-    os::unix::prelude::OsStringExt,
+    mem::{size_of, ManuallyDrop},
+    os::unix::prelude::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
     slice,
 };
 
-use libc::{c_int, c_void, dl_iterate_phdr, dl_phdr_info, size_t, PT_LOAD};
-use log::warn;
-use memmap2::Mmap;
-use once_cell::sync::Lazy;
-
-use super::{Object, ObjectPhdr, Segment};
+use gimli::{BaseAddresses, EhFrameHdr, LittleEndian, Pointer};
+use libc::{
+    c_int, c_void, dl_iterate_phdr, dl_phdr_info, size_t, PT_DYNAMIC, PT_GNU_EH_FRAME, PT_LOAD,
+    PT_NOTE,
+};
+use memmap2::{Mmap, MmapMut};
 
-static OBJECTS: Lazy<Vec<Object>> = Lazy::new(find_objects);
+use object::Object as _;
 
-pub fn get_objects() -> &'static [Object] {
-    &OBJECTS
-}
+use super::{DebugFile, EhFrameData, Object, ObjectPhdr, Segment, UnwindData, PF_X};
+use crate::diagnostic::{info, warning};
+use crate::LoadError;
 
-fn find_objects() -> Vec<Object> {
-    let mut objects = Vec::new();
+/// Collects the program headers of all loaded objects.
+pub fn find_phdrs() -> Vec<ObjectPhdr> {
+    let mut phdrs = Vec::new();
     unsafe {
         dl_iterate_phdr(
             Some(iterate_phdr_cb),
-            &mut objects as *mut Vec<Object> as *mut c_void,
+            &mut phdrs as *mut Vec<ObjectPhdr> as *mut c_void,
         );
     }
-    objects
+    phdrs
+}
+
+/// Loads the unwind information of an object, preferably by mapping its file.
+pub fn load_object(phdr: ObjectPhdr) -> Object {
+    // The vDSO has no backing file, but its whole image is mapped.
+    let mmap = match vdso_image(&phdr) {
+        Some(image) => ObjectMmap::from_memory(image),
+        None => ObjectMmap::new(&phdr.path),
+    };
+    let (mmap, load_error) = match mmap {
+        Ok(mmap) => (Some(mmap), None),
+        Err(e) => {
+            warning!("Failed to map {:?}: {e}", phdr.path);
+            (None, Some(e))
+        }
+    };
+    let unwind_data = match mmap {
+        Some(mmap) => UnwindData::Mmap(Box::new(mmap)),
+        None => match phdr
+            .eh_frame_hdr
+            .as_ref()
+            .and_then(|hdr| unsafe { find_eh_frame(phdr.base_addr, hdr, &phdr.load_segments) })
+        {
+            Some(eh_frame) => {
+                info!("Use .eh_frame in memory for {:?}", phdr.path);
+                UnwindData::EhFrame(eh_frame)
+            }
+            None => {
+                warning!(
+                    "Cannot mmap or find .eh_frame for {:?}, unwinding it with frame pointers",
+                    phdr.path
+                );
+                UnwindData::None
+            }
+        },
+    };
+    let mut obj = Object::new(phdr, unwind_data);
+    obj.load_error = load_error;
+    obj
+}
+
+/// The global directory of separate debug files, as in GDB.
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// Finds and maps the separate debug file of the object at `path`, first by
+/// build-id and then through `.gnu_debuglink`, in the same places as GDB.
+pub fn load_debug_file(path: &Path, obj_file: &object::File) -> Option<DebugFile> {
+    if let Some(build_id) = obj_file.build_id().ok().flatten().filter(|id| id.len() > 1) {
+        let hex: String = build_id.iter().map(|b| format!("{b:02x}")).collect();
+        let debug_path = Path::new(DEBUG_DIR)
+            .join(".build-id")
+            .join(&hex[..2])
+            .join(format!("{}.debug", &hex[2..]));
+        if debug_path.is_file() {
+            if let Ok(mmap) = ObjectMmap::new(&debug_path) {
+                return Some(DebugFile {
+                    path: debug_path,
+                    mmap,
+                });
+            }
+        }
+    }
+
+    let (name, crc) = obj_file.gnu_debuglink().ok().flatten()?;
+    let name = Path::new(OsStr::from_bytes(name));
+    let path = path.canonicalize().ok()?;
+    let dir = path.parent()?;
+    [
+        dir.join(name),
+        dir.join(".debug").join(name),
+        Path::new(DEBUG_DIR)
+            .join(dir.strip_prefix("/").ok()?)
+            .join(name),
+    ]
+    .into_iter()
+    .filter(|debug_path| *debug_path != path && debug_path.is_file())
+    .find_map(|debug_path| {
+        let mmap = ObjectMmap::new(&debug_path)
+            .map_err(|e| warning!("Failed to map {debug_path:?}: {e}"))
+            .ok()?;
+        if crc32(&mmap.mmap) != crc {
+            warning!("CRC of {debug_path:?} does not match the .gnu_debuglink of {path:?}");
+            return None;
+        }
+        Some(DebugFile {
+            path: debug_path,
+            mmap,
+        })
+    })
+}
+
+/// The CRC-32 used by `.gnu_debuglink`, as in zlib.
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    0xedb8_8320 ^ (crc >> 1)
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!0, |crc, b| {
+        TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Returns the mapped ELF image if `phdr` describes the vDSO.
+#[cfg(target_os = "linux")]
+fn vdso_image(phdr: &ObjectPhdr) -> Option<&'static [u8]> {
+    let start = unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) } as usize;
+    let text_start = phdr.base_addr + phdr.text.p_vaddr;
+    let text_end = text_start + phdr.text.p_memsz;
+    if start == 0 || !(text_start..text_end).contains(&start) {
+        return None;
+    }
+    // The section headers lie beyond the loaded segments, but the kernel maps
+    // the image as a whole.
+    let ehdr = unsafe { &*(start as *const libc::Elf64_Ehdr) };
+    let shdrs_end = ehdr.e_shoff as usize + ehdr.e_shnum as usize * ehdr.e_shentsize as usize;
+    let len = shdrs_end.max(text_end - start);
+    Some(unsafe { slice::from_raw_parts(start as *const u8, len) })
 }
 
-/// An executable segment.
-const PF_X: u32 = 1;
-/// A readable segment.
-const PF_R: u32 = 4;
+#[cfg(not(target_os = "linux"))]
+fn vdso_image(_phdr: &ObjectPhdr) -> Option<&'static [u8]> {
+    None
+}
 
 unsafe extern "C" fn iterate_phdr_cb(
     info: *mut dl_phdr_info,
@@ -46,11 +179,12 @@ unsafe extern "C" fn iterate_phdr_cb(
     let base_addr = info.dlpi_addr as usize;
 
     // The dlpi_name of the current executable is a empty C string.
-    let path = if *info.dlpi_name == 0 {
+    let is_main_executable = *info.dlpi_name == 0;
+    let path = if is_main_executable {
         match env::current_exe() {
             Ok(path) => path,
             Err(e) => {
-                warn!("Could not get current executable path: {e}");
+                warning!("Could not get current executable path: {e}");
                 return 0;
             }
         }
@@ -59,70 +193,221 @@ unsafe extern "C" fn iterate_phdr_cb(
             CStr::from_ptr(info.dlpi_name).to_bytes().to_vec(),
         ))
     };
-    let mut text = None;
+    let mut text_segments = Vec::new();
+    let mut load_segments = Vec::new();
+    let mut eh_frame_hdr = None;
+    let mut notes = Vec::new();
+    let mut dynamic = None;
 
     let phdrs = slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
     for phdr in phdrs {
         let segment = Segment {
             p_vaddr: phdr.p_vaddr as usize,
             p_memsz: phdr.p_memsz as usize,
+            p_flags: phdr.p_flags,
         };
         match phdr.p_type {
-            // .text segment
-            PT_LOAD if phdr.p_flags == PF_X | PF_R => {
-                if text.is_some() {
-                    warn!("Multiple text segments found in {path:?}");
+            PT_LOAD => {
+                // Executable segments. Linkers using `-z separate-code` may
+                // emit several of them.
+                if phdr.p_flags & PF_X != 0 {
+                    text_segments.push(segment.clone());
                 }
-                text = Some(segment);
+                load_segments.push(segment);
             }
+            // .eh_frame_hdr section
+            PT_GNU_EH_FRAME => eh_frame_hdr = Some(segment),
+            PT_NOTE => notes.push(segment),
+            PT_DYNAMIC => dynamic = Some(segment),
             // Ignore other segments
             _ => {}
         }
     }
 
-    let text = match text {
-        Some(text) => text,
+    let start = text_segments.iter().map(|s| s.p_vaddr).min();
+    let end = text_segments.iter().map(|s| s.p_vaddr + s.p_memsz).max();
+    let text = match start.zip(end) {
+        Some((start, end)) => Segment {
+            p_vaddr: start,
+            p_memsz: end - start,
+            p_flags: text_segments.iter().fold(0, |flags, s| flags | s.p_flags),
+        },
         None => {
-            warn!("No text segment found in {path:?}");
+            warning!("No text segment found in {path:?}");
             return 0;
         }
     };
 
-    let phdr = ObjectPhdr {
+    let phdrs = &mut *(data as *mut Vec<ObjectPhdr>);
+    phdrs.push(ObjectPhdr {
         base_addr,
+        base_svma: 0,
         path,
+        is_main_executable,
         text,
+        text_segments,
+        load_segments,
+        eh_frame_hdr,
+        sections: Vec::new(),
+        notes,
+        got: dynamic.and_then(|dynamic| find_pltgot(base_addr, &dynamic)),
+    });
+
+    0
+}
+
+/// The dynamic entry holding the address of the GOT used by the PLT.
+const DT_PLTGOT: u64 = 3;
+
+/// Reads the `DT_PLTGOT` entry of the loaded dynamic segment.
+///
+/// # Safety
+///
+/// The segment must be mapped at `base_addr` in the current process.
+unsafe fn find_pltgot(base_addr: usize, dynamic: &Segment) -> Option<usize> {
+    // `Elf64_Dyn` entries, terminated by `DT_NULL`.
+    let entries = slice::from_raw_parts(
+        (base_addr + dynamic.p_vaddr) as *const [u64; 2],
+        dynamic.p_memsz / size_of::<[u64; 2]>(),
+    );
+    let [_, got] = entries
+        .iter()
+        .take_while(|[tag, _]| *tag != 0)
+        .find(|[tag, _]| *tag == DT_PLTGOT)?;
+    let got = *got as usize;
+    // glibc relocates the addresses in the dynamic segment in place, but other
+    // loaders such as musl leave them as SVMAs, which are below the load bias.
+    Some(if got >= base_addr {
+        got
+    } else {
+        got + base_addr
+    })
+}
+
+/// Locates `.eh_frame` in memory through the `.eh_frame_hdr` pointed to by the
+/// `PT_GNU_EH_FRAME` segment.
+///
+/// The end of `.eh_frame` is not recorded anywhere in memory, so it is found by
+/// walking the CIE/FDE records until the zero terminator or the end of the
+/// `PT_LOAD` segment containing it. Records running past the segment are
+/// treated as corrupted. Every byte is validated before it is read, so
+/// unreadable sections make this return `None` rather than crash.
+unsafe fn find_eh_frame(
+    base_addr: usize,
+    eh_frame_hdr: &Segment,
+    load_segments: &[Segment],
+) -> Option<EhFrameData> {
+    let hdr_start = base_addr + eh_frame_hdr.p_vaddr;
+    // Sandboxes may map the unwind sections without read permission.
+    if !crate::addr_validate::validate_range(hdr_start as *const c_void, eh_frame_hdr.p_memsz) {
+        warning!(".eh_frame_hdr at {hdr_start:#x} is not readable");
+        return None;
+    }
+    let hdr_data = slice::from_raw_parts(hdr_start as *const u8, eh_frame_hdr.p_memsz);
+    let bases = BaseAddresses::default().set_eh_frame_hdr(hdr_start as u64);
+    let parsed = EhFrameHdr::new(hdr_data, LittleEndian)
+        .parse(&bases, size_of::<usize>() as u8)
+        .map_err(|e| warning!("Failed to parse .eh_frame_hdr at {hdr_start:#x}: {e}"))
+        .ok()?;
+    let eh_frame_start = match parsed.eh_frame_ptr() {
+        Pointer::Direct(addr) => addr as usize,
+        // The address is stored at this address, usually in the GOT.
+        Pointer::Indirect(addr) => match crate::addr_validate::read_u64(addr as usize) {
+            Some(eh_frame_start) => eh_frame_start as usize,
+            None => {
+                warning!("Indirect .eh_frame pointer at {addr:#x} is not readable");
+                return None;
+            }
+        },
     };
-    if let Some(mmap) = ObjectMmap::new(&phdr.path) {
-        let objects = &mut *(data as *mut Vec<Object>);
-        objects.push(Object { phdr, mmap });
+
+    let Some(segment_end) = load_segments.iter().find_map(|segment| {
+        let start = base_addr + segment.p_vaddr;
+        let end = start + segment.p_memsz;
+        (start..end).contains(&eh_frame_start).then_some(end)
+    }) else {
+        warning!(".eh_frame at {eh_frame_start:#x} is outside of the loaded segments");
+        return None;
+    };
+
+    // Points to the `len` bytes at `addr` if they are in the segment and readable.
+    let read = |addr: usize, len: usize| {
+        (addr.checked_add(len)? <= segment_end
+            && crate::addr_validate::validate_range(addr as *const c_void, len))
+        .then_some(addr as *const u8)
+    };
+    let corrupted = |addr: usize| warning!("Corrupted .eh_frame record at {addr:#x}");
+
+    let mut addr = eh_frame_start;
+    // Every record takes at least 4 bytes, so this ends at the segment end.
+    while addr < segment_end {
+        let Some(len_ptr) = read(addr, 4) else {
+            corrupted(addr);
+            return None;
+        };
+        let len = (len_ptr as *const u32).read_unaligned();
+        if len == 0 {
+            // Zero terminator
+            addr += 4;
+            break;
+        }
+        let record_len = if len == 0xffff_ffff {
+            // 64-bit DWARF: the real length follows
+            let Some(len_ptr) = read(addr + 4, 8) else {
+                corrupted(addr);
+                return None;
+            };
+            (len_ptr as *const u64)
+                .read_unaligned()
+                .checked_add(12)
+                .and_then(|len| usize::try_from(len).ok())
+        } else {
+            Some(4 + len as usize)
+        };
+        match record_len.and_then(|len| read(addr, len).map(|_| len)) {
+            Some(len) => addr += len,
+            None => {
+                corrupted(addr);
+                return None;
+            }
+        }
     }
 
-    0
+    Some(EhFrameData {
+        eh_frame_hdr: hdr_start..hdr_start + eh_frame_hdr.p_memsz,
+        eh_frame: eh_frame_start..addr,
+    })
 }
 
 pub struct ObjectMmap {
-    pub file: ManuallyDrop<File>,
+    /// `None` if the mmap is an anonymous copy of an image in memory.
+    pub file: ManuallyDrop<Option<File>>,
     pub mmap: ManuallyDrop<Mmap>,
     pub obj_file: ManuallyDrop<object::File<'static, &'static [u8]>>,
 }
 
 impl ObjectMmap {
-    fn new(path: &Path) -> Option<ObjectMmap> {
-        let file = File::open(path)
-            .map_err(|e| warn!("Failed to open {path:?}: {e}"))
-            .ok()?;
-        let mmap = unsafe {
-            Mmap::map(&file)
-                .map_err(|e| warn!("Failed to mmap {path:?}: {e}"))
-                .ok()?
-        };
+    pub fn new(path: &Path) -> Result<ObjectMmap, LoadError> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::parse(Some(file), mmap)
+    }
+
+    /// Copies an object image found in memory into an anonymous mmap.
+    fn from_memory(image: &[u8]) -> Result<ObjectMmap, LoadError> {
+        let mut mmap = MmapMut::map_anon(image.len())?;
+        mmap.copy_from_slice(image);
+        Self::parse(None, mmap.make_read_only()?)
+    }
+
+    fn parse(file: Option<File>, mmap: Mmap) -> Result<ObjectMmap, LoadError> {
+        // Extend the lifetime of the mapped bytes to 'static. This is sound because
+        // `obj_file` is the only borrower and it is dropped before `mmap` (see the
+        // `Drop` impl below), and `Object`s are never dropped once discovered.
         let (ptr, len) = (mmap.as_ptr(), mmap.len());
         let data = unsafe { slice::from_raw_parts(ptr, len) };
-        let obj_file = object::File::parse(data)
-            .map_err(|e| warn!("Failed to parse {path:?}: {e}"))
-            .ok()?;
-        Some(ObjectMmap {
+        let obj_file = object::File::parse(data)?;
+        Ok(ObjectMmap {
             file: ManuallyDrop::new(file),
             mmap: ManuallyDrop::new(mmap),
             obj_file: ManuallyDrop::new(obj_file),
@@ -143,3 +428,194 @@ impl Drop for ObjectMmap {
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multiple_text_segments() {
+        let segment = |p_flags, p_vaddr, p_memsz| {
+            let mut phdr: libc::Elf64_Phdr = unsafe { std::mem::zeroed() };
+            phdr.p_type = PT_LOAD;
+            phdr.p_flags = p_flags;
+            phdr.p_vaddr = p_vaddr;
+            phdr.p_memsz = p_memsz;
+            phdr
+        };
+        // The layout of `-z separate-code` with code in two segments, the
+        // second one also writable.
+        let segments = [
+            segment(4, 0, 0x1000),
+            segment(5, 0x1000, 0x2000),
+            segment(4, 0x3000, 0x1000),
+            segment(7, 0x4000, 0x800),
+            segment(6, 0x5000, 0x1000),
+        ];
+        let mut info: dl_phdr_info = unsafe { std::mem::zeroed() };
+        info.dlpi_addr = 0x10000;
+        info.dlpi_name = c"libfake.so".as_ptr();
+        info.dlpi_phdr = segments.as_ptr();
+        info.dlpi_phnum = segments.len() as _;
+
+        let mut phdrs = Vec::<ObjectPhdr>::new();
+        unsafe {
+            iterate_phdr_cb(
+                &mut info,
+                size_of::<dl_phdr_info>(),
+                &mut phdrs as *mut Vec<ObjectPhdr> as *mut c_void,
+            )
+        };
+        assert_eq!(phdrs.len(), 1);
+        assert_eq!(phdrs[0].text_avma(), 0x11000..0x14800);
+        assert_eq!(phdrs[0].text_segments.len(), 2);
+
+        // The file doesn't exist and there is no `PT_GNU_EH_FRAME`, but the
+        // object is kept for frame pointer unwinding.
+        let obj = load_object(phdrs.pop().unwrap());
+        assert_eq!(obj.unwind_source(), crate::UnwindSource::None);
+        assert!(
+            matches!(&obj.load_error, Some(LoadError::Map(e)) if e.kind() == std::io::ErrorKind::NotFound)
+        );
+        assert_eq!(obj.text_avma(), 0x11000..0x14800);
+        let writable: Vec<_> = obj
+            .text_segments()
+            .into_iter()
+            .map(|segment| (segment.avma, segment.writable))
+            .collect();
+        assert_eq!(
+            writable,
+            [(0x11000..0x13000, false), (0x14000..0x14800, true)]
+        );
+    }
+
+    #[test]
+    fn pltgot() {
+        let exe = std::env::current_exe().unwrap();
+        let phdr = find_phdrs()
+            .into_iter()
+            .find(|phdr| phdr.path == exe)
+            .unwrap();
+        let got = phdr.got.unwrap();
+        let base_addr = phdr.base_addr;
+        let obj = load_object(phdr);
+        let got_plt = obj.section_svma(".got.plt").unwrap();
+        assert_eq!(got, got_plt.start as usize + base_addr);
+        // The mapped file gives the same GOT as the dynamic segment.
+        assert_eq!(obj.got_svma(), Some(got_plt));
+    }
+
+    #[test]
+    fn indirect_eh_frame_ptr() {
+        // An empty `.eh_frame` and the pointer to it.
+        let eh_frame = 0u32;
+        let eh_frame_ptr = &eh_frame as *const u32 as usize;
+        // Version 1, `DW_EH_PE_indirect | DW_EH_PE_udata8` for `eh_frame_ptr`,
+        // no search table.
+        let mut hdr = vec![1u8, 0x84, 0xff, 0xff];
+        hdr.extend_from_slice(&(&eh_frame_ptr as *const usize as u64).to_ne_bytes());
+        let segment = |hdr: &[u8]| Segment {
+            p_vaddr: hdr.as_ptr() as usize,
+            p_memsz: hdr.len(),
+            p_flags: 0,
+        };
+
+        let loads = [Segment {
+            p_vaddr: eh_frame_ptr,
+            p_memsz: 4,
+            p_flags: 0,
+        }];
+
+        let data = unsafe { find_eh_frame(0, &segment(&hdr), &loads) }.unwrap();
+        assert_eq!(data.eh_frame, eh_frame_ptr..eh_frame_ptr + 4);
+
+        // The pointer can't be read.
+        hdr[4..].copy_from_slice(&0u64.to_ne_bytes());
+        assert!(unsafe { find_eh_frame(0, &segment(&hdr), &loads) }.is_none());
+    }
+
+    #[test]
+    fn eh_frame_bounds() {
+        let mut eh_frame = [0u8; 36];
+        let eh_frame_ptr = eh_frame.as_ptr() as usize;
+        // A record of 12 bytes, followed by one claiming 24 more bytes, which
+        // would end past the segment.
+        eh_frame[..4].copy_from_slice(&8u32.to_ne_bytes());
+        eh_frame[12..16].copy_from_slice(&20u32.to_ne_bytes());
+        // Version 1, `DW_EH_PE_absptr` for `eh_frame_ptr`, no search table.
+        let mut hdr = vec![1u8, 0x00, 0xff, 0xff];
+        hdr.extend_from_slice(&(eh_frame_ptr as u64).to_ne_bytes());
+        let hdr_segment = Segment {
+            p_vaddr: hdr.as_ptr() as usize,
+            p_memsz: hdr.len(),
+            p_flags: 0,
+        };
+        let find = |len| {
+            let loads = [Segment {
+                p_vaddr: eh_frame_ptr,
+                p_memsz: len,
+                p_flags: 0,
+            }];
+            unsafe { find_eh_frame(0, &hdr_segment, &loads) }.map(|data| data.eh_frame)
+        };
+
+        // Without a terminator, `.eh_frame` ends with the segment.
+        assert_eq!(find(12), Some(eh_frame_ptr..eh_frame_ptr + 12));
+        assert_eq!(find(32), None);
+        assert_eq!(find(36), Some(eh_frame_ptr..eh_frame_ptr + 36));
+        // A 64-bit length overflowing the address space.
+        eh_frame[12..16].copy_from_slice(&u32::MAX.to_ne_bytes());
+        eh_frame[16..24].copy_from_slice(&u64::MAX.to_ne_bytes());
+        assert_eq!(find(36), None);
+        // Outside of the loaded segments.
+        assert_eq!(find(0), None);
+    }
+
+    #[test]
+    fn unreadable_eh_frame() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(page, libc::MAP_FAILED);
+        let page = page as usize;
+        let loads = [Segment {
+            p_vaddr: page,
+            p_memsz: page_size,
+            p_flags: 0,
+        }];
+
+        // `.eh_frame_hdr` itself can't be read.
+        let unreadable_hdr = Segment {
+            p_vaddr: page,
+            p_memsz: 12,
+            p_flags: 0,
+        };
+        assert!(unsafe { find_eh_frame(0, &unreadable_hdr, &loads) }.is_none());
+
+        // `.eh_frame_hdr` points to an unreadable `.eh_frame`.
+        let mut hdr = vec![1u8, 0x00, 0xff, 0xff];
+        hdr.extend_from_slice(&(page as u64).to_ne_bytes());
+        let hdr_segment = Segment {
+            p_vaddr: hdr.as_ptr() as usize,
+            p_memsz: hdr.len(),
+            p_flags: 0,
+        };
+        assert!(unsafe { find_eh_frame(0, &hdr_segment, &loads) }.is_none());
+
+        unsafe { libc::munmap(page as _, page_size) };
+    }
+
+    #[test]
+    fn debuglink_crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
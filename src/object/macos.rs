@@ -1,32 +1,370 @@
-use std::mem::ManuallyDrop;
+// The dyld and mach-o definitions in libc are deprecated in favor of the mach2
+// crate, but they are all we need here.
+#![allow(deprecated)]
 
+use std::{
+    ffi::{CStr, OsString},
+    fs::File,
+    io,
+    mem::{size_of, ManuallyDrop},
+    os::unix::prelude::OsStringExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use libc::{load_command, mach_header_64, segment_command_64, LC_SEGMENT_64, MH_MAGIC_64};
 use memmap2::Mmap;
-use once_cell::sync::Lazy;
+use object::{
+    macho::{Section64, MH_EXECUTE},
+    read::macho::{FatArch, FatHeader, Section as _},
+    FileKind, NativeEndian, Object as _,
+};
+
+use super::{
+    elf_flags_from_vm_prot, CompactUnwindData, DebugFile, Object, ObjectPhdr, Segment, UnwindData,
+};
+use crate::diagnostic::{info, warning};
+use crate::LoadError;
+
+/// Collects the __TEXT segments of all loaded images.
+pub fn find_phdrs() -> Vec<ObjectPhdr> {
+    let mut phdrs = Vec::new();
+    let n = unsafe { libc::_dyld_image_count() };
+    for i in 0..n {
+        if let Some(phdr) = find_phdr(i) {
+            phdrs.push(phdr);
+        }
+    }
+    phdrs
+}
 
-use super::Object;
+/// Loads the unwind information of an image by mapping its file, or from the
+/// process memory if the file can't be mapped.
+pub fn load_object(phdr: ObjectPhdr) -> Object {
+    let e = match ObjectMmap::new(&phdr.path) {
+        Ok(mmap) => return Object::new(phdr, UnwindData::Mmap(Box::new(mmap))),
+        Err(e) => e,
+    };
+    let unwind_data = match phdr.section("__unwind_info") {
+        Some(unwind_info) => {
+            // The libraries in the dyld shared cache have no files, but their
+            // `__TEXT` segment is mapped, including the unwind sections, and
+            // their load commands tell where the sections are.
+            if matches!(&e, LoadError::Map(e) if e.kind() == io::ErrorKind::NotFound) {
+                info!("Use the image in memory for {:?}", phdr.path);
+            } else {
+                warning!(
+                    "Failed to map {:?}, using the image in memory: {e}",
+                    phdr.path
+                );
+            }
+            let avma = |section: &Segment| {
+                let start = phdr.base_addr + section.p_vaddr;
+                start..start + section.p_memsz
+            };
+            UnwindData::CompactUnwind(CompactUnwindData {
+                unwind_info: avma(unwind_info),
+                eh_frame: phdr.section("__eh_frame").map(avma),
+            })
+        }
+        None => {
+            warning!(
+                "Failed to map {:?}, unwinding it with frame pointers: {e}",
+                phdr.path
+            );
+            UnwindData::None
+        }
+    };
+    let mut obj = Object::new(phdr, unwind_data);
+    obj.load_error = Some(e);
+    obj
+}
 
-static OBJECTS: Lazy<Vec<Object>> = Lazy::new(find_objects);
+static DSYM_COMMANDS: AtomicBool = AtomicBool::new(false);
 
-pub fn get_objects() -> &'static [Object] {
-    &OBJECTS
+/// Makes the search for the dSYM bundle of an image without one next to it
+/// ask Spotlight with `mdfind`, then run the `DBGShellCommands` of the
+/// `com.apple.DebugSymbols` defaults, like LLDB. By default, no command is run.
+///
+/// This spawns up to three processes per image when its debug info is first
+/// needed, and `DBGShellCommands` may name any program.
+pub fn set_dsym_lookup_commands(enabled: bool) {
+    DSYM_COMMANDS.store(enabled, Ordering::Relaxed);
 }
 
-fn find_objects() -> Vec<Object> {
-    let mut objects = Vec::new();
-    let n = unsafe { libc::_dyld_image_count() };
-    for i in 0..n {
-        if let Some(obj) = load_object(i) {
-            objects.push(obj);
+/// Finds and maps the DWARF file in the dSYM bundle of the image at `path`,
+/// in the same places as LLDB: next to the image or the bundle containing it,
+/// then, if enabled with [`set_dsym_lookup_commands`], through Spotlight and
+/// the `DBGShellCommands` of the `com.apple.DebugSymbols` defaults. The file
+/// must have the UUID of the image.
+pub fn load_debug_file(path: &Path, obj_file: &object::File) -> Option<DebugFile> {
+    let uuid = obj_file.mach_uuid().ok().flatten()?;
+    // e.g. `Foo.app/Contents/MacOS/Foo` has its debug info in `Foo.app.dSYM`.
+    let mut next_to_image = path.ancestors().take(4).filter_map(|ancestor| {
+        let mut dsym = ancestor.as_os_str().to_owned();
+        dsym.push(".dSYM");
+        load_dsym(Path::new(&dsym), &uuid)
+    });
+    let uuid_string = format_uuid(&uuid);
+    let from_spotlight = || {
+        run(Command::new("mdfind").arg(format!("com_apple_xcode_dsym_uuids == {uuid_string}")))?
+            .lines()
+            .find_map(|bundle| load_dsym(Path::new(bundle), &uuid))
+    };
+    let from_shell_command = || {
+        let command = run(Command::new("defaults").args([
+            "read",
+            "com.apple.DebugSymbols",
+            "DBGShellCommands",
+        ]))?;
+        let plist = run(Command::new(command.trim()).arg(&uuid_string))?;
+        let debug_file =
+            dsym_paths_in_plist(&plist).find_map(|dwarf| load_dwarf_file(Path::new(dwarf), &uuid));
+        debug_file
+    };
+    next_to_image.next().or_else(|| {
+        DSYM_COMMANDS
+            .load(Ordering::Relaxed)
+            .then(|| from_spotlight().or_else(from_shell_command))?
+    })
+}
+
+/// Maps the DWARF file with `uuid` in the dSYM bundle at `bundle`.
+fn load_dsym(bundle: &Path, uuid: &[u8; 16]) -> Option<DebugFile> {
+    // A bundle of a universal binary has one file with the slices of all
+    // architectures, and some bundles hold the files of several images.
+    std::fs::read_dir(bundle.join("Contents/Resources/DWARF"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| load_dwarf_file(&entry.path(), uuid))
+}
+
+fn load_dwarf_file(path: &Path, uuid: &[u8; 16]) -> Option<DebugFile> {
+    let mmap = ObjectMmap::new(path)
+        .map_err(|e| warning!("Failed to map {path:?}: {e}"))
+        .ok()?;
+    if mmap.obj_file.mach_uuid().ok().flatten() != Some(*uuid) {
+        return None;
+    }
+    Some(DebugFile {
+        path: path.to_path_buf(),
+        mmap,
+    })
+}
+
+/// Runs `command` and returns its standard output if it succeeds.
+fn run(command: &mut Command) -> Option<String> {
+    let output = command.stderr(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Formats a UUID like `dwarfdump --uuid`, e.g.
+/// `4C4C44B5-5555-3144-A1B4-3F2E1D0C0B0A`.
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut s = String::with_capacity(36);
+    for (i, byte) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
         }
+        s.push_str(&format!("{byte:02X}"));
     }
-    objects
+    s
+}
+
+/// Returns the `DBGDSYMPath` values in the property list printed by a
+/// `DBGShellCommands` command, which are the paths of the DWARF files.
+fn dsym_paths_in_plist(plist: &str) -> impl Iterator<Item = &str> {
+    plist
+        .split("<key>DBGDSYMPath</key>")
+        .skip(1)
+        .filter_map(|rest| {
+            let rest = rest.trim_start().strip_prefix("<string>")?;
+            Some(&rest[..rest.find("</string>")?])
+        })
 }
 
-fn load_object(i: u32) -> Option<Object> {
-    None
+fn find_phdr(i: u32) -> Option<ObjectPhdr> {
+    let header = unsafe { libc::_dyld_get_image_header(i) } as *const mach_header_64;
+    let name = unsafe { libc::_dyld_get_image_name(i) };
+    if header.is_null() || name.is_null() {
+        return None;
+    }
+    let path = PathBuf::from(OsString::from_vec(
+        unsafe { CStr::from_ptr(name) }.to_bytes().to_vec(),
+    ));
+    let header = unsafe { &*header };
+    if header.magic != MH_MAGIC_64 {
+        warning!("Unsupported mach-o magic {:#x} in {path:?}", header.magic);
+        return None;
+    }
+    let slide = unsafe { libc::_dyld_get_image_vmaddr_slide(i) } as usize;
+
+    let mut text = None;
+    let mut sections = Vec::new();
+    let mut cmd_ptr = unsafe { (header as *const mach_header_64).add(1) } as *const u8;
+    for _ in 0..header.ncmds {
+        let cmd = unsafe { &*(cmd_ptr as *const load_command) };
+        if cmd.cmd == LC_SEGMENT_64 && cmd.cmdsize as usize >= size_of::<segment_command_64>() {
+            let segment = unsafe { &*(cmd_ptr as *const segment_command_64) };
+            let segname = unsafe { CStr::from_ptr(segment.segname.as_ptr()) };
+            let p_flags = elf_flags_from_vm_prot(segment.initprot as u32);
+            // __TEXT segment
+            if segname.to_bytes() == b"__TEXT" {
+                text = Some(Segment {
+                    p_vaddr: segment.vmaddr as usize,
+                    p_memsz: segment.vmsize as usize,
+                    p_flags,
+                });
+            }
+            // The section headers follow the segment command.
+            let section_headers = unsafe {
+                slice::from_raw_parts(
+                    (segment as *const segment_command_64).add(1) as *const Section64<NativeEndian>,
+                    segment.nsects as usize,
+                )
+            };
+            for section in section_headers {
+                sections.push((
+                    String::from_utf8_lossy(section.name()).into_owned(),
+                    Segment {
+                        p_vaddr: section.addr.get(NativeEndian) as usize,
+                        p_memsz: section.size.get(NativeEndian) as usize,
+                        p_flags,
+                    },
+                ));
+            }
+        }
+        cmd_ptr = unsafe { cmd_ptr.add(cmd.cmdsize as usize) };
+    }
+
+    let text = match text {
+        Some(text) => text,
+        None => {
+            warning!("No __TEXT segment found in {path:?}");
+            return None;
+        }
+    };
+
+    Some(ObjectPhdr {
+        base_addr: slide,
+        base_svma: text.p_vaddr,
+        path,
+        is_main_executable: header.filetype == MH_EXECUTE,
+        text_segments: vec![text.clone()],
+        load_segments: vec![text.clone()],
+        text,
+        eh_frame_hdr: None,
+        sections,
+        notes: Vec::new(),
+        got: None,
+    })
 }
 
 pub struct ObjectMmap {
     pub mmap: ManuallyDrop<Mmap>,
     pub obj_file: ManuallyDrop<object::File<'static, &'static [u8]>>,
 }
+
+impl ObjectMmap {
+    pub fn new(path: &Path) -> Result<ObjectMmap, LoadError> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        // See the Linux implementation for why the data may be treated as 'static.
+        let (ptr, len) = (mmap.as_ptr(), mmap.len());
+        let data = unsafe { slice::from_raw_parts(ptr, len) };
+        let obj_file = object::File::parse(thin_image(data)?)?;
+        Ok(ObjectMmap {
+            mmap: ManuallyDrop::new(mmap),
+            obj_file: ManuallyDrop::new(obj_file),
+        })
+    }
+}
+
+/// The CPU type of the slices of universal binaries that may be loaded.
+#[cfg(target_arch = "x86_64")]
+const CPU_TYPE: u32 = object::macho::CPU_TYPE_X86_64;
+#[cfg(target_arch = "aarch64")]
+const CPU_TYPE: u32 = object::macho::CPU_TYPE_ARM64;
+
+/// Returns the slice of a universal binary for the current architecture, or
+/// `data` itself if it is not a universal binary.
+fn thin_image(data: &[u8]) -> Result<&[u8], LoadError> {
+    fn find_arch<'a>(data: &'a [u8], arches: &[impl FatArch]) -> Result<&'a [u8], LoadError> {
+        match arches.iter().find(|arch| arch.cputype() == CPU_TYPE) {
+            Some(arch) => Ok(arch.data(data)?),
+            // Parsing the universal binary itself fails with a fitting error.
+            None => Ok(data),
+        }
+    }
+
+    match FileKind::parse(data)? {
+        FileKind::MachOFat32 => find_arch(data, FatHeader::parse_arch32(data)?),
+        FileKind::MachOFat64 => find_arch(data, FatHeader::parse_arch64(data)?),
+        _ => Ok(data),
+    }
+}
+
+impl Drop for ObjectMmap {
+    fn drop(&mut self) {
+        // Drop the object::File that may reference the mmap before the mmap.
+        unsafe {
+            ManuallyDrop::drop(&mut self.obj_file);
+            ManuallyDrop::drop(&mut self.mmap);
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{find_object_for_addr, UnwindSource};
+
+    #[test]
+    fn uuid() {
+        let uuid = [
+            0x4c, 0x4c, 0x44, 0xb5, 0x55, 0x55, 0x31, 0x44, 0xa1, 0xb4, 0x3f, 0x2e, 0x1d, 0x0c,
+            0x0b, 0x0a,
+        ];
+        assert_eq!(format_uuid(&uuid), "4C4C44B5-5555-3144-A1B4-3F2E1D0C0B0A");
+    }
+
+    #[test]
+    fn plist() {
+        let plist = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>4C4C44B5-5555-3144-A1B4-3F2E1D0C0B0A</key>
+    <dict>
+        <key>DBGArchitecture</key>
+        <string>arm64</string>
+        <key>DBGDSYMPath</key>
+        <string>/symbols/Foo.dSYM/Contents/Resources/DWARF/Foo</string>
+    </dict>
+    <key>0A0B0C1D-2E3F-B4A1-4431-5555B5444C4C</key>
+    <dict>
+        <key>DBGDSYMPath</key> <string>/symbols/Bar.dSYM/Contents/Resources/DWARF/Bar</string>
+    </dict>
+    <key>DBGDSYMPath</key>
+    <integer>1</integer>
+</dict>
+</plist>"#;
+        assert!(dsym_paths_in_plist(plist).eq([
+            "/symbols/Foo.dSYM/Contents/Resources/DWARF/Foo",
+            "/symbols/Bar.dSYM/Contents/Resources/DWARF/Bar",
+        ]));
+        assert_eq!(dsym_paths_in_plist("").count(), 0);
+    }
+
+    #[test]
+    fn compact_unwind_in_memory() {
+        // libSystem is in the dyld shared cache, so its file can't be mapped.
+        let obj = find_object_for_addr(libc::getpid as *const () as usize).unwrap();
+        assert!(obj.phdr.section("__unwind_info").is_some());
+        assert_ne!(obj.unwind_source(), UnwindSource::None);
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    io,
+    mem::{size_of, MaybeUninit},
+};
+
+use framehop::UnwindRegsNative;
+use nix::{
+    errno::Errno,
+    sys::{
+        ptrace,
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+
+/// A thread of another process, stopped with `PTRACE_SEIZE` and
+/// `PTRACE_INTERRUPT` until this is dropped.
+///
+/// The unwinder only knows the objects of the current process, so the target
+/// must have them mapped at the same addresses, e.g. because it is a process
+/// forked from this one. A thread can't be traced by its own process.
+pub struct StoppedThread {
+    tid: Pid,
+}
+
+impl StoppedThread {
+    /// Attaches to the thread `tid` and waits until it has stopped.
+    pub fn attach(tid: libc::pid_t) -> io::Result<Self> {
+        let tid = Pid::from_raw(tid);
+        ptrace::seize(tid, ptrace::Options::empty())?;
+        // Detach on drop from here on, even if stopping the thread fails.
+        let thread = StoppedThread { tid };
+        ptrace::interrupt(tid)?;
+        loop {
+            match waitpid(tid, Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::PtraceEvent(..)) | Ok(WaitStatus::Stopped(..)) => break,
+                Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) => {
+                    return Err(Errno::ESRCH.into())
+                }
+                Ok(_) | Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(thread)
+    }
+
+    /// Returns the thread id.
+    pub fn tid(&self) -> libc::pid_t {
+        self.tid.as_raw()
+    }
+
+    /// Reads the registers of the stopped thread with `PTRACE_GETREGSET`.
+    pub(crate) fn regs(&self) -> io::Result<(u64, UnwindRegsNative)> {
+        let mut regs = MaybeUninit::<libc::user_regs_struct>::uninit();
+        let mut iov = libc::iovec {
+            iov_base: regs.as_mut_ptr().cast(),
+            iov_len: size_of::<libc::user_regs_struct>(),
+        };
+        Errno::result(unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETREGSET,
+                self.tid.as_raw(),
+                libc::NT_PRSTATUS as usize as *mut libc::c_void,
+                &mut iov as *mut libc::iovec,
+            )
+        })?;
+        Ok(crate::regs_from_user_regs(unsafe { &regs.assume_init() }))
+    }
+}
+
+impl Drop for StoppedThread {
+    fn drop(&mut self) {
+        // Resumes the thread. Fails only if it has exited in the meantime.
+        let _ = ptrace::detach(self.tid, None);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CacheNative, MayAllocateDuringUnwind, Unwinder};
+
+    #[test]
+    fn unwind_forked_child() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0);
+        if child == 0 {
+            // The child shares the parent's mappings. Only async-signal-safe calls
+            // are allowed here.
+            loop {
+                unsafe { libc::pause() };
+            }
+        }
+
+        let frames = StoppedThread::attach(child).map(|thread| {
+            let mut cache = CacheNative::new();
+            let mut iter = unwinder.iter_frames_of_thread(&thread, &mut cache).unwrap();
+            let mut frames = 0;
+            while let Ok(Some(_)) = iter.try_next() {
+                frames += 1;
+            }
+            frames
+        });
+        unsafe {
+            libc::kill(child, libc::SIGKILL);
+            libc::waitpid(child, std::ptr::null_mut(), 0);
+        }
+        // From `pause` in libc up to this test function.
+        assert!(frames.unwrap() > 1);
+    }
+}
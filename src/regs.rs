@@ -0,0 +1,90 @@
+use std::mem::size_of;
+
+use framehop::UnwindRegsNative;
+
+/// The number of 64-bit registers in the general-purpose register set of a
+/// thread, as read with `PTRACE_GETREGSET` and `NT_PRSTATUS` or found in core
+/// dumps. This is the layout of `libc::user_regs_struct`.
+pub const GREGS_LEN: usize = size_of::<libc::user_regs_struct>() / size_of::<u64>();
+
+#[cfg(target_arch = "x86_64")]
+mod index {
+    pub const RBP: usize = 4;
+    pub const RIP: usize = 16;
+    pub const RSP: usize = 19;
+}
+
+#[cfg(target_arch = "aarch64")]
+mod index {
+    pub const X29: usize = 29;
+    pub const X30: usize = 30;
+    pub const SP: usize = 31;
+    pub const PC: usize = 32;
+}
+
+/// Returns the instruction pointer and the unwind registers from a raw
+/// general-purpose register set. See [`GREGS_LEN`] for the layout.
+#[cfg(target_arch = "x86_64")]
+pub fn regs_from_gregs(gregs: &[u64; GREGS_LEN]) -> (u64, UnwindRegsNative) {
+    let ip = gregs[index::RIP];
+    (
+        ip,
+        UnwindRegsNative::new(ip, gregs[index::RSP], gregs[index::RBP]),
+    )
+}
+
+/// Returns the instruction pointer and the unwind registers from a raw
+/// general-purpose register set. See [`GREGS_LEN`] for the layout.
+#[cfg(target_arch = "aarch64")]
+pub fn regs_from_gregs(gregs: &[u64; GREGS_LEN]) -> (u64, UnwindRegsNative) {
+    (
+        gregs[index::PC],
+        UnwindRegsNative::new(gregs[index::X30], gregs[index::SP], gregs[index::X29]),
+    )
+}
+
+/// Returns the instruction pointer and the unwind registers from the registers
+/// of a thread read with ptrace.
+#[cfg(target_arch = "x86_64")]
+pub fn regs_from_user_regs(regs: &libc::user_regs_struct) -> (u64, UnwindRegsNative) {
+    (
+        regs.rip,
+        UnwindRegsNative::new(regs.rip, regs.rsp, regs.rbp),
+    )
+}
+
+/// Returns the instruction pointer and the unwind registers from the registers
+/// of a thread read with ptrace.
+#[cfg(target_arch = "aarch64")]
+pub fn regs_from_user_regs(regs: &libc::user_regs_struct) -> (u64, UnwindRegsNative) {
+    (
+        regs.pc,
+        UnwindRegsNative::new(regs.regs[30], regs.sp, regs.regs[29]),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gregs_round_trip() {
+        let mut gregs = [0u64; GREGS_LEN];
+        for (i, reg) in gregs.iter_mut().enumerate() {
+            *reg = 0x1000 + i as u64 * 8;
+        }
+        let user_regs: libc::user_regs_struct = unsafe { std::mem::transmute(gregs) };
+        let (pc, regs) = regs_from_gregs(&gregs);
+        let (user_pc, user_regs) = regs_from_user_regs(&user_regs);
+        assert_eq!(pc, user_pc);
+        assert!(regs == user_regs);
+
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!((pc, regs.sp(), regs.bp()), (0x1080, 0x1098, 0x1020));
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(
+            (pc, regs.lr(), regs.sp(), regs.fp()),
+            (0x1100, 0x10f0, 0x10f8, 0x10e8)
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! A fixed-capacity queue of backtraces from one producer to one consumer,
+//! e.g. from a signal handler to a thread processing the samples.
+
+use std::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Holds up to `CAP` backtraces of up to `N` frames each.
+///
+/// The queue is used through the [`Producer`] and [`Consumer`] returned by
+/// [`BacktraceRing::split`]. Pushing never allocates, locks or makes syscalls,
+/// so it is async-signal-safe. The storage is inline, so large rings belong in
+/// a `static` or a `Box`:
+///
+/// ```
+/// use runwind::ring::BacktraceRing;
+///
+/// static RING: BacktraceRing<64, 256> = BacktraceRing::new();
+/// let (mut producer, mut consumer) = RING.split().unwrap();
+/// assert!(producer.push(&[1, 2, 3]));
+/// assert_eq!(consumer.pop(), Some(vec![1, 2, 3]));
+/// ```
+pub struct BacktraceRing<const N: usize, const CAP: usize> {
+    slots: [UnsafeCell<Slot<N>>; CAP],
+    /// The number of backtraces popped so far. Only the consumer stores it.
+    head: AtomicUsize,
+    /// The number of backtraces pushed so far. Only the producer stores it.
+    tail: AtomicUsize,
+    split: AtomicBool,
+}
+
+struct Slot<const N: usize> {
+    len: usize,
+    frames: [usize; N],
+}
+
+// A slot is only accessed by the producer while it is free, and by the
+// consumer while it is full, as told by `head` and `tail`.
+unsafe impl<const N: usize, const CAP: usize> Sync for BacktraceRing<N, CAP> {}
+
+impl<const N: usize, const CAP: usize> BacktraceRing<N, CAP> {
+    pub const fn new() -> Self {
+        assert!(CAP > 0, "the capacity must not be zero");
+        BacktraceRing {
+            slots: [const {
+                UnsafeCell::new(Slot {
+                    len: 0,
+                    frames: [0; N],
+                })
+            }; CAP],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            split: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the only producer and consumer of the ring, or `None` if they
+    /// were already taken.
+    pub fn split(&self) -> Option<(Producer<'_, N, CAP>, Consumer<'_, N, CAP>)> {
+        if self.split.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some((Producer { ring: self }, Consumer { ring: self }))
+    }
+
+    /// Returns the number of backtraces waiting to be popped.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Acquire).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, index: usize) -> *mut Slot<N> {
+        self.slots[index % CAP].get()
+    }
+}
+
+impl<const N: usize, const CAP: usize> Default for BacktraceRing<N, CAP> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pushing side of a [`BacktraceRing`].
+pub struct Producer<'a, const N: usize, const CAP: usize> {
+    ring: &'a BacktraceRing<N, CAP>,
+}
+
+impl<const N: usize, const CAP: usize> Producer<'_, N, CAP> {
+    /// Pushes up to `N` frames of `frames`. Returns `false` if the ring is
+    /// full.
+    pub fn push(&mut self, frames: &[usize]) -> bool {
+        self.push_with(|out| {
+            let len = frames.len().min(N);
+            out[..len].copy_from_slice(&frames[..len]);
+            len
+        })
+    }
+
+    /// Lets `fill` write the frames into the free slot and return how many it
+    /// wrote, e.g. with [`Unwinder::fill_backtrace`](crate::Unwinder::fill_backtrace),
+    /// so that they are not copied. Returns `false` without calling `fill` if
+    /// the ring is full.
+    pub fn push_with(&mut self, fill: impl FnOnce(&mut [usize; N]) -> usize) -> bool {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        // Acquire the consumer's reads of the slot before reusing it.
+        if tail.wrapping_sub(ring.head.load(Ordering::Acquire)) == CAP {
+            return false;
+        }
+        let slot = unsafe { &mut *ring.slot(tail) };
+        slot.len = fill(&mut slot.frames).min(N);
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+}
+
+/// The popping side of a [`BacktraceRing`].
+pub struct Consumer<'a, const N: usize, const CAP: usize> {
+    ring: &'a BacktraceRing<N, CAP>,
+}
+
+impl<const N: usize, const CAP: usize> Consumer<'_, N, CAP> {
+    /// Pops the oldest backtrace.
+    pub fn pop(&mut self) -> Option<Vec<usize>> {
+        self.pop_with(|frames| frames.to_vec())
+    }
+
+    /// Calls `f` with the frames of the oldest backtrace, then pops it.
+    pub fn pop_with<R>(&mut self, f: impl FnOnce(&[usize]) -> R) -> Option<R> {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        // Acquire the producer's writes to the slot.
+        if head == ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = unsafe { &*ring.slot(head) };
+        let result = f(&slot.frames[..slot.len]);
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let ring = BacktraceRing::<3, 2>::new();
+        let (mut producer, mut consumer) = ring.split().unwrap();
+        assert!(ring.split().is_none());
+
+        assert_eq!(consumer.pop(), None);
+        assert!(producer.push(&[1, 2]));
+        // Truncated to 3 frames.
+        assert!(producer.push(&[3, 4, 5, 6]));
+        assert!(!producer.push(&[7]));
+        assert_eq!(ring.len(), 2);
+
+        assert_eq!(consumer.pop(), Some(vec![1, 2]));
+        assert!(producer.push_with(|frames| {
+            frames[0] = 8;
+            1
+        }));
+        assert_eq!(consumer.pop(), Some(vec![3, 4, 5]));
+        assert_eq!(consumer.pop_with(|frames| frames.len()), Some(1));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn across_threads() {
+        static RING: BacktraceRing<2, 16> = BacktraceRing::new();
+        let (mut producer, mut consumer) = RING.split().unwrap();
+        const COUNT: usize = 10_000;
+        let thread = std::thread::spawn(move || {
+            for i in 0..COUNT {
+                while !producer.push(&[i, !i]) {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < COUNT {
+            match consumer.pop() {
+                Some(frames) => {
+                    assert_eq!(frames, [next, !next]);
+                    next += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        thread.join().unwrap();
+        assert!(RING.is_empty());
+    }
+}
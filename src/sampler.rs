@@ -0,0 +1,261 @@
+//! Samples the call stacks of threads at a fixed rate of their CPU time.
+//!
+//! Each sampled thread has a `CLOCK_THREAD_CPUTIME_ID` timer delivering
+//! `SIGPROF` to that thread. The handler unwinds the interrupted context on its
+//! stack, then pushes it to a [`BacktraceRing`] shared by all threads, without
+//! allocating or locking, and [`drain_samples`] empties it. Samples are dropped
+//! while the ring is full or another thread is pushing to it.
+//!
+//! The handler unwinds with the objects loaded when sampling was first started.
+
+use std::{
+    cell::{RefCell, UnsafeCell},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use libc::{c_int, c_void, siginfo_t};
+
+use crate::ring::{BacktraceRing, Consumer, Producer};
+use crate::{MustNotAllocateDuringUnwind, Unwinder};
+
+/// The maximum number of frames kept per sample.
+pub const MAX_FRAMES: usize = 128;
+/// The number of samples kept until they are drained.
+const CAPACITY: usize = 1024;
+
+/// Each backtrace in the ring starts with the id of the sampled thread,
+/// followed by the frames.
+const SAMPLE_LEN: usize = MAX_FRAMES + 1;
+
+static RING: BacktraceRing<SAMPLE_LEN, CAPACITY> = BacktraceRing::new();
+
+/// The producer of [`RING`], used by the handlers of all sampled threads in
+/// turn.
+struct SharedProducer {
+    busy: AtomicBool,
+    producer: UnsafeCell<Producer<'static, SAMPLE_LEN, CAPACITY>>,
+}
+
+// The producer is only accessed by the thread that set `busy`.
+unsafe impl Sync for SharedProducer {}
+
+impl SharedProducer {
+    /// Pushes `sample` and returns whether it could, without waiting for
+    /// another thread pushing meanwhile.
+    fn try_push(&self, sample: &[usize]) -> bool {
+        if self.busy.swap(true, Ordering::Acquire) {
+            return false;
+        }
+        let pushed = unsafe { (*self.producer.get()).push(sample) };
+        self.busy.store(false, Ordering::Release);
+        pushed
+    }
+}
+
+static SAMPLES: OnceLock<(
+    SharedProducer,
+    Mutex<Consumer<'static, SAMPLE_LEN, CAPACITY>>,
+)> = OnceLock::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+static UNWINDER: OnceLock<Unwinder<MustNotAllocateDuringUnwind>> = OnceLock::new();
+/// The errno of installing the handler, if it failed.
+static HANDLER: OnceLock<Result<(), i32>> = OnceLock::new();
+
+/// A timer of the current thread, deleted when the thread exits.
+struct ThreadTimer(libc::timer_t);
+
+impl Drop for ThreadTimer {
+    fn drop(&mut self) {
+        unsafe { libc::timer_delete(self.0) };
+    }
+}
+
+thread_local! {
+    static TIMER: RefCell<Option<ThreadTimer>> = const { RefCell::new(None) };
+}
+
+/// The call stack of a thread when it was interrupted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub tid: libc::pid_t,
+    /// The interrupted instruction, then the return addresses.
+    pub frames: Vec<usize>,
+}
+
+/// Starts sampling the current thread `hz` times per second of its CPU time,
+/// or changes the rate if it is already sampled.
+///
+/// This installs a `SIGPROF` handler for the whole process, replacing any
+/// other. Sampling stops when the thread exits.
+pub fn start_sampling(hz: u32) -> io::Result<()> {
+    if hz == 0 {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    UNWINDER.get_or_init(Unwinder::new);
+    SAMPLES.get_or_init(|| {
+        let (producer, consumer) = RING.split().unwrap();
+        let producer = SharedProducer {
+            busy: AtomicBool::new(false),
+            producer: UnsafeCell::new(producer),
+        };
+        (producer, Mutex::new(consumer))
+    });
+    (*HANDLER.get_or_init(|| unsafe { install_handler() }))
+        .map_err(io::Error::from_raw_os_error)?;
+    // The handler must not be the first user of the thread cache or of the
+    // validation pipe.
+    Unwinder::<MustNotAllocateDuringUnwind>::with_thread_cache(|_| ());
+    crate::addr_validate::init_thread();
+
+    TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        let timer = match &mut *timer {
+            Some(timer) => timer,
+            None => timer.insert(ThreadTimer(create_timer()?)),
+        };
+        // A zero interval would disarm the timer.
+        let period = (Duration::from_secs(1) / hz).max(Duration::from_nanos(1));
+        let interval = libc::timespec {
+            tv_sec: period.as_secs() as _,
+            tv_nsec: period.subsec_nanos() as _,
+        };
+        let spec = libc::itimerspec {
+            it_interval: interval,
+            it_value: interval,
+        };
+        if unsafe { libc::timer_settime(timer.0, 0, &spec, std::ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Stops sampling the current thread. Its samples are kept until drained.
+pub fn stop_sampling() {
+    TIMER.with(|timer| timer.borrow_mut().take());
+}
+
+/// Takes the samples of all threads taken so far, oldest first.
+pub fn drain_samples() -> Vec<Sample> {
+    let Some((_, consumer)) = SAMPLES.get() else {
+        return Vec::new();
+    };
+    let mut consumer = consumer.lock().unwrap();
+    std::iter::from_fn(|| {
+        consumer.pop_with(|sample| Sample {
+            tid: sample[0] as libc::pid_t,
+            frames: sample[1..].to_vec(),
+        })
+    })
+    .collect()
+}
+
+/// Returns how many samples were dropped because the ring was full or in use,
+/// or the thread cache of the interrupted thread was in use.
+pub fn dropped_samples() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+fn create_timer() -> io::Result<libc::timer_t> {
+    let mut event: libc::sigevent = unsafe { std::mem::zeroed() };
+    event.sigev_notify = libc::SIGEV_THREAD_ID;
+    event.sigev_signo = libc::SIGPROF;
+    event.sigev_notify_thread_id = unsafe { libc::gettid() };
+    let mut timer: libc::timer_t = std::ptr::null_mut();
+    if unsafe { libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut event, &mut timer) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(timer)
+}
+
+unsafe fn install_handler() -> Result<(), i32> {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler as *const () as usize;
+    // Not `SA_ONSTACK`: alternate signal stacks are too small for unwinding.
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+    if libc::sigaction(libc::SIGPROF, &action, std::ptr::null_mut()) != 0 {
+        return Err(*libc::__errno_location());
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn handler(_sig: c_int, _info: *mut siginfo_t, ucontext: *mut c_void) {
+    // Validating addresses may fail syscalls, which must not be visible to the
+    // interrupted code.
+    let errno = *libc::__errno_location();
+    if let Some(unwinder) = UNWINDER.get() {
+        if !record(unwinder, ucontext as *const libc::ucontext_t) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    *libc::__errno_location() = errno;
+}
+
+/// Unwinds `ucontext` and returns whether the sample could be kept.
+unsafe fn record(
+    unwinder: &Unwinder<MustNotAllocateDuringUnwind>,
+    ucontext: *const libc::ucontext_t,
+) -> bool {
+    let Some((producer, _)) = SAMPLES.get() else {
+        return false;
+    };
+    let mut sample = [0; SAMPLE_LEN];
+    let len = Unwinder::with_thread_cache(|cache| {
+        let mut iter = unwinder.iter_frames_from_ucontext(ucontext, cache);
+        let mut len = 0;
+        while len < MAX_FRAMES {
+            match iter.try_next() {
+                Ok(Some(addr)) => sample[1 + len] = addr,
+                _ => break,
+            }
+            len += 1;
+        }
+        len
+    });
+    let Some(len) = len else {
+        return false;
+    };
+    sample[0] = libc::gettid() as usize;
+    producer.try_push(&sample[..1 + len])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn spin() -> u64 {
+        let start = std::time::Instant::now();
+        let mut x = 0u64;
+        while start.elapsed() < std::time::Duration::from_millis(200) {
+            x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+        x
+    }
+
+    #[test]
+    fn sample_current_thread() {
+        assert!(start_sampling(0).is_err());
+        // A period of a whole second.
+        start_sampling(1).unwrap();
+        start_sampling(1000).unwrap();
+        spin();
+        stop_sampling();
+
+        let tid = unsafe { libc::gettid() };
+        let samples: Vec<_> = drain_samples()
+            .into_iter()
+            .filter(|sample| sample.tid == tid)
+            .collect();
+        assert!(!samples.is_empty());
+        // The samples are unwound past `spin` into the test harness.
+        assert!(samples.iter().any(|sample| sample.frames.len() > 2));
+    }
+}
@@ -0,0 +1,50 @@
+use std::ops::Range;
+
+use libc::pthread_self;
+
+/// Returns the address range of the current thread's stack.
+///
+/// This is not async-signal-safe: on Linux the main thread's stack is looked up
+/// in `/proc/self/maps`. Call it once per thread and reuse the result.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub fn current_thread_stack() -> Option<Range<usize>> {
+    use std::mem::MaybeUninit;
+
+    #[cfg(target_os = "freebsd")]
+    use libc::pthread_attr_get_np as get_attr;
+    #[cfg(target_os = "linux")]
+    use libc::pthread_getattr_np as get_attr;
+    use libc::{c_void, pthread_attr_t};
+
+    unsafe {
+        let mut attr = MaybeUninit::<pthread_attr_t>::uninit();
+        #[cfg(target_os = "freebsd")]
+        if libc::pthread_attr_init(attr.as_mut_ptr()) != 0 {
+            return None;
+        }
+        if get_attr(pthread_self(), attr.as_mut_ptr()) != 0 {
+            return None;
+        }
+        let mut attr = attr.assume_init();
+        let mut addr: *mut c_void = std::ptr::null_mut();
+        let mut size = 0;
+        let res = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        if res != 0 {
+            return None;
+        }
+        Some(addr as usize..addr as usize + size)
+    }
+}
+
+/// Returns the address range of the current thread's stack.
+#[cfg(target_os = "macos")]
+pub fn current_thread_stack() -> Option<Range<usize>> {
+    unsafe {
+        let thread = pthread_self();
+        // The stack address is the top of the stack on macOS.
+        let top = libc::pthread_get_stackaddr_np(thread) as usize;
+        let size = libc::pthread_get_stacksize_np(thread);
+        Some(top.checked_sub(size)?..top)
+    }
+}
@@ -0,0 +1,604 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    fs::File,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use addr2line::{
+    demangle_auto,
+    gimli::{self, EndianArcSlice, RunTimeEndian},
+    Context,
+};
+use framehop::AllocationPolicy;
+use memmap2::Mmap;
+use object::{Object as _, ObjectSection, ObjectSegment, SymbolMap, SymbolMapEntry};
+
+use crate::diagnostic::warning;
+use crate::object::{find_object_for_addr, Object};
+use crate::{CapturedModule, LocalMemory, StackMemory, UnwindError, UnwindIterator};
+
+/// A function frame at an address, possibly inlined into its caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// The demangled function name.
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// A symbol with its name copied out of the object file, so that the file
+/// doesn't need to stay mapped.
+struct Symbol {
+    address: u64,
+    name: String,
+}
+
+impl SymbolMapEntry for Symbol {
+    fn address(&self) -> u64 {
+        self.address
+    }
+}
+
+struct ObjectSymbols {
+    /// `addr2line::Context` parses units lazily, so it is not `Sync`, and is
+    /// locked to share it between threads.
+    context: Option<Mutex<Context<EndianArcSlice<RunTimeEndian>>>>,
+    symbols: SymbolMap<Symbol>,
+}
+
+impl ObjectSymbols {
+    fn new<'data>(obj_file: &object::File<'data, &'data [u8]>, path: &Path) -> ObjectSymbols {
+        let endian = if obj_file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        // Like `Context::new`, but with `Arc`s instead of `Rc`s.
+        let load_section = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+            let data = obj_file
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or_default();
+            Ok(EndianArcSlice::new(Arc::from(&*data), endian))
+        };
+        let context = gimli::Dwarf::load(load_section)
+            .and_then(Context::from_dwarf)
+            .map(Mutex::new)
+            .map_err(|e| warning!("Failed to parse DWARF of {path:?}: {e}"))
+            .ok();
+        let symbols = obj_file
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|symbol| Symbol {
+                address: symbol.address(),
+                name: symbol.name().to_string(),
+            })
+            .collect();
+        ObjectSymbols {
+            context,
+            symbols: SymbolMap::new(symbols),
+        }
+    }
+
+    fn for_object(obj: &Object) -> Option<ObjectSymbols> {
+        // Prefer the separate debug file of stripped objects.
+        let obj_file = obj.debug_obj_file().or(obj.obj_file())?;
+        Some(ObjectSymbols::new(obj_file, obj.path()))
+    }
+
+    /// Looks up the frames at `svma`, innermost (inlined) first.
+    fn frames(&self, svma: u64) -> Vec<FrameInfo> {
+        let mut frames = Vec::new();
+        if let Some(context) = &self.context {
+            let context = context.lock().unwrap_or_else(|e| e.into_inner());
+            if let Ok(mut iter) = context.find_frames(svma) {
+                while let Ok(Some(frame)) = iter.next() {
+                    frames.push(FrameInfo {
+                        function: frame
+                            .function
+                            .and_then(|f| f.demangle().ok().map(Cow::into_owned)),
+                        file: frame
+                            .location
+                            .as_ref()
+                            .and_then(|l| l.file.map(String::from)),
+                        line: frame.location.and_then(|l| l.line),
+                    });
+                }
+            };
+        }
+        // Without debug info, fall back to the symbol table.
+        if frames.is_empty() {
+            if let Some(symbol) = self.symbols.get(svma) {
+                frames.push(FrameInfo {
+                    function: Some(demangle_auto(symbol.name.as_str().into(), None).into_owned()),
+                    file: None,
+                    line: None,
+                });
+            }
+        }
+        frames
+    }
+}
+
+thread_local! {
+    /// The parsed symbols, cached per thread so that parsing a large object
+    /// doesn't block the other threads, keyed by the start of the text of the
+    /// object. Unlike the base address, the slide on macOS, it is unique even
+    /// among the images of the dyld shared cache, and for non-PIE executables
+    /// or prelinked libraries, whose base address is 0.
+    static SYMBOLS: RefCell<HashMap<usize, Option<Arc<ObjectSymbols>>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Looks up the frames at the return address `addr`, innermost (inlined) first.
+///
+/// Return addresses point to the instruction after the call, so the address
+/// before it is looked up instead.
+pub(crate) fn symbolicate(addr: usize) -> Vec<FrameInfo> {
+    let lookup_addr = addr.saturating_sub(1);
+    let obj = match find_object_for_addr(lookup_addr) {
+        Some(obj) => obj,
+        None => return symbolicate_jit(lookup_addr),
+    };
+    let symbols = SYMBOLS.with(|symbols| {
+        symbols
+            .borrow_mut()
+            .entry(obj.text_avma().start)
+            .or_insert_with(|| ObjectSymbols::for_object(obj).map(Arc::new))
+            .clone()
+    });
+    let symbols = match symbols {
+        Some(symbols) => symbols,
+        None => return Vec::new(),
+    };
+
+    symbols.frames((lookup_addr - obj.base_addr()) as u64)
+}
+
+/// Looks up a function generated at runtime, see
+/// [`set_jit_symbols`](crate::jit::set_jit_symbols).
+fn symbolicate_jit(lookup_addr: usize) -> Vec<FrameInfo> {
+    crate::jit::lookup_jit_symbol(lookup_addr)
+        .map(|name| FrameInfo {
+            function: Some(name),
+            file: None,
+            line: None,
+        })
+        .into_iter()
+        .collect()
+}
+
+/// A function at a return address. See [`UnwindIterator::inlined`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlinedFrame {
+    /// The return address of the physical frame.
+    pub avma: usize,
+    /// The function, or only `None`s if the address has no symbols.
+    pub info: FrameInfo,
+    /// Whether the function is inlined into the function of the next frame,
+    /// which then has the same `avma`.
+    pub inlined: bool,
+}
+
+/// An iterator over the functions on the stack, innermost first, with inlined
+/// functions expanded. See [`UnwindIterator::inlined`].
+pub struct InlinedFrameIterator<'u, 'c, P, M = LocalMemory>
+where
+    P: AllocationPolicy<&'static [u8]>,
+    M: StackMemory,
+{
+    iter: UnwindIterator<'u, 'c, P, M>,
+    /// The remaining functions of the current physical frame.
+    pending: std::vec::IntoIter<InlinedFrame>,
+}
+
+impl<'u, 'c, P, M> InlinedFrameIterator<'u, 'c, P, M>
+where
+    P: AllocationPolicy<&'static [u8]>,
+    M: StackMemory,
+{
+    pub(crate) fn new(iter: UnwindIterator<'u, 'c, P, M>) -> Self {
+        InlinedFrameIterator {
+            iter,
+            pending: Vec::new().into_iter(),
+        }
+    }
+
+    /// Returns the next function, unwinding to the next physical frame once
+    /// all functions inlined at the current one are returned.
+    ///
+    /// This allocates and parses debug info, so it must not be used in signal
+    /// handlers.
+    pub fn try_next(&mut self) -> Result<Option<InlinedFrame>, UnwindError> {
+        if let Some(frame) = self.pending.next() {
+            return Ok(Some(frame));
+        }
+        let avma = match self.iter.try_next()? {
+            Some(avma) => avma,
+            None => return Ok(None),
+        };
+        let mut infos = symbolicate(avma);
+        if infos.is_empty() {
+            infos.push(FrameInfo {
+                function: None,
+                file: None,
+                line: None,
+            });
+        }
+        let outermost = infos.len() - 1;
+        self.pending = infos
+            .into_iter()
+            .enumerate()
+            .map(|(i, info)| InlinedFrame {
+                avma,
+                info,
+                inlined: i != outermost,
+            })
+            .collect::<Vec<_>>()
+            .into_iter();
+        Ok(self.pending.next())
+    }
+}
+
+/// Symbolicates the addresses of a [`CapturedBacktrace`](crate::CapturedBacktrace)
+/// outside of the process it was captured in.
+///
+/// Each module is read from the given binaries with the same build-id, or else
+/// from its original path if that file has the same build-id or the module has
+/// none.
+pub struct Symbolizer {
+    /// Sorted by [`SymbolizerModule::start_avma`].
+    modules: Vec<SymbolizerModule>,
+}
+
+struct SymbolizerModule {
+    module: CapturedModule,
+    /// The SVMA ranges of the loadable segments, if the file was found.
+    segments: Vec<Range<u64>>,
+    symbols: Option<Arc<ObjectSymbols>>,
+}
+
+/// The symbols and debug info parsed by [`Symbolizer::with_cache`], so that
+/// symbolizers for many backtraces of the same binaries parse each file once.
+///
+/// Files are keyed by path, build-id and modification time, so a file that is
+/// rebuilt is parsed again. The parsed debug info is shared with the
+/// symbolizers, and both can be sent to other threads, e.g. to keep one cache
+/// in a `Mutex` for all threads that symbolicate backtraces.
+#[derive(Default)]
+pub struct ContextCache {
+    files: HashMap<ContextCacheKey, (Vec<Range<u64>>, Arc<ObjectSymbols>)>,
+}
+
+type ContextCacheKey = (PathBuf, Option<Vec<u8>>, Option<SystemTime>);
+
+impl ContextCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of parsed files, including those without debug
+    /// info, whose symbol tables are cached.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns whether no file has been parsed yet.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Forgets the parsed files. Those still used by symbolizers are dropped
+    /// with them.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// An address resolved by a [`Symbolizer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolizedAddress<'a> {
+    /// The module containing the address.
+    pub module: &'a CapturedModule,
+    /// The address relative to the load bias of the module.
+    pub svma: u64,
+    /// The functions at the address, innermost (inlined) first. Empty if no
+    /// binary was found for the module.
+    pub frames: Vec<FrameInfo>,
+}
+
+impl Symbolizer {
+    /// Loads the symbols of `modules`, e.g. the
+    /// [`modules`](crate::CapturedBacktrace::modules) of a backtrace, reading
+    /// them from `binaries` or the original paths.
+    pub fn new(modules: &[CapturedModule], binaries: &[PathBuf]) -> Self {
+        Self::with_cache(modules, binaries, &mut ContextCache::new())
+    }
+
+    /// Like [`Symbolizer::new`], but takes the files already parsed for another
+    /// symbolizer from `cache`, and adds those it parses.
+    pub fn with_cache(
+        modules: &[CapturedModule],
+        binaries: &[PathBuf],
+        cache: &mut ContextCache,
+    ) -> Self {
+        let binaries: Vec<(PathBuf, Mmap)> = binaries
+            .iter()
+            .filter_map(|path| Some((path.clone(), map_file(path)?)))
+            .collect();
+        let modules = modules
+            .iter()
+            .map(|module| {
+                // Binaries other than the original file are only used if their
+                // build-id matches.
+                let mut load = |path: &Path, mmap: &Mmap, by_build_id: bool| {
+                    let obj_file = object::File::parse(&**mmap).ok()?;
+                    let build_id = obj_file.build_id().ok().flatten();
+                    match &module.build_id {
+                        Some(expected) if build_id != Some(expected.as_slice()) => return None,
+                        None if by_build_id => return None,
+                        _ => {}
+                    }
+                    let key = (
+                        path.to_path_buf(),
+                        build_id.map(<[u8]>::to_vec),
+                        std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+                    );
+                    let (segments, symbols) = cache.files.entry(key).or_insert_with(|| {
+                        let (segments, symbols) = load_module(path, &obj_file);
+                        (segments, Arc::new(symbols))
+                    });
+                    Some((segments.clone(), symbols.clone()))
+                };
+                let found = binaries
+                    .iter()
+                    .find_map(|(path, mmap)| load(path, mmap, true))
+                    .or_else(|| load(&module.path, &map_file(&module.path)?, false));
+                let (segments, symbols) = match found {
+                    Some((segments, symbols)) => (segments, Some(symbols)),
+                    None => (Vec::new(), None),
+                };
+                SymbolizerModule {
+                    module: module.clone(),
+                    segments,
+                    symbols,
+                }
+            })
+            .collect();
+        Self::from_modules(modules)
+    }
+
+    fn from_modules(mut modules: Vec<SymbolizerModule>) -> Self {
+        modules.sort_by_key(SymbolizerModule::start_avma);
+        Symbolizer { modules }
+    }
+
+    /// Resolves the return address `avma` to its module and functions.
+    ///
+    /// Return addresses point to the instruction after the call, so the
+    /// functions are looked up at the address before it. Returns `None` if no
+    /// module contains the address.
+    pub fn resolve(&self, avma: u64) -> Option<SymbolizedAddress<'_>> {
+        let lookup_avma = avma.checked_sub(1)?;
+        let idx = self
+            .modules
+            .partition_point(|module| module.start_avma() <= lookup_avma);
+        let module = &self.modules[idx.checked_sub(1)?];
+        let lookup_svma = lookup_avma.wrapping_sub(module.module.base_addr);
+        // Without the file the module's extent is unknown, so the address is
+        // attributed to the closest module below it.
+        if !module.segments.is_empty()
+            && !module
+                .segments
+                .iter()
+                .any(|segment| segment.contains(&lookup_svma))
+        {
+            return None;
+        }
+        Some(SymbolizedAddress {
+            module: &module.module,
+            svma: avma.wrapping_sub(module.module.base_addr),
+            frames: module
+                .symbols
+                .as_ref()
+                .map_or_else(Vec::new, |symbols| symbols.frames(lookup_svma)),
+        })
+    }
+}
+
+impl SymbolizerModule {
+    /// Returns the address of the lowest segment. It is the base address only
+    /// if the segment has the SVMA 0, unlike in non-PIE executables or
+    /// prelinked libraries, whose base address may be 0 or even lie in another
+    /// module.
+    fn start_avma(&self) -> u64 {
+        let start_svma = self.segments.iter().map(|segment| segment.start).min();
+        self.module
+            .base_addr
+            .wrapping_add(start_svma.unwrap_or_default())
+    }
+}
+
+fn map_file(path: &Path) -> Option<Mmap> {
+    let file = File::open(path).ok()?;
+    unsafe { Mmap::map(&file) }.ok()
+}
+
+fn load_module<'data>(
+    path: &Path,
+    obj_file: &object::File<'data, &'data [u8]>,
+) -> (Vec<Range<u64>>, ObjectSymbols) {
+    let segments = obj_file
+        .segments()
+        .map(|segment| segment.address()..segment.address() + segment.size())
+        .collect();
+    (segments, ObjectSymbols::new(obj_file, path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CacheNative, MayAllocateDuringUnwind, Unwinder};
+
+    #[test]
+    #[cfg_attr(not(debug_assertions), ignore = "needs debug info")]
+    fn inlined() {
+        #[inline(never)]
+        fn outer() -> Vec<InlinedFrame> {
+            inner()
+        }
+
+        #[inline(always)]
+        fn inner() -> Vec<InlinedFrame> {
+            unwind()
+        }
+
+        #[inline(never)]
+        fn unwind() -> Vec<InlinedFrame> {
+            let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+            let mut cache = CacheNative::new();
+            let mut iter = unwinder.iter_frames(&mut cache).inlined();
+            let mut frames = Vec::new();
+            while let Ok(Some(frame)) = iter.try_next() {
+                frames.push(frame);
+            }
+            frames
+        }
+
+        let frames = outer();
+        let i = frames
+            .iter()
+            .position(|frame| {
+                frame
+                    .info
+                    .function
+                    .as_ref()
+                    .is_some_and(|f| f.ends_with("inlined::inner"))
+            })
+            .unwrap();
+        assert!(frames[i].inlined);
+        let function = frames[i + 1].info.function.as_ref().unwrap();
+        assert!(function.ends_with("inlined::outer"), "{function}");
+        assert!(!frames[i + 1].inlined);
+        assert_eq!(frames[i].avma, frames[i + 1].avma);
+    }
+
+    #[test]
+    // The first frame must be this function, not the test harness.
+    #[inline(never)]
+    fn offline() {
+        let backtrace = Unwinder::<MayAllocateDuringUnwind>::new().capture_serializable();
+        let symbolizer = Symbolizer::new(&backtrace.modules, &[]);
+        let resolved = symbolizer.resolve(backtrace.frames[0]).unwrap();
+        assert_eq!(
+            resolved.svma,
+            backtrace.frames[0] - resolved.module.base_addr
+        );
+        let function = resolved.frames.last().unwrap().function.as_ref().unwrap();
+        assert!(function.ends_with("symbolize::test::offline"), "{function}");
+
+        // A file with another build-id is not used.
+        let mut modules = backtrace.modules.clone();
+        for module in &mut modules {
+            module.build_id = Some(vec![0; 20]);
+        }
+        let symbolizer = Symbolizer::new(&modules, &[]);
+        let resolved = symbolizer.resolve(backtrace.frames[0]).unwrap();
+        assert!(resolved.frames.is_empty());
+        assert_eq!(symbolizer.resolve(1), None);
+    }
+
+    #[test]
+    fn context_cache() {
+        fn shared<T: Send + Sync>(_: &T) {}
+
+        let backtrace = Unwinder::<MayAllocateDuringUnwind>::new().capture_serializable();
+        let mut cache = ContextCache::new();
+        shared(&cache);
+        let first = Symbolizer::with_cache(&backtrace.modules, &[], &mut cache);
+        shared(&first);
+        let parsed = cache.len();
+        assert!(parsed > 0);
+        let second = Symbolizer::with_cache(&backtrace.modules, &[], &mut cache);
+        assert_eq!(cache.len(), parsed);
+        for (a, b) in first.modules.iter().zip(&second.modules) {
+            if let (Some(a), Some(b)) = (&a.symbols, &b.symbols) {
+                assert!(Arc::ptr_eq(a, b));
+            }
+        }
+        assert_eq!(
+            first.resolve(backtrace.frames[0]),
+            second.resolve(backtrace.frames[0])
+        );
+
+        // A file with another build-id is not taken from the cache.
+        let mut modules = backtrace.modules.clone();
+        for module in &mut modules {
+            module.build_id = Some(vec![0; 20]);
+        }
+        let symbolizer = Symbolizer::with_cache(&modules, &[], &mut cache);
+        assert!(symbolizer
+            .modules
+            .iter()
+            .all(|module| module.symbols.is_none()));
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn non_pie_modules() {
+        let module = |path: &str, base_addr, segment| SymbolizerModule {
+            module: CapturedModule {
+                path: PathBuf::from(path),
+                base_addr,
+                build_id: None,
+            },
+            segments: vec![segment],
+            symbols: None,
+        };
+        // A non-PIE executable and a prelinked library, both with the base
+        // address 0, and a library whose base address is between them.
+        let symbolizer = Symbolizer::from_modules(vec![
+            module("/bin/exe", 0, 0x40_0000..0x50_0000),
+            module("/lib/libprelinked.so", 0, 0x30_0000_0000..0x30_0010_0000),
+            module("/lib/libfoo.so", 0x1_0000, 0..0x1000),
+        ]);
+        let resolve = |avma| {
+            symbolizer
+                .resolve(avma)
+                .map(|resolved| resolved.module.path.clone())
+        };
+        assert_eq!(resolve(0x1_0001), Some(PathBuf::from("/lib/libfoo.so")));
+        assert_eq!(resolve(0x40_1001), Some(PathBuf::from("/bin/exe")));
+        assert_eq!(
+            resolve(0x30_0000_1001),
+            Some(PathBuf::from("/lib/libprelinked.so"))
+        );
+        assert_eq!(resolve(0x2_0000), None);
+        assert_eq!(symbolizer.resolve(0x40_1001).unwrap().svma, 0x40_1001);
+    }
+
+    #[test]
+    fn missing_file() {
+        let module = CapturedModule {
+            path: PathBuf::from("/nonexistent/libmissing.so"),
+            base_addr: 0x7000_0000,
+            build_id: None,
+        };
+        let symbolizer = Symbolizer::new(std::slice::from_ref(&module), &[]);
+        // Without segments, any address above the base address is attributed
+        // to the module.
+        let resolved = symbolizer.resolve(0x7000_1001).unwrap();
+        assert_eq!(resolved.module, &module);
+        assert_eq!(resolved.svma, 0x1001);
+        assert!(resolved.frames.is_empty());
+        assert_eq!(symbolizer.resolve(0x7000_0000), None);
+    }
+}
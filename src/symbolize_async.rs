@@ -0,0 +1,166 @@
+//! Symbolication on background threads, so that async code does not block
+//! its runtime while the debug info is read and parsed.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use once_cell::sync::Lazy;
+
+use crate::diagnostic::warning;
+use crate::FrameInfo;
+
+type Job = (Vec<usize>, Arc<Mutex<State>>);
+
+/// The most threads that symbolicate at once. Each thread keeps its own cache
+/// of parsed debug info, so more threads parse the same files more often.
+const MAX_WORKERS: usize = 4;
+
+/// The queue shared by the symbolication threads, so that a backtrace whose
+/// debug info is slow to load doesn't hold up the others.
+static JOBS: Lazy<Option<mpsc::Sender<Job>>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let workers = thread::available_parallelism().map_or(1, |n| n.get().min(MAX_WORKERS));
+    let started = (0..workers)
+        .filter(|_| {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name("runwind-symbolize".to_string())
+                .spawn(move || work(&receiver))
+                .map_err(|e| warning!("Failed to start a symbolication thread: {e}"))
+                .is_ok()
+        })
+        .count();
+    (started > 0).then_some(sender)
+});
+
+fn work(receiver: &Mutex<mpsc::Receiver<Job>>) {
+    loop {
+        // The lock is only held while waiting, not while symbolicating.
+        let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let Ok((addrs, state)) = job else {
+            return;
+        };
+        let frames = symbolicate_all(&addrs);
+        state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .complete(frames);
+    }
+}
+
+fn symbolicate_all(addrs: &[usize]) -> Vec<FrameInfo> {
+    addrs
+        .iter()
+        .flat_map(|&addr| crate::symbolize::symbolicate(addr))
+        .collect()
+}
+
+#[derive(Default)]
+struct State {
+    frames: Option<Vec<FrameInfo>>,
+    waker: Option<Waker>,
+}
+
+impl State {
+    fn complete(&mut self, frames: Vec<FrameInfo>) {
+        self.frames = Some(frames);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The frames at the addresses of a backtrace, symbolicated on a background
+/// thread, see [`Unwinder::symbolicate_async`](crate::Unwinder::symbolicate_async).
+pub struct SymbolicateFuture {
+    state: Arc<Mutex<State>>,
+}
+
+impl SymbolicateFuture {
+    pub(crate) fn new(addrs: &[usize]) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let queued = JOBS
+            .as_ref()
+            .is_some_and(|jobs| jobs.send((addrs.to_vec(), state.clone())).is_ok());
+        if !queued {
+            // Blocking is better than never completing.
+            let frames = symbolicate_all(addrs);
+            state
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .complete(frames);
+        }
+        SymbolicateFuture { state }
+    }
+}
+
+impl Future for SymbolicateFuture {
+    type Output = Vec<FrameInfo>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Vec<FrameInfo>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.frames.take() {
+            Some(frames) => Poll::Ready(frames),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{pin::pin, task::Wake};
+
+    use super::*;
+    use crate::{MayAllocateDuringUnwind, Unwinder};
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    // The first frame must be this function, not the test harness.
+    #[inline(never)]
+    fn symbolicate_async() {
+        let frames = Unwinder::<MayAllocateDuringUnwind>::new().capture();
+        let expected: Vec<_> = frames
+            .iter()
+            .flat_map(|&addr| Unwinder::<MayAllocateDuringUnwind>::symbolicate(addr))
+            .collect();
+        let futures: Vec<_> = (0..8)
+            .map(|_| Unwinder::<MayAllocateDuringUnwind>::symbolicate_async(&frames))
+            .collect();
+        for future in futures {
+            assert_eq!(block_on(future), expected);
+        }
+        let names = block_on(Unwinder::<MayAllocateDuringUnwind>::symbolicate_async(
+            &frames[..1],
+        ));
+        assert!(names.iter().any(|frame| frame.function.as_deref()
+            == Some("runwind::symbolize_async::test::symbolicate_async")));
+    }
+}
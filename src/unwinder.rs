@@ -1,15 +1,133 @@
-use std::{arch::asm, num::NonZeroU64};
+use std::{arch::asm, cell::RefCell, num::NonZeroU64, ops::Range, path::PathBuf, thread::LocalKey};
 
 use framehop::{
-    AllocationPolicy, CacheNative, Error, FrameAddress, UnwindRegsNative, Unwinder as _,
-    UnwinderNative,
+    AllocationPolicy, CacheNative, Error, FrameAddress, MayAllocateDuringUnwind, Module,
+    ModuleSvmaInfo, ModuleUnwindData, MustNotAllocateDuringUnwind, TextByteData, UnwindRegsNative,
+    Unwinder as _, UnwinderNative,
 };
+use once_cell::sync::OnceCell;
 
-pub struct Unwinder<P>
+use crate::jit::JitUnwindInfo;
+#[cfg(target_os = "linux")]
+use crate::memory::ProcessMemory;
+use crate::memory::{LocalMemory, StackMemory};
+use crate::object::Object;
+use crate::{Backtrace, CapturedBacktrace, LoadError, UnwindError};
+
+/// The functions at the bottom of the stacks of threads, where unwinding stops
+/// by default.
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_TERMINATORS: &[&str] = &[
+    "_start",
+    "__libc_start_main",
+    "__libc_start_call_main",
+    "start_thread",
+    "clone",
+    "clone3",
+];
+
+/// The functions at the bottom of the stacks of threads, where unwinding stops
+/// by default.
+#[cfg(target_os = "macos")]
+pub const DEFAULT_TERMINATORS: &[&str] = &["start", "thread_start"];
+
+/// The objects holding the `rt_sigreturn` trampoline that signal handlers
+/// return to: the vDSO on aarch64, libc on x86_64. Without libc, e.g. in a
+/// static binary, the executable holds it.
+const SIGRETURN_OBJECTS: [&[&str]; 2] = [
+    &["linux-vdso.so*", "linux-gate.so*"],
+    &["libc.so*", "libc.musl*", "ld-musl*"],
+];
+
+/// A module added to an [`Unwinder`], see [`Unwinder::modules`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    /// The path of the object, or any name for a module added by hand.
+    pub name: String,
+    /// The AVMA of SVMA 0, i.e. the load bias of an ELF object.
+    pub base_avma: u64,
+    /// The AVMA range of the text, which identifies the module in framehop.
+    pub avma_range: Range<u64>,
+}
+
+/// The size of the unwind information an [`Unwinder`] knows, see
+/// [`Unwinder::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnwinderStats {
+    /// The number of modules, both discovered and added by hand.
+    pub modules: usize,
+    /// The total size of the `.eh_frame` sections of the discovered objects.
+    pub eh_frame_bytes: usize,
+    /// The total number of FDEs in the `.eh_frame_hdr` search tables of the
+    /// discovered objects. Objects without a table, like Mach-O images, count
+    /// none.
+    pub fde_count: usize,
+}
+
+/// Unwinds the stacks of the current process with the unwind information of
+/// its loaded objects.
+///
+/// An `Unwinder` is `Send` and `Sync`: the objects it borrows from are mapped
+/// for the lifetime of the process and never mutated, so it can be built once
+/// and shared by reference between threads. Each thread needs its own
+/// [`CacheNative`], since unwinding mutates it.
+pub struct Unwinder<P = MayAllocateDuringUnwind>
 where
     P: AllocationPolicy<&'static [u8]>,
 {
     unwinder: UnwinderNative<&'static [u8], P>,
+    /// All modules added to `unwinder`.
+    modules: Vec<ModuleInfo>,
+    /// The discovered objects added to `unwinder`.
+    objects: Vec<&'static Object>,
+    terminators: Vec<String>,
+    /// The AVMA ranges of the `terminators` functions found in the objects.
+    terminator_avmas: Vec<Range<u64>>,
+    /// The path patterns of the objects whose frames are not returned, see
+    /// [`Unwinder::set_opaque_objects`].
+    opaque_objects: Vec<String>,
+    /// The base AVMAs set with [`Unwinder::set_opaque_modules`].
+    opaque_bases: Vec<u64>,
+    /// The text AVMA ranges of the objects matching `opaque_objects` and of the
+    /// modules at `opaque_bases`.
+    opaque_avmas: Vec<Range<u64>>,
+    /// The AVMA ranges of the PLT stubs without unwind information.
+    stub_avmas: Vec<Range<u64>>,
+    /// The text AVMA ranges of the objects that may hold the signal return
+    /// trampoline, see [`SIGRETURN_OBJECTS`].
+    sigreturn_avmas: Vec<Range<u64>>,
+    /// The JIT regions added with [`JitUnwindInfo::FixedFrame`] and their
+    /// frame sizes.
+    fixed_frames: Vec<(Range<u64>, u64)>,
+    /// Whether the modules of discovered objects are built when first needed,
+    /// see [`Unwinder::new_lazy`].
+    lazy: bool,
+    /// The discovered objects whose modules are not added to `unwinder`.
+    lazy_modules: Vec<LazyModule<P>>,
+}
+
+/// An object whose module is built when an address in its text is unwound,
+/// in a framehop unwinder of its own so that `&self` suffices.
+struct LazyModule<P>
+where
+    P: AllocationPolicy<&'static [u8]>,
+{
+    object: &'static Object,
+    avma_range: Range<u64>,
+    unwinder: OnceCell<UnwinderNative<&'static [u8], P>>,
+}
+
+impl<P> LazyModule<P>
+where
+    P: AllocationPolicy<&'static [u8]>,
+{
+    fn unwinder(&self) -> &UnwinderNative<&'static [u8], P> {
+        self.unwinder.get_or_init(|| {
+            let mut unwinder = UnwinderNative::new();
+            unwinder.add_module(self.object.to_module());
+            unwinder
+        })
+    }
 }
 
 impl<P> Unwinder<P>
@@ -17,96 +135,837 @@ where
     P: AllocationPolicy<&'static [u8]>,
 {
     pub fn new() -> Self {
-        let mut unwinder = UnwinderNative::new();
-        for obj in crate::get_objects() {
-            unwinder.add_module(obj.to_module());
+        Self::from_objects(&crate::get_objects())
+    }
+
+    /// Creates an unwinder with the modules of `objects`, e.g. those returned
+    /// by [`get_objects`](crate::get_objects) once and shared by unwinders of
+    /// different allocation policies. The objects are not parsed again.
+    ///
+    /// [`Unwinder::refresh`] replaces the modules with those of all loaded
+    /// objects.
+    pub fn from_objects(objects: &[&'static Object]) -> Self {
+        let mut unwinder = Unwinder {
+            unwinder: UnwinderNative::new(),
+            modules: Vec::new(),
+            objects: Vec::new(),
+            terminators: DEFAULT_TERMINATORS.iter().map(|s| s.to_string()).collect(),
+            terminator_avmas: Vec::new(),
+            opaque_objects: Vec::new(),
+            opaque_bases: Vec::new(),
+            opaque_avmas: Vec::new(),
+            stub_avmas: Vec::new(),
+            sigreturn_avmas: Vec::new(),
+            fixed_frames: Vec::new(),
+            lazy: false,
+            lazy_modules: Vec::new(),
+        };
+        unwinder.sync_objects(objects);
+        unwinder
+    }
+
+    /// Adds a module that is not discovered automatically, e.g. one with unwind
+    /// information constructed by hand. The arguments are those of
+    /// [`Module::new`], with the name and ranges given by `info`.
+    pub fn add_module(
+        &mut self,
+        info: ModuleInfo,
+        svma_info: ModuleSvmaInfo,
+        unwind_data: ModuleUnwindData<&'static [u8]>,
+        text_data: Option<TextByteData<&'static [u8]>>,
+    ) {
+        self.unwinder.add_module(Module::new(
+            info.name.clone(),
+            info.avma_range.clone(),
+            info.base_avma,
+            svma_info,
+            unwind_data,
+            text_data,
+        ));
+        self.modules.push(info);
+        self.find_opaque_objects();
+    }
+
+    /// Adds a region of code generated at runtime, e.g. one returned by
+    /// [`find_jit_regions`](crate::jit::find_jit_regions), so that its frames
+    /// are unwound with `unwind_info`. Without it, frames in the region are
+    /// unwound with frame pointers at best.
+    ///
+    /// The region must not overlap the text of an object.
+    ///
+    /// Remove the region with [`Unwinder::remove_module`] and the start of
+    /// `range` before the code is freed.
+    pub fn add_jit_region(&mut self, range: Range<usize>, unwind_info: JitUnwindInfo) {
+        let avma_range = range.start as u64..range.end as u64;
+        // The code is not relocated, so its SVMAs are its AVMAs.
+        let (unwind_data, eh_frame) = match unwind_info {
+            JitUnwindInfo::FramePointer => (ModuleUnwindData::None, None),
+            JitUnwindInfo::FixedFrame { size } => {
+                // Unwound by the iterator, framehop only knows the range.
+                self.fixed_frames.push((avma_range.clone(), size));
+                (ModuleUnwindData::None, None)
+            }
+            JitUnwindInfo::EhFrame(data) => {
+                let start = data.as_ptr() as u64;
+                (
+                    ModuleUnwindData::EhFrame(data),
+                    Some(start..start + data.len() as u64),
+                )
+            }
+        };
+        let svma_info = ModuleSvmaInfo {
+            base_svma: avma_range.start,
+            text: Some(avma_range.clone()),
+            text_env: None,
+            stubs: None,
+            stub_helper: None,
+            eh_frame,
+            eh_frame_hdr: None,
+            got: None,
+        };
+        let info = ModuleInfo {
+            name: "[jit]".to_string(),
+            base_avma: avma_range.start,
+            avma_range,
+        };
+        self.add_module(info, svma_info, unwind_data, None);
+    }
+
+    /// Removes the module whose text AVMA range starts at `avma_range_start`.
+    pub fn remove_module(&mut self, avma_range_start: u64) {
+        self.remove(avma_range_start);
+        self.find_object_ranges();
+    }
+
+    fn remove(&mut self, avma_range_start: u64) {
+        self.unwinder.remove_module(avma_range_start);
+        self.modules
+            .retain(|module| module.avma_range.start != avma_range_start);
+        self.objects
+            .retain(|obj| obj.text_avma().start as u64 != avma_range_start);
+        self.fixed_frames
+            .retain(|(avma, _)| avma.start != avma_range_start);
+        self.lazy_modules
+            .retain(|module| module.avma_range.start != avma_range_start);
+    }
+
+    /// Returns the modules the unwinder knows, both discovered and added by
+    /// hand, in the order they were added.
+    pub fn modules(&self) -> impl Iterator<Item = &ModuleInfo> {
+        self.modules.iter()
+    }
+
+    /// Returns the number of modules and the size of the unwind information
+    /// of the discovered objects, e.g. to report what a profiler loaded.
+    pub fn stats(&self) -> UnwinderStats {
+        let mut stats = UnwinderStats {
+            modules: self.modules.len(),
+            ..UnwinderStats::default()
+        };
+        for obj in &self.objects {
+            stats.eh_frame_bytes += obj.eh_frame_len();
+            stats.fde_count += obj.fde_count();
         }
-        Unwinder { unwinder }
+        stats
     }
 
-    #[cfg(target_arch = "x86_64")]
+    /// Discovers the loaded objects again with
+    /// [`refresh_objects`](crate::refresh_objects), then adds the modules of new
+    /// objects and removes those of objects that are gone.
+    pub fn refresh(&mut self) {
+        crate::refresh_objects();
+        self.sync_objects(&crate::get_objects());
+    }
+
+    /// Sets the names of the functions where unwinding stops, replacing
+    /// [`DEFAULT_TERMINATORS`]. The frame of such a function is the last one
+    /// returned, so that unwinding doesn't run past the entry point of a thread
+    /// into invalid memory.
+    ///
+    /// The functions are looked up in the symbol tables of the objects.
+    pub fn set_terminators(&mut self, names: &[&str]) {
+        self.terminators = names.iter().map(|s| s.to_string()).collect();
+        self.find_terminators();
+    }
+
+    /// Sets the objects whose frames are unwound through but not returned, e.g.
+    /// libc or the allocator when profiling the application's code, replacing
+    /// the previous ones.
+    ///
+    /// The patterns are matched against the path of each object and its file
+    /// name, and `*` matches any characters, e.g. `libc.so*` or
+    /// `*/libjemalloc.so*`.
+    pub fn set_opaque_objects(&mut self, patterns: &[&str]) {
+        self.opaque_objects = patterns.iter().map(|s| s.to_string()).collect();
+        self.find_opaque_objects();
+    }
+
+    /// Like [`Unwinder::set_opaque_objects`], but selects the modules by their
+    /// [`ModuleInfo::base_avma`], e.g. the load biases given to
+    /// [`Unwinder::from_paths`]. This also applies to modules added by hand.
+    pub fn set_opaque_modules(&mut self, base_avmas: &[u64]) {
+        self.opaque_bases = base_avmas.to_vec();
+        self.find_opaque_objects();
+    }
+
+    /// Creates an unwinder with the modules of the object files at `paths`,
+    /// loaded with the load biases `bases` (the slides on macOS), without
+    /// discovering the objects of the process. This is useful when the loader
+    /// can't be asked, e.g. for the mappings of a core dump.
+    ///
+    /// The objects are never freed, like those of
+    /// [`get_objects`](crate::get_objects). Fails with the path of the first
+    /// object that can't be loaded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `paths` and `bases` have different lengths.
+    pub fn from_paths(paths: &[PathBuf], bases: &[usize]) -> Result<Self, (PathBuf, LoadError)> {
+        assert_eq!(paths.len(), bases.len(), "one base per path");
+        let objects = paths
+            .iter()
+            .zip(bases)
+            .map(|(path, &base_addr)| {
+                let obj = Object::from_file(path, base_addr).map_err(|e| (path.clone(), e))?;
+                Ok(&*Box::leak(Box::new(obj)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_objects(&objects))
+    }
+
+    /// Makes the first backtraces with `cache` faster, e.g. before sampling
+    /// starts. This prepares the modules of [`Unwinder::new_lazy`], pages in
+    /// the unwind sections of all objects, and caches the unwind rules of the
+    /// current call stack, whose outer frames are shared by most stacks of the
+    /// thread.
+    ///
+    /// The CIEs and FDEs of other functions are not parsed ahead of time:
+    /// framehop parses the FDE of an address when it is first unwound, and
+    /// caches only the resulting rule.
+    ///
+    /// The cache holds a fixed number of rules, so this costs no memory in
+    /// it. The prepared modules keep their indexes until the unwinder is
+    /// dropped, and the paged in sections count towards the resident memory
+    /// of the process, although the kernel may drop them again.
+    #[inline(never)]
+    pub fn warm_up(&self, cache: &mut CacheNative<&'static [u8], P>) {
+        for module in &self.lazy_modules {
+            module.unwinder();
+        }
+        for obj in &self.objects {
+            obj.prefault_unwind_data();
+        }
+        let (pc, regs) = current_regs();
+        let mut iter = self.iter_frames_with_regs(pc as usize, regs, cache);
+        while let Ok(Some(_)) = iter.try_next() {}
+    }
+
+    fn find_terminators(&mut self) {
+        self.terminator_avmas = self
+            .objects
+            .iter()
+            .flat_map(|obj| obj.symbol_avmas(&self.terminators))
+            .map(|avma| avma.start as u64..avma.end as u64)
+            .collect();
+    }
+
+    fn find_opaque_objects(&mut self) {
+        let by_base = self
+            .modules
+            .iter()
+            .filter(|module| self.opaque_bases.contains(&module.base_avma))
+            .map(|module| module.avma_range.clone());
+        self.opaque_avmas = self
+            .objects
+            .iter()
+            .filter(|obj| {
+                let path = obj.path().to_string_lossy();
+                let name = obj.name().unwrap_or_default();
+                self.opaque_objects.iter().any(|pattern| {
+                    matches_pattern(pattern.as_bytes(), path.as_bytes())
+                        || matches_pattern(pattern.as_bytes(), name.as_bytes())
+                })
+            })
+            .map(|obj| obj.text_avma().start as u64..obj.text_avma().end as u64)
+            .chain(by_base)
+            .collect();
+    }
+
+    /// Finds the ranges of the objects handled apart from their modules: the
+    /// terminators, the opaque objects, the PLT stubs and the signal return
+    /// trampoline.
+    fn find_object_ranges(&mut self) {
+        self.find_terminators();
+        self.find_opaque_objects();
+        self.stub_avmas = self
+            .objects
+            .iter()
+            .flat_map(|obj| obj.stub_avmas())
+            .map(|avma| avma.start as u64..avma.end as u64)
+            .collect();
+        let [vdso, libc] = SIGRETURN_OBJECTS.map(|patterns| {
+            move |obj: &Object| {
+                let name = obj.name().unwrap_or_default();
+                patterns
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern.as_bytes(), name.as_bytes()))
+            }
+        });
+        let static_exe = !self.objects.iter().any(|obj| libc(obj));
+        self.sigreturn_avmas = self
+            .objects
+            .iter()
+            .filter(|obj| vdso(obj) || libc(obj) || static_exe && obj.is_main_executable())
+            .map(|obj| obj.text_avma().start as u64..obj.text_avma().end as u64)
+            .collect();
+    }
+
+    fn sync_objects(&mut self, objects: &[&'static Object]) {
+        let contains = |objects: &[&'static Object], obj: &'static Object| {
+            objects.iter().any(|o| std::ptr::eq(*o, obj))
+        };
+        let removed: Vec<_> = self
+            .objects
+            .iter()
+            .filter(|obj| !contains(objects, obj))
+            .map(|obj| obj.text_avma().start as u64)
+            .collect();
+        for start in removed {
+            self.remove(start);
+        }
+        for &obj in objects {
+            if !contains(&self.objects, obj) {
+                let info = obj.module_info();
+                if self.lazy {
+                    self.lazy_modules.push(LazyModule {
+                        object: obj,
+                        avma_range: info.avma_range.clone(),
+                        unwinder: OnceCell::new(),
+                    });
+                } else {
+                    self.unwinder.add_module(obj.to_module());
+                }
+                self.modules.push(info);
+                self.objects.push(obj);
+            }
+        }
+        self.find_object_ranges();
+    }
+
+    /// Unwinds the current call stack, starting from the caller of
+    /// `iter_frames`.
+    #[inline(never)]
     pub fn iter_frames<'u, 'c>(
         &'u self,
         cache: &'c mut CacheNative<&'static [u8], P>,
     ) -> UnwindIterator<'u, 'c, P> {
-        let (ip, sp, bp): (u64, u64, u64);
-        unsafe {
-            asm!(
-                "lea {ip}, [rip]",
-                "mov {sp}, rsp",
-                "mov {bp}, rbp",
-                ip = out(reg) ip,
-                sp = out(reg) sp,
-                bp = out(reg) bp,
-            );
-        }
-        let regs = UnwindRegsNative::new(ip, sp, bp);
-        UnwindIterator {
-            unwinder: &self.unwinder,
-            cache,
-            regs,
-            addr: FrameAddress::InstructionPointer(ip),
-        }
+        let (pc, regs) = current_regs();
+        self.iter_frames_with_regs(pc as usize, regs, cache)
     }
 
-    #[cfg(target_arch = "x86_64")]
+    /// Unwinds from the registers of a frame interrupted at the instruction
+    /// `pc`, e.g. by a signal or ptrace. See
+    /// [`Unwinder::iter_frames_from_address`] for a saved context that
+    /// continues at a return address.
+    ///
+    /// The registers may also be those saved for a suspended coroutine or green
+    /// thread. If its stack is not in place, e.g. because it was copied out
+    /// when switching, read it with [`UnwindIterator::with_memory`].
     pub fn iter_frames_with_regs<'u, 'c>(
         &'u self,
         pc: usize,
         regs: UnwindRegsNative,
         cache: &'c mut CacheNative<&'static [u8], P>,
     ) -> UnwindIterator<'u, 'c, P> {
-        UnwindIterator {
-            unwinder: &self.unwinder,
-            cache,
-            regs,
-            addr: FrameAddress::InstructionPointer(pc as u64),
-        }
+        self.iter_frames_from_address(FrameAddress::InstructionPointer(pc as u64), regs, cache)
     }
 
-    #[cfg(target_arch = "aarch64")]
-    pub fn iter_frames<'u, 'c>(
+    /// Like [`Unwinder::iter_frames_with_regs`], but tells whether the address
+    /// of the first frame is an instruction pointer or a return address, e.g.
+    /// for a context saved by a call to a function like `setjmp` or
+    /// `swapcontext`. A return address is looked up as the call before it, like
+    /// the addresses of the outer frames.
+    pub fn iter_frames_from_address<'u, 'c>(
         &'u self,
+        addr: FrameAddress,
+        regs: UnwindRegsNative,
         cache: &'c mut CacheNative<&'static [u8], P>,
     ) -> UnwindIterator<'u, 'c, P> {
-        let (lr, sp, fp, pc): (u64, u64, u64, u64);
-        unsafe {
-            asm!(
-                "mov {lr}, lr",
-                "mov {sp}, sp",
-                "mov {fp}, fp",
-                "adr {pc}, .",
-                lr = out(reg) lr,
-                sp = out(reg) sp,
-                fp = out(reg) fp,
-                pc = out(reg) pc,
-            );
-        }
-        let regs = UnwindRegsNative::new(lr, sp, fp);
         UnwindIterator {
             unwinder: &self.unwinder,
             cache,
             regs,
-            addr: FrameAddress::InstructionPointer(pc),
+            addr,
+            max_depth: None,
+            count: 0,
+            recent: [(0, 0); RECENT_FRAMES],
+            check_sp: true,
+            stack_bounds: None,
+            memory: LocalMemory,
+            terminators: &self.terminator_avmas,
+            stubs: &self.stub_avmas,
+            sigreturn: &self.sigreturn_avmas,
+            fixed_frames: &self.fixed_frames,
+            lazy_modules: &self.lazy_modules,
+            skip_below: None,
+            opaque: &self.opaque_avmas,
+            switched_to: None,
         }
     }
 
-    #[cfg(target_arch = "aarch64")]
-    pub fn iter_frames_with_regs<'u, 'c>(
+    /// Unwinds a thread of another process that has been stopped with ptrace.
+    /// Its stack is read with `process_vm_readv`.
+    ///
+    /// See [`StoppedThread`](crate::StoppedThread) for which processes can be
+    /// unwound this way.
+    #[cfg(target_os = "linux")]
+    pub fn iter_frames_of_thread<'u, 'c>(
         &'u self,
-        pc: usize,
-        regs: UnwindRegsNative,
+        thread: &crate::StoppedThread,
+        cache: &'c mut CacheNative<&'static [u8], P>,
+    ) -> std::io::Result<UnwindIterator<'u, 'c, P, ProcessMemory>> {
+        let (pc, regs) = thread.regs()?;
+        Ok(self
+            .iter_frames_with_regs(pc as usize, regs, cache)
+            .with_memory(ProcessMemory::new(thread.tid())))
+    }
+
+    /// Unwinds the context interrupted by a signal, e.g. from a `SIGPROF` handler
+    /// installed with `SA_SIGINFO`.
+    ///
+    /// Everything done while unwinding must be async-signal-safe: use
+    /// [`MustNotAllocateDuringUnwind`](crate::MustNotAllocateDuringUnwind), create
+    /// the cache outside of the handler, and do not share it with the code that
+    /// may be interrupted.
+    ///
+    /// # Safety
+    ///
+    /// `ucontext` must point to a valid `ucontext_t`, such as the third argument
+    /// passed to the signal handler.
+    pub unsafe fn iter_frames_from_ucontext<'u, 'c>(
+        &'u self,
+        ucontext: *const libc::ucontext_t,
         cache: &'c mut CacheNative<&'static [u8], P>,
     ) -> UnwindIterator<'u, 'c, P> {
-        UnwindIterator {
-            unwinder: &self.unwinder,
-            cache,
-            regs,
-            addr: FrameAddress::InstructionPointer(pc as u64),
+        let (pc, regs) = regs_from_ucontext(&*ucontext);
+        self.iter_frames_with_regs(pc as usize, regs, cache)
+    }
+
+    /// Writes up to `out.len()` return addresses of the current call stack into
+    /// `out`, starting from the caller of `fill_backtrace`, and returns how many
+    /// were written. Unwinding stops at the first error.
+    ///
+    /// This never allocates when used with
+    /// [`MustNotAllocateDuringUnwind`](crate::MustNotAllocateDuringUnwind), so it
+    /// is suitable for signal handlers.
+    #[inline(never)]
+    pub fn fill_backtrace(
+        &self,
+        cache: &mut CacheNative<&'static [u8], P>,
+        out: &mut [usize],
+    ) -> usize {
+        let (pc, regs) = current_regs();
+        self.fill_backtrace_with_regs(pc, regs, cache, out)
+    }
+
+    fn fill_backtrace_with_regs(
+        &self,
+        pc: u64,
+        regs: UnwindRegsNative,
+        cache: &mut CacheNative<&'static [u8], P>,
+        out: &mut [usize],
+    ) -> usize {
+        Self::fill(self.iter_frames_with_regs(pc as usize, regs, cache), out)
+    }
+
+    fn fill(mut iter: UnwindIterator<'_, '_, P>, out: &mut [usize]) -> usize {
+        let mut len = 0;
+        while len < out.len() {
+            match iter.try_next() {
+                Ok(Some(addr)) => out[len] = addr,
+                _ => break,
+            }
+            len += 1;
+        }
+        len
+    }
+
+    /// Returns the function, file and line of the return address `addr`, one
+    /// entry per inlined function, innermost first.
+    ///
+    /// The parsed debug info is cached per thread and per object.
+    #[cfg(feature = "symbolize")]
+    pub fn symbolicate(addr: usize) -> Vec<crate::FrameInfo> {
+        crate::symbolize::symbolicate(addr)
+    }
+
+    /// Like [`Unwinder::symbolicate`], but symbolicates all the addresses of a
+    /// backtrace on one of a few background threads and returns a future of
+    /// their frames, in order, so that async code doesn't block its runtime on
+    /// reading debug info. Capture the backtrace itself synchronously, since it
+    /// reads the registers of the current thread.
+    ///
+    /// The future doesn't depend on a particular runtime.
+    #[cfg(feature = "async")]
+    pub fn symbolicate_async(addrs: &[usize]) -> crate::SymbolicateFuture {
+        crate::SymbolicateFuture::new(addrs)
+    }
+
+    /// Walks the frame pointer chain from the current frame, without consulting
+    /// any unwind information.
+    ///
+    /// This is faster than [`Unwinder::iter_frames`], but only accurate if all
+    /// code on the stack is compiled with frame pointers (e.g. with
+    /// `-C force-frame-pointers=yes`). The walk stops as soon as the chain leaves
+    /// readable memory.
+    #[inline(never)]
+    pub fn iter_frames_fp(&self) -> FramePointerIterator {
+        let (_, regs) = current_regs();
+        FramePointerIterator {
+            fp: frame_pointer(&regs),
+        }
+    }
+
+    /// Captures the return addresses of the current call stack, starting from the
+    /// caller of `backtrace`. Unwinding stops at the first error.
+    #[inline(never)]
+    pub fn backtrace(&self, cache: &mut CacheNative<&'static [u8], P>) -> Vec<usize> {
+        // The registers belong to the frame of `backtrace` itself, so the first
+        // unwound address is already in the caller.
+        let (pc, regs) = current_regs();
+        let mut iter = self.iter_frames_with_regs(pc as usize, regs, cache);
+        let mut frames = Vec::new();
+        while let Ok(Some(addr)) = iter.try_next() {
+            frames.push(addr);
         }
+        frames
     }
 }
 
+thread_local! {
+    static MAY_ALLOCATE_CACHE: RefCell<CacheNative<&'static [u8], MayAllocateDuringUnwind>> =
+        RefCell::new(CacheNative::new());
+    static MUST_NOT_ALLOCATE_CACHE: RefCell<CacheNative<&'static [u8], MustNotAllocateDuringUnwind>> =
+        RefCell::new(CacheNative::new());
+}
+
+/// An allocation policy with a [`CacheNative`] kept per thread, see
+/// [`Unwinder::with_thread_cache`].
+///
+/// Unwinding takes the cache by `&mut`, so a cache can't be used by several
+/// threads at once.
+pub trait ThreadCachePolicy: AllocationPolicy<&'static [u8]> + Sized + 'static {
+    #[doc(hidden)]
+    fn thread_cache() -> &'static LocalKey<RefCell<CacheNative<&'static [u8], Self>>>;
+}
+
+impl ThreadCachePolicy for MayAllocateDuringUnwind {
+    fn thread_cache() -> &'static LocalKey<RefCell<CacheNative<&'static [u8], Self>>> {
+        &MAY_ALLOCATE_CACHE
+    }
+}
+
+impl ThreadCachePolicy for MustNotAllocateDuringUnwind {
+    fn thread_cache() -> &'static LocalKey<RefCell<CacheNative<&'static [u8], Self>>> {
+        &MUST_NOT_ALLOCATE_CACHE
+    }
+}
+
+impl<P: ThreadCachePolicy> Unwinder<P> {
+    /// Calls `f` with the cache of the current thread, so that repeated
+    /// unwinding on a thread reuses one cache without passing it around.
+    ///
+    /// Returns `None` if the cache is already in use on this thread, e.g. by
+    /// the code a signal handler interrupted. The cache is allocated on the
+    /// first use in each thread, so a signal handler must not be the first
+    /// user.
+    pub fn with_thread_cache<R>(
+        f: impl FnOnce(&mut CacheNative<&'static [u8], P>) -> R,
+    ) -> Option<R> {
+        P::thread_cache().with(|cache| cache.try_borrow_mut().ok().map(|mut cache| f(&mut cache)))
+    }
+
+    /// Like [`Unwinder::fill_backtrace`], but with the cache of the current
+    /// thread. Writes nothing if the cache is in use, see
+    /// [`Unwinder::with_thread_cache`].
+    #[inline(never)]
+    pub fn fill_backtrace_cached(&self, out: &mut [usize]) -> usize {
+        let (pc, regs) = current_regs();
+        Self::with_thread_cache(|cache| self.fill_backtrace_with_regs(pc, regs, cache, out))
+            .unwrap_or(0)
+    }
+
+    /// Like [`Unwinder::fill_backtrace_cached`], but returns up to `N` frames
+    /// in a [`Backtrace`] instead of writing them to a slice, starting at the
+    /// caller of this function. This never allocates with
+    /// [`MustNotAllocateDuringUnwind`](crate::MustNotAllocateDuringUnwind).
+    #[inline(never)]
+    pub fn capture_into<const N: usize>(&self) -> Backtrace<N> {
+        let (pc, regs) = current_regs();
+        Backtrace::fill(|out| {
+            Self::with_thread_cache(|cache| {
+                let iter = self.iter_frames_with_regs(pc as usize, regs, cache);
+                Self::fill(iter, out)
+            })
+            .unwrap_or(0)
+        })
+    }
+}
+
+impl Unwinder<MayAllocateDuringUnwind> {
+    /// Like [`Unwinder::new`], but the unwind information of each object is
+    /// only prepared when the first address in its text is unwound, including
+    /// after [`Unwinder::refresh`]. This makes creating the unwinder cheaper in
+    /// processes with many libraries, most of which never appear in a
+    /// backtrace.
+    ///
+    /// Preparing a module allocates, so this is only available to unwinders
+    /// that may allocate.
+    pub fn new_lazy() -> Self {
+        let mut unwinder = Self::from_objects(&[]);
+        unwinder.lazy = true;
+        unwinder.sync_objects(&crate::get_objects());
+        unwinder
+    }
+
+    /// Like [`Unwinder::backtrace`], but with a cache kept per thread, for
+    /// callers that don't need to control caching. The frames start at the
+    /// caller of this function: it is never inlined and reads the registers in
+    /// its own frame, so no frame of runwind is returned.
+    ///
+    /// This allocates, so it must not be used in signal handlers.
+    #[inline(never)]
+    pub fn capture(&self) -> Vec<usize> {
+        let (pc, regs) = current_regs();
+        self.capture_with_regs(pc, regs)
+    }
+
+    /// Like [`Unwinder::capture`], but also records the objects the frames are
+    /// in, so that the backtrace can be sent elsewhere and symbolicated there.
+    #[inline(never)]
+    pub fn capture_serializable(&self) -> CapturedBacktrace {
+        let (pc, regs) = current_regs();
+        let frames = self.capture_with_regs(pc, regs);
+        CapturedBacktrace::new(frames.into_iter().map(|addr| addr as u64).collect())
+    }
+
+    fn capture_with_regs(&self, pc: u64, regs: UnwindRegsNative) -> Vec<usize> {
+        let unwind = |cache: &mut CacheNative<_, _>| {
+            let mut iter = self.iter_frames_with_regs(pc as usize, regs, cache);
+            let mut frames = Vec::new();
+            while let Ok(Some(addr)) = iter.try_next() {
+                frames.push(addr);
+            }
+            frames
+        };
+        // The cache is in use if this is re-entered, e.g. from a panic hook or
+        // a destructor while capturing.
+        Self::with_thread_cache(unwind).unwrap_or_else(|| unwind(&mut CacheNative::new()))
+    }
+}
+
+/// An [`Unwinder`] whose allocation policy is chosen at runtime.
+///
+/// The methods dispatch to the inner unwinder and unwind with the cache of the
+/// current thread, see [`Unwinder::with_thread_cache`].
+pub enum AnyUnwinder {
+    /// For unwinding outside of signal handlers, e.g. before symbolicating.
+    MayAllocate(Unwinder<MayAllocateDuringUnwind>),
+    /// For unwinding in signal handlers.
+    MustNotAllocate(Unwinder<MustNotAllocateDuringUnwind>),
+}
+
+impl AnyUnwinder {
+    /// Creates an unwinder with [`MayAllocateDuringUnwind`] if `may_allocate`
+    /// is true, otherwise with [`MustNotAllocateDuringUnwind`].
+    pub fn new(may_allocate: bool) -> Self {
+        if may_allocate {
+            AnyUnwinder::MayAllocate(Unwinder::new())
+        } else {
+            AnyUnwinder::MustNotAllocate(Unwinder::new())
+        }
+    }
+
+    /// Returns whether the unwinder may allocate while unwinding.
+    pub fn may_allocate(&self) -> bool {
+        matches!(self, AnyUnwinder::MayAllocate(_))
+    }
+
+    /// See [`Unwinder::modules`].
+    pub fn modules(&self) -> impl Iterator<Item = &ModuleInfo> {
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => unwinder.modules.iter(),
+            AnyUnwinder::MustNotAllocate(unwinder) => unwinder.modules.iter(),
+        }
+    }
+
+    /// See [`Unwinder::stats`].
+    pub fn stats(&self) -> UnwinderStats {
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => unwinder.stats(),
+            AnyUnwinder::MustNotAllocate(unwinder) => unwinder.stats(),
+        }
+    }
+
+    /// See [`Unwinder::refresh`].
+    pub fn refresh(&mut self) {
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => unwinder.refresh(),
+            AnyUnwinder::MustNotAllocate(unwinder) => unwinder.refresh(),
+        }
+    }
+
+    /// See [`Unwinder::set_terminators`].
+    pub fn set_terminators(&mut self, names: &[&str]) {
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => unwinder.set_terminators(names),
+            AnyUnwinder::MustNotAllocate(unwinder) => unwinder.set_terminators(names),
+        }
+    }
+
+    /// See [`Unwinder::set_opaque_objects`].
+    pub fn set_opaque_objects(&mut self, patterns: &[&str]) {
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => unwinder.set_opaque_objects(patterns),
+            AnyUnwinder::MustNotAllocate(unwinder) => unwinder.set_opaque_objects(patterns),
+        }
+    }
+
+    /// See [`Unwinder::set_opaque_modules`].
+    pub fn set_opaque_modules(&mut self, base_avmas: &[u64]) {
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => unwinder.set_opaque_modules(base_avmas),
+            AnyUnwinder::MustNotAllocate(unwinder) => unwinder.set_opaque_modules(base_avmas),
+        }
+    }
+
+    /// See [`Unwinder::fill_backtrace_cached`]. Suitable for signal handlers
+    /// only if the unwinder doesn't allocate.
+    #[inline(never)]
+    pub fn fill_backtrace_cached(&self, out: &mut [usize]) -> usize {
+        let (pc, regs) = current_regs();
+        match self {
+            AnyUnwinder::MayAllocate(unwinder) => Unwinder::with_thread_cache(|cache| {
+                unwinder.fill_backtrace_with_regs(pc, regs, cache, out)
+            }),
+            AnyUnwinder::MustNotAllocate(unwinder) => Unwinder::with_thread_cache(|cache| {
+                unwinder.fill_backtrace_with_regs(pc, regs, cache, out)
+            }),
+        }
+        .unwrap_or(0)
+    }
+}
+
+impl From<Unwinder<MayAllocateDuringUnwind>> for AnyUnwinder {
+    fn from(unwinder: Unwinder<MayAllocateDuringUnwind>) -> Self {
+        AnyUnwinder::MayAllocate(unwinder)
+    }
+}
+
+impl From<Unwinder<MustNotAllocateDuringUnwind>> for AnyUnwinder {
+    fn from(unwinder: Unwinder<MustNotAllocateDuringUnwind>) -> Self {
+        AnyUnwinder::MustNotAllocate(unwinder)
+    }
+}
+
+/// Reads the registers of the frame this function is inlined into.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub(crate) fn current_regs() -> (u64, UnwindRegsNative) {
+    let (ip, sp, bp): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "lea {ip}, [rip]",
+            "mov {sp}, rsp",
+            "mov {bp}, rbp",
+            ip = out(reg) ip,
+            sp = out(reg) sp,
+            bp = out(reg) bp,
+        );
+    }
+    (ip, UnwindRegsNative::new(ip, sp, bp))
+}
+
+/// Reads the registers of the frame this function is inlined into.
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub(crate) fn current_regs() -> (u64, UnwindRegsNative) {
+    let (lr, sp, fp, pc): (u64, u64, u64, u64);
+    unsafe {
+        asm!(
+            "mov {lr}, x30",
+            "mov {sp}, sp",
+            "mov {fp}, x29",
+            "adr {pc}, .",
+            lr = out(reg) lr,
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+            pc = out(reg) pc,
+        );
+    }
+    (pc, UnwindRegsNative::new(lr, sp, fp))
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn regs_from_ucontext(ucontext: &libc::ucontext_t) -> (u64, UnwindRegsNative) {
+    let gregs = &ucontext.uc_mcontext.gregs;
+    let ip = gregs[libc::REG_RIP as usize] as u64;
+    let sp = gregs[libc::REG_RSP as usize] as u64;
+    let bp = gregs[libc::REG_RBP as usize] as u64;
+    (ip, UnwindRegsNative::new(ip, sp, bp))
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+fn regs_from_ucontext(ucontext: &libc::ucontext_t) -> (u64, UnwindRegsNative) {
+    let mcontext = &ucontext.uc_mcontext;
+    let (fp, lr) = (mcontext.regs[29], mcontext.regs[30]);
+    (mcontext.pc, UnwindRegsNative::new(lr, mcontext.sp, fp))
+}
+
+#[cfg(all(target_os = "freebsd", target_arch = "x86_64"))]
+fn regs_from_ucontext(ucontext: &libc::ucontext_t) -> (u64, UnwindRegsNative) {
+    let mcontext = &ucontext.uc_mcontext;
+    let ip = mcontext.mc_rip as u64;
+    let sp = mcontext.mc_rsp as u64;
+    let bp = mcontext.mc_rbp as u64;
+    (ip, UnwindRegsNative::new(ip, sp, bp))
+}
+
+#[cfg(all(target_os = "freebsd", target_arch = "aarch64"))]
+fn regs_from_ucontext(ucontext: &libc::ucontext_t) -> (u64, UnwindRegsNative) {
+    let gpregs = &ucontext.uc_mcontext.mc_gpregs;
+    let (fp, lr, sp) = (gpregs.gp_x[29], gpregs.gp_lr, gpregs.gp_sp);
+    (
+        gpregs.gp_elr as u64,
+        UnwindRegsNative::new(lr as u64, sp as u64, fp as u64),
+    )
+}
+
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+fn regs_from_ucontext(ucontext: &libc::ucontext_t) -> (u64, UnwindRegsNative) {
+    let ss = unsafe { &(*ucontext.uc_mcontext).__ss };
+    (
+        ss.__rip,
+        UnwindRegsNative::new(ss.__rip, ss.__rsp, ss.__rbp),
+    )
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn regs_from_ucontext(ucontext: &libc::ucontext_t) -> (u64, UnwindRegsNative) {
+    let ss = unsafe { &(*ucontext.uc_mcontext).__ss };
+    (ss.__pc, UnwindRegsNative::new(ss.__lr, ss.__sp, ss.__fp))
+}
+
+#[cfg(target_arch = "x86_64")]
+fn frame_pointer(regs: &UnwindRegsNative) -> u64 {
+    regs.bp()
+}
+
+#[cfg(target_arch = "aarch64")]
+fn frame_pointer(regs: &UnwindRegsNative) -> u64 {
+    regs.fp()
+}
+
 impl<P> Default for Unwinder<P>
 where
     P: AllocationPolicy<&'static [u8]>,
@@ -116,39 +975,1598 @@ where
     }
 }
 
-pub struct UnwindIterator<'u, 'c, P>
+/// The number of frames checked for a cycle by [`UnwindIterator`]. A corrupt
+/// stack usually loops through only one or two frames.
+const RECENT_FRAMES: usize = 4;
+
+pub struct UnwindIterator<'u, 'c, P, M = LocalMemory>
 where
     P: AllocationPolicy<&'static [u8]>,
+    M: StackMemory,
 {
     unwinder: &'u UnwinderNative<&'static [u8], P>,
     cache: &'c mut CacheNative<&'static [u8], P>,
     regs: UnwindRegsNative,
     addr: FrameAddress,
+    max_depth: Option<usize>,
+    count: usize,
+    /// The addresses and stack pointers of the last frames, to detect cycles.
+    recent: [(u64, u64); RECENT_FRAMES],
+    /// Whether the stack pointer must increase from frame to frame.
+    check_sp: bool,
+    stack_bounds: Option<Range<u64>>,
+    memory: M,
+    terminators: &'u [Range<u64>],
+    stubs: &'u [Range<u64>],
+    /// Where the signal return trampoline may be, see [`SIGRETURN_OBJECTS`].
+    sigreturn: &'u [Range<u64>],
+    fixed_frames: &'u [(Range<u64>, u64)],
+    lazy_modules: &'u [LazyModule<P>],
+    /// The stack pointer up to which the leading frames are skipped, see
+    /// [`UnwindIterator::skip_frames_below`].
+    skip_below: Option<u64>,
+    /// The frames to skip, see [`Unwinder::set_opaque_objects`].
+    opaque: &'u [Range<u64>],
+    /// The frame to return next, see [`UnwindIterator::switch_stack`].
+    switched_to: Option<FrameAddress>,
 }
 
-impl<'u, 'c, P> UnwindIterator<'u, 'c, P>
+impl<'u, 'c, P, M> UnwindIterator<'u, 'c, P, M>
 where
     P: AllocationPolicy<&'static [u8]>,
+    M: StackMemory,
 {
-    pub fn try_next(&mut self) -> Result<Option<usize>, Error> {
-        if let Some(new_addr) = self
-            .unwinder
-            .unwind_frame(self.addr, &mut self.regs, self.cache, &mut read_stack)?
-            .and_then(NonZeroU64::new)
+    /// Limits the number of frames yielded by this iterator. Once `max_depth`
+    /// frames have been returned, `try_next` returns `Ok(None)`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Rejects stack reads outside of `bounds`, so that a corrupt frame can't
+    /// send the unwinder into unrelated mappings such as the heap.
+    ///
+    /// The bounds of the current thread's stack can be obtained with
+    /// [`current_thread_stack`](crate::current_thread_stack).
+    pub fn with_stack_bounds(mut self, bounds: Range<usize>) -> Self {
+        self.stack_bounds = Some(bounds.start as u64..bounds.end as u64);
+        self
+    }
+
+    /// Allows the stack pointer to move down from a frame to its caller,
+    /// which is otherwise reported as
+    /// [`UnwindError::StackPointerNotAdvancing`]. This is needed for stacks
+    /// that continue on another stack, e.g. coroutines or green threads whose
+    /// stacks are allocated below the stack of the thread running them.
+    ///
+    /// Signal frames may always switch stacks, e.g. from an alternate signal
+    /// stack.
+    pub fn allow_stack_switches(mut self) -> Self {
+        self.check_sp = false;
+        self
+    }
+
+    /// Skips the leading frames whose stack pointer is at or below `sp`: the
+    /// frames of the function whose stack `sp` points into and of its callees.
+    /// The first frame returned is then the return address into the caller of
+    /// that function, however the functions between it and where the registers
+    /// were read are inlined.
+    ///
+    /// `sp` may be the address of a local variable of a function that is never
+    /// inlined, e.g. the entry point of a logging or profiling library that
+    /// unwinds in its callees.
+    pub fn skip_frames_below(mut self, sp: usize) -> Self {
+        self.skip_below = Some(sp as u64);
+        self
+    }
+
+    /// Continues with the frames of another stack, e.g. once `try_next` returns
+    /// `Ok(None)` at the entry function of a coroutine made a
+    /// [terminator](Unwinder::set_terminators). `addr` and `regs` are the
+    /// context saved when switching to the stack that ended, usually in the
+    /// function that resumed the coroutine. `addr` is returned next, then its
+    /// callers.
+    ///
+    /// The stack bounds are dropped, since they were those of the previous
+    /// stack.
+    pub fn switch_stack(&mut self, addr: FrameAddress, regs: UnwindRegsNative) {
+        self.switched_to = Some(addr);
+        self.regs = regs;
+        self.stack_bounds = None;
+        self.recent = [(0, 0); RECENT_FRAMES];
+    }
+
+    /// Reads the stack through `memory` instead of probing the current
+    /// process's memory with [`LocalMemory`]. `memory` may be a closure
+    /// returning the word at an address, see [`StackMemory`].
+    pub fn with_memory<M2: StackMemory>(self, memory: M2) -> UnwindIterator<'u, 'c, P, M2> {
+        UnwindIterator {
+            unwinder: self.unwinder,
+            cache: self.cache,
+            regs: self.regs,
+            addr: self.addr,
+            max_depth: self.max_depth,
+            count: self.count,
+            recent: self.recent,
+            check_sp: self.check_sp,
+            stack_bounds: self.stack_bounds,
+            memory,
+            terminators: self.terminators,
+            stubs: self.stubs,
+            sigreturn: self.sigreturn,
+            fixed_frames: self.fixed_frames,
+            lazy_modules: self.lazy_modules,
+            skip_below: self.skip_below,
+            opaque: self.opaque,
+            switched_to: self.switched_to,
+        }
+    }
+
+    /// Returns the next return address.
+    ///
+    /// `Ok(None)` is the normal end of the stack: the previous frame was in one
+    /// of the [terminators](Unwinder::set_terminators), the unwinder found a
+    /// return address of 0 or 1, or `max_depth` frames were returned. An
+    /// unreadable stack word, usually left by a corrupt frame or a frame without
+    /// unwind information, is reported as [`UnwindError::StackReadFailed`] or
+    /// [`UnwindError::StackOutOfBounds`] instead, and a frame that unwinds to
+    /// one of the last frames again as [`UnwindError::Cycle`].
+    pub fn try_next(&mut self) -> Result<Option<usize>, UnwindError> {
+        Ok(self.try_next_address()?.map(|addr| addr.address() as usize))
+    }
+
+    /// Like [`UnwindIterator::try_next`], but also returns the stack pointer of
+    /// the frame the return address is in, right after returning to it. The
+    /// difference between the stack pointers of two consecutive frames is the
+    /// stack used by the frame between them.
+    pub fn try_next_with_sp(&mut self) -> Result<Option<(usize, usize)>, UnwindError> {
+        Ok(self.try_next()?.map(|addr| (addr, self.regs.sp() as usize)))
+    }
+
+    /// Like [`UnwindIterator::try_next`], but tells whether the address is
+    /// that of an interrupted instruction or a return address, which must be
+    /// symbolicated differently. See [`Frame::address_for_lookup`].
+    pub fn try_next_frame(&mut self) -> Result<Option<Frame>, UnwindError> {
+        Ok(self.try_next_address()?.map(Frame::from))
+    }
+
+    /// Like [`UnwindIterator::try_next`], but tells whether the address is an
+    /// instruction pointer or a return address.
+    ///
+    /// A return address points after the call instruction, so symbolicating it
+    /// as is may attribute the frame to the wrong line or even the wrong inlined
+    /// function. Use [`FrameAddress::address_for_lookup`] instead.
+    pub fn try_next_address(&mut self) -> Result<Option<FrameAddress>, UnwindError> {
+        if self
+            .max_depth
+            .is_some_and(|max_depth| self.count >= max_depth)
         {
-            self.addr = FrameAddress::ReturnAddress(new_addr);
-            Ok(Some(new_addr.get() as usize))
+            return Ok(None);
+        }
+        if let Some(addr) = self.switched_to.take() {
+            self.addr = addr;
+            self.count += 1;
+            return Ok(Some(addr));
+        }
+        let mut addr = self.unwind_frame()?;
+        while let Some(skipped) = addr.filter(|addr| {
+            let lookup_addr = addr.address_for_lookup();
+            self.skip_below.is_some_and(|sp| self.regs.sp() <= sp)
+                || self.opaque.iter().any(|avma| avma.contains(&lookup_addr))
+        }) {
+            self.addr = skipped;
+            addr = self.unwind_frame()?;
+        }
+        // Only the leading frames are skipped, since the stack may switch to
+        // one below later.
+        self.skip_below = None;
+        if let Some(addr) = addr {
+            self.addr = addr;
+            self.count += 1;
+        }
+        Ok(addr)
+    }
+
+    /// Unwinds the current frame and returns the address in its caller.
+    fn unwind_frame(&mut self) -> Result<Option<FrameAddress>, UnwindError> {
+        let lookup_addr = self.addr.address_for_lookup();
+        if self
+            .terminators
+            .iter()
+            .any(|avma| avma.contains(&lookup_addr))
+        {
+            return Ok(None);
+        }
+        let sp = self.regs.sp();
+        // Reading the code to recognize the signal return trampoline is only
+        // worth it in the objects that hold it, or once the frame can't be
+        // unwound as a call, e.g. for the `sa_restorer` of another object.
+        let in_sigreturn_object = self
+            .sigreturn
+            .iter()
+            .any(|avma| avma.contains(&lookup_addr));
+        let mut signal_pc = None;
+        if in_sigreturn_object {
+            signal_pc = self.unwind_signal_frame()?;
+        }
+        let mut return_addr = None;
+        if signal_pc.is_none() {
+            let regs = self.regs;
+            match self.unwind_call() {
+                Ok(addr) => return_addr = addr,
+                Err(e) if in_sigreturn_object => return Err(e),
+                Err(e) => {
+                    self.regs = regs;
+                    signal_pc = Some(self.unwind_signal_frame()?.ok_or(e)?);
+                }
+            }
+        }
+        let (new_addr, check_sp) = match signal_pc {
+            // The frame interrupted by the signal, at the faulting or next
+            // instruction rather than after a call.
+            Some(pc) => (
+                (pc != 0).then_some(FrameAddress::InstructionPointer(pc)),
+                false,
+            ),
+            None => (
+                return_addr
+                    .filter(|addr| *addr > 1)
+                    .and_then(NonZeroU64::new)
+                    .map(FrameAddress::ReturnAddress),
+                self.check_sp,
+            ),
+        };
+        if let Some(new_addr) = new_addr {
+            let frame = (new_addr.address(), self.regs.sp());
+            if self.recent.contains(&frame) {
+                return Err(UnwindError::Cycle(frame.0));
+            }
+            // Returning pops at least the return address, except from a leaf
+            // function on aarch64, whose return address is in the link
+            // register, and which can only be the innermost frame.
+            let new_sp = self.regs.sp();
+            if check_sp && (new_sp < sp || new_sp == sp && self.addr.is_return_address()) {
+                return Err(UnwindError::StackPointerNotAdvancing(new_sp));
+            }
+            self.recent.rotate_right(1);
+            self.recent[0] = frame;
+            Ok(Some(new_addr))
         } else {
             Ok(None)
         }
     }
+
+    /// Unwinds a frame that made a call and returns the return address.
+    fn unwind_call(&mut self) -> Result<Option<u64>, UnwindError> {
+        let lookup_addr = self.addr.address_for_lookup();
+        if self.stubs.iter().any(|avma| avma.contains(&lookup_addr)) {
+            Ok(Some(self.unwind_stub()?))
+        } else if let Some((_, size)) = self
+            .fixed_frames
+            .iter()
+            .find(|(avma, _)| avma.contains(&lookup_addr))
+        {
+            Ok(Some(self.pop_frame(*size)?))
+        } else {
+            let unwinder = self
+                .lazy_modules
+                .iter()
+                .find(|module| module.avma_range.contains(&lookup_addr))
+                .map_or(self.unwinder, LazyModule::unwinder);
+            // framehop only reports the address of a failed read, so remember why
+            // it failed.
+            let mut read_error = None;
+            unwinder
+                .unwind_frame(self.addr, &mut self.regs, self.cache, &mut |addr| {
+                    read_stack(&mut self.memory, &self.stack_bounds, addr)
+                        .map_err(|e| read_error = Some(e))
+                })
+                .map_err(|e| match (e, read_error) {
+                    (Error::CouldNotReadStack(_), Some(read_error)) => read_error,
+                    (e, _) => UnwindError::Framehop(e),
+                })
+        }
+    }
+
+    /// If the frame address is the `rt_sigreturn` trampoline that a signal
+    /// handler returns to, restores the registers the kernel saved in the
+    /// signal frame and returns the interrupted instruction pointer.
+    ///
+    /// The trampoline (`__restore_rt` in libc on x86_64, `__kernel_rt_sigreturn`
+    /// in the vDSO on aarch64) is recognized by its code, since its symbol is
+    /// usually stripped and its CFI can't be followed by framehop.
+    #[cfg(target_os = "linux")]
+    fn unwind_signal_frame(&mut self) -> Result<Option<u64>, UnwindError> {
+        if !code_matches(&mut self.memory, self.addr.address(), SIGRETURN_CODE) {
+            return Ok(None);
+        }
+        let mut regs = [0; SIGNAL_FRAME_REGS.len()];
+        for (reg, offset) in regs.iter_mut().zip(SIGNAL_FRAME_REGS) {
+            let addr = self.regs.sp() + offset as u64;
+            *reg = read_stack(&mut self.memory, &self.stack_bounds, addr)?;
+        }
+        let (pc, regs) = regs_from_signal_frame(regs);
+        self.regs = regs;
+        Ok(Some(pc))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn unwind_signal_frame(&mut self) -> Result<Option<u64>, UnwindError> {
+        Ok(None)
+    }
+
+    /// Unwinds a PLT stub, which jumps to its target with the return address
+    /// still at the top of the stack.
+    #[cfg(target_arch = "x86_64")]
+    fn unwind_stub(&mut self) -> Result<u64, UnwindError> {
+        self.pop_frame(0)
+    }
+
+    /// Unwinds a PLT stub, which jumps to its target with the return address
+    /// still in the link register.
+    #[cfg(target_arch = "aarch64")]
+    fn unwind_stub(&mut self) -> Result<u64, UnwindError> {
+        Ok(self.regs.lr())
+    }
+
+    /// Pops a frame of `size` bytes followed by the return address, see
+    /// [`JitUnwindInfo::FixedFrame`].
+    fn pop_frame(&mut self, size: u64) -> Result<u64, UnwindError> {
+        let addr = self.regs.sp().wrapping_add(size);
+        let return_addr = read_stack(&mut self.memory, &self.stack_bounds, addr)?;
+        self.regs.set_sp(addr.wrapping_add(8));
+        Ok(return_addr)
+    }
+
+    /// Like [`UnwindIterator::try_next`], but also looks up the object that
+    /// contains the address. This allocates and takes a lock, so it must not be
+    /// used in signal handlers.
+    pub fn try_next_resolved(&mut self) -> Result<Option<ResolvedFrame>, UnwindError> {
+        Ok(self.try_next_address()?.map(ResolvedFrame::resolve))
+    }
+
+    /// Like [`UnwindIterator::try_next_address`], but also returns the state of
+    /// the unwinder, to diagnose a wrong backtrace.
+    #[cfg(feature = "debug-frames")]
+    pub fn try_next_debug(&mut self) -> Result<Option<DebugFrame>, UnwindError> {
+        let previous = self.addr;
+        Ok(self.try_next_address()?.map(|address| DebugFrame {
+            previous,
+            // Returning pops the frame of the callee, so the stack pointer of
+            // the caller is the callee's CFA on both x86_64 and aarch64.
+            cfa: self.regs.sp(),
+            address,
+            regs: self.regs,
+        }))
+    }
+
+    /// Turns this into an iterator that returns one frame per function,
+    /// including the functions inlined at each return address.
+    #[cfg(feature = "symbolize")]
+    pub fn inlined(self) -> crate::InlinedFrameIterator<'u, 'c, P, M> {
+        crate::symbolize::InlinedFrameIterator::new(self)
+    }
+}
+
+/// `mov rax, 15` (`SYS_rt_sigreturn`), `syscall`
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SIGRETURN_CODE: &[u8] = &[0x48, 0xc7, 0xc0, 0x0f, 0x00, 0x00, 0x00, 0x0f, 0x05];
+
+/// `mov x8, #139` (`SYS_rt_sigreturn`), `svc #0`
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const SIGRETURN_CODE: &[u8] = &[0x68, 0x11, 0x80, 0xd2, 0x01, 0x00, 0x00, 0xd4];
+
+/// The offsets of the saved instruction pointer, stack pointer and frame
+/// pointer from the stack pointer in the trampoline. The handler has returned
+/// to it by popping the return address, so the stack pointer is at the
+/// `ucontext_t` of the signal frame.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const SIGNAL_FRAME_REGS: [usize; 3] = {
+    use std::mem::offset_of;
+    let gregs = offset_of!(libc::ucontext_t, uc_mcontext) + offset_of!(libc::mcontext_t, gregs);
+    [
+        gregs + 8 * libc::REG_RIP as usize,
+        gregs + 8 * libc::REG_RSP as usize,
+        gregs + 8 * libc::REG_RBP as usize,
+    ]
+};
+
+/// The offsets of the saved program counter, link register, stack pointer and
+/// frame pointer from the stack pointer in the trampoline, where the signal
+/// frame starts with a `siginfo_t` followed by the `ucontext_t`.
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const SIGNAL_FRAME_REGS: [usize; 4] = {
+    use std::mem::{offset_of, size_of};
+    let mcontext = size_of::<libc::siginfo_t>() + offset_of!(libc::ucontext_t, uc_mcontext);
+    let regs = mcontext + offset_of!(libc::mcontext_t, regs);
+    [
+        mcontext + offset_of!(libc::mcontext_t, pc),
+        regs + 8 * 30,
+        mcontext + offset_of!(libc::mcontext_t, sp),
+        regs + 8 * 29,
+    ]
+};
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn regs_from_signal_frame([ip, sp, bp]: [u64; 3]) -> (u64, UnwindRegsNative) {
+    (ip, UnwindRegsNative::new(ip, sp, bp))
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+fn regs_from_signal_frame([pc, lr, sp, fp]: [u64; 4]) -> (u64, UnwindRegsNative) {
+    (pc, UnwindRegsNative::new(lr, sp, fp))
+}
+
+/// Checks whether the code at `addr` is `expected`, which is at most 16 bytes.
+#[cfg(target_os = "linux")]
+fn code_matches<M: StackMemory>(memory: &mut M, addr: u64, expected: &[u8]) -> bool {
+    let start = addr & !0b111;
+    let offset = (addr - start) as usize;
+    let mut code = [0u8; 24];
+    for (i, word) in code
+        .chunks_exact_mut(8)
+        .take((offset + expected.len()).div_ceil(8))
+        .enumerate()
+    {
+        match memory.read_u64(start + 8 * i as u64) {
+            Ok(value) => word.copy_from_slice(&value.to_ne_bytes()),
+            Err(()) => return false,
+        }
+    }
+    code[offset..offset + expected.len()] == *expected
+}
+
+/// Matches `text` against `pattern`, in which `*` matches any characters.
+///
+/// On a mismatch, only the last `*` is retried with one more character, since
+/// the earlier ones can only match more of the text if it can too. This takes
+/// `O(pattern.len() * text.len())` steps at most.
+fn matches_pattern(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The pattern after the last `*` and where its match in the text starts.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, t));
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after_star, start)) => {
+                    p = after_star;
+                    t = start + 1;
+                    star = Some((after_star, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Reads the stack word at `addr` if it is within `bounds`.
+fn read_stack<M: StackMemory>(
+    memory: &mut M,
+    bounds: &Option<Range<u64>>,
+    addr: u64,
+) -> Result<u64, UnwindError> {
+    if let Some(bounds) = bounds {
+        let aligned_addr = addr & !0b111;
+        if aligned_addr < bounds.start || aligned_addr + 8 > bounds.end {
+            return Err(UnwindError::StackOutOfBounds(addr));
+        }
+    }
+    memory
+        .read_u64(addr)
+        .map_err(|()| UnwindError::StackReadFailed(addr))
 }
 
-fn read_stack(addr: u64) -> Result<u64, ()> {
-    let aligned_addr = addr & !0b111;
-    if crate::addr_validate::validate(aligned_addr as _) {
-        Ok(unsafe { (aligned_addr as *const u64).read() })
-    } else {
-        Err(())
+/// A frame with the registers recovered for it. See
+/// [`UnwindIterator::try_next_debug`].
+#[cfg(feature = "debug-frames")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugFrame {
+    /// The address of the frame that was unwound to find this one.
+    pub previous: FrameAddress,
+    /// The canonical frame address of the previous frame.
+    pub cfa: u64,
+    pub address: FrameAddress,
+    /// The registers of this frame, including its stack pointer and frame
+    /// pointer.
+    pub regs: UnwindRegsNative,
+}
+
+/// A frame address that tells how to symbolicate it, as a native-width
+/// wrapper over [`FrameAddress`]. See [`UnwindIterator::try_next_frame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Frame {
+    /// The instruction a frame was at when it was interrupted, e.g. by a
+    /// signal. It belongs to the function it points to.
+    Leaf(usize),
+    /// The address a call returns to, right after the call instruction. It
+    /// may point into the next line, inlined function or function.
+    Return(usize),
+}
+
+impl Frame {
+    /// Returns the address, as returned by [`UnwindIterator::try_next`].
+    pub fn address(&self) -> usize {
+        match *self {
+            Frame::Leaf(addr) | Frame::Return(addr) => addr,
+        }
+    }
+
+    /// Returns the address to symbolicate: the address of a leaf frame, or
+    /// the return address minus one, which is within the call instruction.
+    pub fn address_for_lookup(&self) -> usize {
+        match *self {
+            Frame::Leaf(addr) => addr,
+            Frame::Return(addr) => addr - 1,
+        }
+    }
+}
+
+impl From<FrameAddress> for Frame {
+    fn from(addr: FrameAddress) -> Self {
+        match addr {
+            FrameAddress::InstructionPointer(addr) => Frame::Leaf(addr as usize),
+            FrameAddress::ReturnAddress(addr) => Frame::Return(addr.get() as usize),
+        }
+    }
+}
+
+/// A frame address resolved to the object containing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedFrame {
+    /// The absolute address in the process.
+    pub avma: usize,
+    /// The file name of the object containing the address, e.g. `libc.so.6`.
+    pub module_name: Option<String>,
+    /// The address relative to the object's load bias, suitable for looking up
+    /// symbols in the object file.
+    pub svma: Option<usize>,
+}
+
+impl ResolvedFrame {
+    fn resolve(addr: FrameAddress) -> Self {
+        let avma = addr.address() as usize;
+        // Return addresses may point right past the end of the calling function.
+        match crate::object::find_object_for_addr(addr.address_for_lookup() as usize) {
+            Some(obj) => ResolvedFrame {
+                avma,
+                module_name: obj.name().map(String::from),
+                svma: Some(avma - obj.base_addr()),
+            },
+            None => ResolvedFrame {
+                avma,
+                module_name: None,
+                svma: None,
+            },
+        }
+    }
+}
+
+/// An iterator over the return addresses found by following the frame pointer
+/// chain. See [`Unwinder::iter_frames_fp`].
+pub struct FramePointerIterator {
+    fp: u64,
+}
+
+impl Iterator for FramePointerIterator {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        // On both x86_64 and aarch64, the frame pointer points to the saved frame
+        // pointer of the caller, followed by the return address.
+        if self.fp == 0 || !self.fp.is_multiple_of(8) {
+            return None;
+        }
+        let return_addr = LocalMemory.read_u64(self.fp + 8).ok()?;
+        let next_fp = LocalMemory.read_u64(self.fp).ok()?;
+        if return_addr == 0 {
+            return None;
+        }
+        // The stack grows downwards, so the caller's frame must be above this one.
+        self.fp = if next_fp > self.fp { next_fp } else { 0 };
+        Some(return_addr as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MayAllocateDuringUnwind, MustNotAllocateDuringUnwind};
+
+    #[test]
+    fn backtrace() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let frames = unwinder.backtrace(&mut cache);
+        assert!(frames.len() > 1);
+
+        let mut iter = unwinder.iter_frames(&mut cache);
+        let mut expected = Vec::new();
+        while let Ok(Some(addr)) = iter.try_next() {
+            expected.push(addr);
+        }
+        // Both are captured in this function, so the callers above it are the same.
+        assert_eq!(frames[1..], expected[expected.len() - frames.len() + 1..]);
+    }
+
+    #[test]
+    #[inline(never)]
+    fn iter_frames_starts_at_caller() {
+        // The first frame must be this function, not the test harness.
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache);
+        let mut frames = Vec::new();
+        while let Ok(Some(addr)) = iter.try_next() {
+            frames.push(addr);
+        }
+        let expected = unwinder.backtrace(&mut cache);
+        let this_fn = iter_frames_starts_at_caller as *const () as usize;
+        assert!(this_fn < frames[0]);
+        assert_eq!(frames.len(), expected.len());
+        assert_eq!(frames[1..], expected[1..]);
+    }
+
+    #[test]
+    fn capture() {
+        let unwinder = Unwinder::new();
+        let mut cache = CacheNative::new();
+        let frames = unwinder.capture();
+        let expected = unwinder.backtrace(&mut cache);
+        assert_eq!(frames[1..], expected[1..]);
+    }
+
+    #[test]
+    fn skip_frames_below() {
+        type Frames = Vec<usize>;
+
+        fn collect(mut iter: UnwindIterator<'_, '_, MayAllocateDuringUnwind>) -> Frames {
+            let mut frames = Vec::new();
+            while let Ok(Some(addr)) = iter.try_next() {
+                frames.push(addr);
+            }
+            frames
+        }
+
+        /// Unwinds from its own frame, then from the frame of a callee, skipping
+        /// the frames below its own.
+        #[inline(never)]
+        fn entry(unwinder: &Unwinder) -> (Frames, Frames) {
+            let (pc, regs) = current_regs();
+            let expected =
+                collect(unwinder.iter_frames_with_regs(pc as usize, regs, &mut CacheNative::new()));
+            let skipped = std::hint::black_box(callee(unwinder, regs.sp() as usize));
+            (expected, skipped)
+        }
+
+        #[inline(never)]
+        fn callee(unwinder: &Unwinder, entry_sp: usize) -> Frames {
+            Unwinder::with_thread_cache(|cache| {
+                let (pc, regs) = current_regs();
+                collect(
+                    unwinder
+                        .iter_frames_with_regs(pc as usize, regs, cache)
+                        .skip_frames_below(entry_sp),
+                )
+            })
+            .unwrap()
+        }
+
+        let unwinder = Unwinder::new();
+        let (expected, skipped) = entry(&unwinder);
+        assert!(expected.len() > 1);
+        assert_eq!(skipped, expected);
+    }
+
+    #[test]
+    fn frame_kind() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache);
+        let frame = iter.try_next_address().unwrap().unwrap();
+        assert!(matches!(frame, FrameAddress::ReturnAddress(_)));
+        assert_eq!(frame.address_for_lookup(), frame.address() - 1);
+
+        let frame = FrameAddress::InstructionPointer(0x1000);
+        assert_eq!(frame.address_for_lookup(), 0x1000);
+
+        let frame = iter.try_next_frame().unwrap().unwrap();
+        assert!(matches!(frame, Frame::Return(_)));
+        assert_eq!(frame.address_for_lookup(), frame.address() - 1);
+
+        let frame = Frame::from(FrameAddress::InstructionPointer(0x1000));
+        assert_eq!(frame, Frame::Leaf(0x1000));
+        assert_eq!(frame.address_for_lookup(), 0x1000);
+    }
+
+    #[test]
+    fn capture_serializable() {
+        let unwinder = Unwinder::new();
+        let backtrace = unwinder.capture_serializable();
+        let exe = std::env::current_exe().unwrap().canonicalize().unwrap();
+        let module = backtrace
+            .modules
+            .iter()
+            .find(|module| module.path.canonicalize().ok() == Some(exe.clone()))
+            .unwrap();
+        // The first frame is in this test function.
+        let this_fn = capture_serializable as *const () as u64;
+        assert!(module.base_addr <= this_fn && this_fn < backtrace.frames[0]);
+        assert_eq!(
+            backtrace.modules.len(),
+            backtrace
+                .modules
+                .iter()
+                .map(|module| module.base_addr)
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        );
+    }
+
+    #[test]
+    fn fill_backtrace() {
+        let unwinder = Unwinder::<MustNotAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let expected = unwinder.backtrace(&mut cache);
+
+        let mut frames = [0; 3];
+        assert_eq!(unwinder.fill_backtrace(&mut cache, &mut frames), 3);
+        assert_eq!(frames[1..], expected[1..3]);
+
+        let mut frames = [0; 1024];
+        let len = unwinder.fill_backtrace(&mut cache, &mut frames);
+        assert_eq!(frames[1..len], expected[1..]);
+    }
+
+    #[test]
+    fn capture_into() {
+        let unwinder = Unwinder::<MustNotAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let expected = unwinder.backtrace(&mut cache);
+
+        let backtrace = unwinder.capture_into::<1024>();
+        assert!(!backtrace.is_full());
+        assert_eq!(backtrace[1..], expected[1..]);
+
+        let backtrace = unwinder.capture_into::<3>();
+        assert!(backtrace.is_full());
+        assert_eq!(backtrace[1..], expected[1..3]);
+        assert_eq!(backtrace.into_iter().collect::<Vec<_>>(), *backtrace);
+        assert_eq!(Backtrace::<4>::new().len(), 0);
+    }
+
+    #[test]
+    fn thread_cache() {
+        let unwinder = Unwinder::<MustNotAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let expected = unwinder.backtrace(&mut cache);
+
+        let mut frames = [0; 1024];
+        let len = unwinder.fill_backtrace_cached(&mut frames);
+        assert_eq!(frames[1..len], expected[1..]);
+
+        // The cache can't be borrowed twice.
+        let nested = Unwinder::<MustNotAllocateDuringUnwind>::with_thread_cache(|_| {
+            Unwinder::<MustNotAllocateDuringUnwind>::with_thread_cache(|_| ())
+        });
+        assert_eq!(nested, Some(None));
+        assert_eq!(
+            Unwinder::<MustNotAllocateDuringUnwind>::with_thread_cache(|_| {
+                unwinder.fill_backtrace_cached(&mut frames)
+            }),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn any_unwinder() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let expected = unwinder.capture();
+
+        for may_allocate in [true, false] {
+            let unwinder = AnyUnwinder::new(may_allocate);
+            assert_eq!(unwinder.may_allocate(), may_allocate);
+            assert!(unwinder.modules().next().is_some());
+            let mut frames = [0; 1024];
+            let len = unwinder.fill_backtrace_cached(&mut frames);
+            assert_eq!(frames[1..len], expected[1..]);
+        }
+    }
+
+    #[test]
+    fn shared_objects() {
+        let objects = crate::get_objects();
+        let may_allocate = Unwinder::<MayAllocateDuringUnwind>::from_objects(&objects);
+        let must_not_allocate = Unwinder::<MustNotAllocateDuringUnwind>::from_objects(&objects);
+        assert!(may_allocate.modules().eq(must_not_allocate.modules()));
+        assert_eq!(may_allocate.modules().count(), objects.len());
+        assert!(must_not_allocate.terminator_avmas == may_allocate.terminator_avmas);
+
+        let mut frames = [0; 1024];
+        let len = must_not_allocate.fill_backtrace_cached(&mut frames);
+        assert_eq!(frames[1..len], may_allocate.capture()[1..]);
+
+        let mut empty = Unwinder::<MustNotAllocateDuringUnwind>::from_objects(&[]);
+        assert_eq!(empty.modules().count(), 0);
+        empty.refresh();
+        assert_eq!(empty.modules().count(), crate::get_objects().len());
+    }
+
+    #[test]
+    fn lazy() {
+        let eager = Unwinder::<MayAllocateDuringUnwind>::new();
+        let lazy = Unwinder::new_lazy();
+        assert_eq!(lazy.modules, eager.modules);
+        assert!(lazy.lazy_modules.iter().all(|m| m.unwinder.get().is_none()));
+
+        let (pc, regs) = current_regs();
+        let mut cache = CacheNative::new();
+        let mut eager_frames = [0; 64];
+        let len = eager.fill_backtrace_with_regs(pc, regs, &mut cache, &mut eager_frames);
+        let mut lazy_frames = [0; 64];
+        let lazy_len = lazy.fill_backtrace_with_regs(pc, regs, &mut cache, &mut lazy_frames);
+        assert_eq!(lazy_frames[..lazy_len], eager_frames[..len]);
+
+        // Only the modules on the stack were prepared, not e.g. the vDSO's.
+        let prepared = lazy
+            .lazy_modules
+            .iter()
+            .filter(|m| m.unwinder.get().is_some())
+            .count();
+        assert!(prepared > 0 && prepared < lazy.lazy_modules.len());
+    }
+
+    #[test]
+    fn warm_up() {
+        let unwinder = Unwinder::new_lazy();
+        let mut cache = CacheNative::new();
+        unwinder.warm_up(&mut cache);
+        assert!(unwinder
+            .lazy_modules
+            .iter()
+            .all(|m| m.unwinder.get().is_some()));
+        assert!(unwinder.backtrace(&mut cache).len() > 1);
+    }
+
+    #[test]
+    fn send_sync() {
+        fn send_sync<T: Send + Sync>() {}
+        send_sync::<Unwinder<MayAllocateDuringUnwind>>();
+        send_sync::<Unwinder<MustNotAllocateDuringUnwind>>();
+        send_sync::<AnyUnwinder>();
+        send_sync::<Object>();
+        send_sync::<CacheNative<&'static [u8], MayAllocateDuringUnwind>>();
+        send_sync::<CacheNative<&'static [u8], MustNotAllocateDuringUnwind>>();
+
+        let unwinder = Unwinder::<MustNotAllocateDuringUnwind>::new();
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mut cache = CacheNative::new();
+                    assert!(unwinder.backtrace(&mut cache).len() > 1);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn from_paths() {
+        let exe = std::env::current_exe().unwrap();
+        let discovered = Unwinder::<MayAllocateDuringUnwind>::new();
+        let module = discovered
+            .modules()
+            .find(|module| module.name == exe.to_string_lossy())
+            .unwrap();
+        let base = crate::get_objects()
+            .iter()
+            .find(|obj| obj.path() == exe)
+            .unwrap()
+            .base_addr();
+
+        let unwinder =
+            Unwinder::<MayAllocateDuringUnwind>::from_paths(std::slice::from_ref(&exe), &[base])
+                .unwrap();
+        assert!(unwinder.modules().eq([module]));
+        let mut cache = CacheNative::new();
+        let frames = unwinder.backtrace(&mut cache);
+        let expected = discovered.backtrace(&mut CacheNative::new());
+        assert_eq!(frames[1], expected[1]);
+
+        let missing = PathBuf::from("/nonexistent");
+        let Err((path, LoadError::Map(e))) =
+            Unwinder::<MayAllocateDuringUnwind>::from_paths(&[exe, missing.clone()], &[base, 0])
+        else {
+            panic!("loaded a missing file");
+        };
+        assert_eq!(path, missing);
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn from_paths_object_ranges() {
+        let libc = crate::get_objects()
+            .iter()
+            .find(|obj| obj.name().is_some_and(|name| name.starts_with("libc.so")))
+            .unwrap()
+            .path()
+            .to_path_buf();
+        // Away from the libc of this process.
+        const BASE: u64 = 0x1000_0000_0000;
+        let mut unwinder =
+            Unwinder::<MayAllocateDuringUnwind>::from_paths(&[libc], &[BASE as usize]).unwrap();
+        let text = unwinder.modules[0].avma_range.clone();
+        assert!(!unwinder.terminator_avmas.is_empty());
+        assert!(unwinder
+            .terminator_avmas
+            .iter()
+            .all(|avma| text.start <= avma.start && avma.end <= text.end));
+        let text = [text];
+        assert_eq!(unwinder.sigreturn_avmas, text);
+        let stats = unwinder.stats();
+        assert_eq!(stats.modules, 1);
+        assert!(stats.fde_count > 0);
+
+        unwinder.set_opaque_objects(&["libc.so*"]);
+        assert_eq!(unwinder.opaque_avmas, text);
+        unwinder.set_opaque_objects(&[]);
+        unwinder.set_opaque_modules(&[BASE]);
+        assert_eq!(unwinder.opaque_avmas, text);
+        unwinder.set_opaque_modules(&[BASE + 0x1000]);
+        assert!(unwinder.opaque_avmas.is_empty());
+    }
+
+    #[test]
+    fn frame_pointer_terminates() {
+        // Three frames of (saved frame pointer, return address), the outermost one
+        // ending the chain with a null frame pointer.
+        let mut stack = [0u64; 6];
+        let base = stack.as_ptr() as u64;
+        stack.copy_from_slice(&[base + 16, 0x1111, base + 32, 0x2222, 0, 0x3333]);
+        let frames: Vec<_> = FramePointerIterator { fp: base }.collect();
+        assert_eq!(frames, [0x1111, 0x2222, 0x3333]);
+
+        // A null return address ends the walk before its frame.
+        stack[3] = 0;
+        let frames: Vec<_> = FramePointerIterator { fp: base }.collect();
+        assert_eq!(frames, [0x1111]);
+
+        // So does a frame pointer that points down the stack.
+        stack.copy_from_slice(&[base + 16, 0x1111, base, 0x2222, base + 16, 0x3333]);
+        let frames: Vec<_> = FramePointerIterator { fp: base }.collect();
+        assert_eq!(frames, [0x1111, 0x2222]);
+    }
+
+    #[test]
+    fn unwind_from_ucontext() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use once_cell::sync::Lazy;
+
+        static UNWINDER: Lazy<Unwinder<MayAllocateDuringUnwind>> = Lazy::new(Unwinder::new);
+        static FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+        extern "C" fn handler(
+            _: libc::c_int,
+            _: *mut libc::siginfo_t,
+            ucontext: *mut libc::c_void,
+        ) {
+            let mut cache = CacheNative::new();
+            let mut iter = unsafe {
+                UNWINDER.iter_frames_from_ucontext(ucontext as *const libc::ucontext_t, &mut cache)
+            };
+            let mut frames = 0;
+            while let Ok(Some(_)) = iter.try_next() {
+                frames += 1;
+            }
+            FRAMES.store(frames, Ordering::SeqCst);
+        }
+
+        Lazy::force(&UNWINDER);
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+                0
+            );
+            libc::raise(libc::SIGUSR1);
+        }
+        // The interrupted context is inside `raise`, below this test function.
+        assert!(FRAMES.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn signal_frame() {
+        use std::sync::Mutex;
+
+        use once_cell::sync::Lazy;
+
+        static UNWINDER: Lazy<Unwinder<MayAllocateDuringUnwind>> = Lazy::new(Unwinder::new);
+        static FRAMES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+        extern "C" fn handler(_: libc::c_int) {
+            let mut cache = CacheNative::new();
+            let mut iter = UNWINDER.iter_frames(&mut cache);
+            let mut frames = Vec::new();
+            while let Ok(Some(addr)) = iter.try_next() {
+                frames.push(addr);
+            }
+            *FRAMES.lock().unwrap() = frames;
+        }
+
+        let expected = UNWINDER.capture();
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as *const () as usize;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()),
+                0
+            );
+            libc::raise(libc::SIGUSR2);
+        }
+        let frames = FRAMES.lock().unwrap().clone();
+        // Unwinding continues past the signal frame into this function's callers.
+        assert!(frames.ends_with(&expected[1..]));
+    }
+
+    #[test]
+    fn from_return_address() {
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        unwinder.terminator_avmas = std::iter::once(0x1000..0x2000).collect();
+        let (_, regs) = current_regs();
+        let mut cache = CacheNative::new();
+
+        // The call before the return address is in the terminator.
+        let addr = FrameAddress::from_return_address(0x2000).unwrap();
+        let mut iter = unwinder.iter_frames_from_address(addr, regs, &mut cache);
+        assert_eq!(iter.try_next_address(), Ok(None));
+
+        let iter = unwinder.iter_frames_with_regs(0x2000, regs, &mut cache);
+        assert_eq!(iter.addr, FrameAddress::InstructionPointer(0x2000));
+        assert!(!iter
+            .terminators
+            .iter()
+            .any(|avma| avma.contains(&iter.addr.address_for_lookup())));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn cycle() {
+        /// A signal frame that restores itself.
+        struct Cycle;
+        const CODE: u64 = 0x1000;
+        const SP: u64 = 0x10000;
+
+        impl StackMemory for Cycle {
+            fn read_u64(&mut self, addr: u64) -> Result<u64, ()> {
+                if (CODE..CODE + 16).contains(&addr) {
+                    let mut code = [0; 16];
+                    code[..SIGRETURN_CODE.len()].copy_from_slice(SIGRETURN_CODE);
+                    let offset = (addr - CODE) as usize;
+                    Ok(u64::from_ne_bytes(
+                        code[offset..offset + 8].try_into().unwrap(),
+                    ))
+                } else if addr == SP + SIGNAL_FRAME_REGS[0] as u64 {
+                    Ok(CODE)
+                } else {
+                    Ok(SP)
+                }
+            }
+        }
+
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let trampoline = CODE..CODE + 16;
+        unwinder.sigreturn_avmas = vec![trampoline];
+        let mut cache = CacheNative::new();
+        let (_, regs) = regs_from_signal_frame([SP; SIGNAL_FRAME_REGS.len()]);
+        let mut iter = unwinder
+            .iter_frames_with_regs(CODE as usize, regs, &mut cache)
+            .with_memory(Cycle);
+        assert_eq!(iter.try_next(), Ok(Some(CODE as usize)));
+        assert_eq!(iter.try_next(), Err(UnwindError::Cycle(CODE)));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sigreturn_code_not_read_elsewhere() {
+        const CODE: u64 = 0x1000;
+        const SP: u64 = 0x10000;
+
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let (_, regs) = regs_from_signal_frame([SP; SIGNAL_FRAME_REGS.len()]);
+        let code_read = std::cell::Cell::new(false);
+        let mut iter = unwinder
+            .iter_frames_with_regs(CODE as usize, regs, &mut cache)
+            .with_memory(|addr| {
+                code_read.set(code_read.get() || (CODE..CODE + 16).contains(&addr));
+                Ok(0x2000)
+            });
+        // Unwound with frame pointers, since no object contains the code.
+        assert!(iter.try_next().is_ok());
+        assert!(!code_read.get());
+    }
+
+    #[test]
+    fn sp_not_advancing() {
+        struct Memory;
+
+        impl StackMemory for Memory {
+            fn read_u64(&mut self, _addr: u64) -> Result<u64, ()> {
+                Ok(0x1800)
+            }
+        }
+
+        // A frame size that wraps around, so the caller's frame is below.
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        unwinder.fixed_frames = vec![(0x1000..0x2000, u64::MAX - 15)];
+        let regs = UnwindRegsNative::new(0x1800, 0x10000, 0x10000);
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder
+            .iter_frames_with_regs(0x1800, regs, &mut cache)
+            .with_memory(Memory);
+        assert_eq!(
+            iter.try_next(),
+            Err(UnwindError::StackPointerNotAdvancing(0xfff8))
+        );
+
+        let mut iter = unwinder
+            .iter_frames_with_regs(0x1800, regs, &mut cache)
+            .with_memory(Memory)
+            .allow_stack_switches();
+        assert_eq!(iter.try_next(), Ok(Some(0x1800)));
+    }
+
+    #[test]
+    fn suspended_stack() {
+        fn collect<M: StackMemory>(
+            mut iter: UnwindIterator<'_, '_, MayAllocateDuringUnwind, M>,
+        ) -> Vec<usize> {
+            let mut frames = Vec::new();
+            while let Ok(Some(addr)) = iter.try_next() {
+                frames.push(addr);
+            }
+            frames
+        }
+
+        // Saves the registers and the stack as a green thread runtime would
+        // when switching it out, along with the frames unwound in place.
+        #[inline(never)]
+        fn suspend(
+            unwinder: &Unwinder<MayAllocateDuringUnwind>,
+        ) -> (u64, UnwindRegsNative, u64, Vec<u64>, Vec<usize>) {
+            let (pc, regs) = current_regs();
+            // Also save the red zone below the stack pointer.
+            let start = regs.sp() - 128;
+            let end = crate::current_thread_stack().unwrap().end as u64;
+            let saved = (start..end)
+                .step_by(8)
+                .map(|addr| unsafe { std::ptr::read(addr as *const u64) })
+                .collect();
+            let mut cache = CacheNative::new();
+            let frames = collect(unwinder.iter_frames_with_regs(pc as usize, regs, &mut cache));
+            (pc, regs, start, saved, frames)
+        }
+
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let (pc, regs, start, saved, expected) = suspend(&unwinder);
+        assert!(expected.len() > 1);
+
+        let mut reads = 0;
+        let memory = |addr: u64| {
+            let index = ((addr & !0b111).wrapping_sub(start) / 8) as usize;
+            match saved.get(index) {
+                Some(word) => {
+                    reads += 1;
+                    Ok(*word)
+                }
+                // The code read to recognize signal trampolines.
+                None => LocalMemory.read_u64(addr),
+            }
+        };
+        let mut cache = CacheNative::new();
+        let iter = unwinder
+            .iter_frames_with_regs(pc as usize, regs, &mut cache)
+            .with_memory(memory);
+        assert_eq!(collect(iter), expected);
+        assert!(reads > 0);
+    }
+
+    #[test]
+    fn with_sp() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache);
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = iter.try_next_with_sp() {
+            frames.push(frame);
+        }
+        assert!(frames.len() > 1);
+        let stack = crate::current_thread_stack().unwrap();
+        assert!(frames.iter().all(|(_, sp)| stack.contains(sp)));
+        // The stack grows downwards.
+        assert!(frames.windows(2).all(|pair| pair[1].1 >= pair[0].1));
+    }
+
+    #[test]
+    #[cfg(feature = "debug-frames")]
+    fn debug_frames() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache);
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = iter.try_next_debug() {
+            frames.push(frame);
+        }
+        assert!(frames.len() > 1);
+        assert!(matches!(
+            frames[0].previous,
+            FrameAddress::InstructionPointer(_)
+        ));
+        for pair in frames.windows(2) {
+            assert_eq!(pair[1].previous, pair[0].address);
+            // The stack grows downwards.
+            assert!(pair[1].cfa >= pair[0].cfa);
+        }
+    }
+
+    #[test]
+    fn stack_bounds() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+
+        let stack = crate::current_thread_stack().unwrap();
+        let local = 0;
+        assert!(stack.contains(&(&local as *const _ as usize)));
+        let mut iter = unwinder.iter_frames(&mut cache).with_stack_bounds(stack);
+        assert!(iter.try_next().unwrap().is_some());
+
+        let mut iter = unwinder.iter_frames(&mut cache).with_stack_bounds(0..0);
+        assert!(matches!(
+            iter.try_next(),
+            Err(UnwindError::StackOutOfBounds(_))
+        ));
+    }
+
+    #[test]
+    fn terminators() {
+        #[no_mangle]
+        #[inline(never)]
+        extern "C" fn runwind_test_terminator(
+            unwinder: &Unwinder<MayAllocateDuringUnwind>,
+        ) -> usize {
+            let mut cache = CacheNative::new();
+            unwinder.backtrace(&mut cache).len()
+        }
+
+        let mut unwinder = Unwinder::new();
+        assert!(runwind_test_terminator(&unwinder) > 1);
+        unwinder.set_terminators(&["runwind_test_terminator"]);
+        // Only the frame of the terminator itself is left.
+        assert_eq!(runwind_test_terminator(&unwinder), 1);
+    }
+
+    #[test]
+    fn switch_stack() {
+        fn collect(iter: &mut UnwindIterator<'_, '_, MayAllocateDuringUnwind>) -> Vec<usize> {
+            let mut frames = Vec::new();
+            while let Some(addr) = iter.try_next().unwrap() {
+                frames.push(addr);
+            }
+            frames
+        }
+
+        #[inline(never)]
+        fn in_coroutine(
+            unwinder: &Unwinder<MayAllocateDuringUnwind>,
+            (pc, regs): (u64, UnwindRegsNative),
+        ) -> (Vec<usize>, Vec<usize>) {
+            let mut cache = CacheNative::new();
+            let mut iter = unwinder.iter_frames(&mut cache);
+            let coroutine = collect(&mut iter);
+            iter.switch_stack(FrameAddress::InstructionPointer(pc), regs);
+            (coroutine, collect(&mut iter))
+        }
+
+        // The entry function of a coroutine, where its stack ends.
+        #[no_mangle]
+        #[inline(never)]
+        fn runwind_test_coroutine_entry(
+            unwinder: &Unwinder<MayAllocateDuringUnwind>,
+            resumer: (u64, UnwindRegsNative),
+        ) -> (Vec<usize>, Vec<usize>) {
+            std::hint::black_box(in_coroutine(unwinder, resumer))
+        }
+
+        let mut unwinder = Unwinder::new();
+        unwinder.set_terminators(&["runwind_test_coroutine_entry"]);
+        // The context of the function resuming the coroutine.
+        let (pc, regs) = current_regs();
+        let mut cache = CacheNative::new();
+        let resumer = collect(&mut unwinder.iter_frames_with_regs(pc as usize, regs, &mut cache));
+
+        let (coroutine, resumed) = runwind_test_coroutine_entry(&unwinder, (pc, regs));
+        // The coroutine's frames end with the return address in its entry.
+        let last = *coroutine.last().unwrap() as u64;
+        assert!(unwinder
+            .terminator_avmas
+            .iter()
+            .any(|avma| avma.contains(&(last - 1))));
+        assert_eq!(resumed[0], pc as usize);
+        assert_eq!(resumed[1..], resumer);
+    }
+
+    #[test]
+    fn opaque_objects() {
+        assert!(matches_pattern(b"libc.so*", b"libc.so.6"));
+        assert!(matches_pattern(b"*/libc.so.6", b"/usr/lib/libc.so.6"));
+        assert!(matches_pattern(b"*", b""));
+        assert!(!matches_pattern(b"libc.so*", b"libcrypto.so.3"));
+        assert!(!matches_pattern(b"libc.so", b"libc.so.6"));
+        assert!(matches_pattern(b"*lib*.so*", b"/usr/lib/libz.so.1"));
+        assert!(!matches_pattern(b"*a*b", b"aaba"));
+        // Takes exponential time if every `*` is retried.
+        let text = [b'a'; 200];
+        assert!(!matches_pattern(
+            &[b"*a".repeat(20), b"b".to_vec()].concat(),
+            &text
+        ));
+
+        let exe = std::env::current_exe().unwrap();
+        let in_exe = |addr: &usize| {
+            crate::find_object_for_addr(addr - 1).is_some_and(|obj| obj.path() == exe)
+        };
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let all = unwinder.backtrace(&mut cache);
+        assert!(all.iter().any(in_exe));
+
+        let name = exe.file_name().unwrap().to_str().unwrap();
+        unwinder.set_opaque_objects(&[name]);
+        let opaque = unwinder.backtrace(&mut cache);
+        assert_eq!(
+            opaque,
+            all.into_iter()
+                .filter(|addr| !in_exe(addr))
+                .collect::<Vec<_>>()
+        );
+        unwinder.set_opaque_objects(&[]);
+        assert!(unwinder.backtrace(&mut cache).iter().any(in_exe));
+    }
+
+    #[test]
+    fn stats() {
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let stats = unwinder.stats();
+        assert_eq!(stats.modules, unwinder.modules().count());
+        assert!(stats.eh_frame_bytes > 0);
+        #[cfg(target_os = "linux")]
+        assert!(stats.fde_count > 0);
+
+        let start = unwinder.modules().next().unwrap().avma_range.start;
+        unwinder.remove_module(start);
+        let removed = unwinder.stats();
+        assert_eq!(removed.modules, stats.modules - 1);
+        assert!(removed.eh_frame_bytes <= stats.eh_frame_bytes);
+    }
+
+    #[test]
+    fn thread_stack_ends_cleanly() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        std::thread::spawn(move || {
+            let mut cache = CacheNative::new();
+            let mut iter = unwinder.iter_frames(&mut cache);
+            while let Some(addr) = iter.try_next().unwrap() {
+                assert_ne!(addr, 0);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn stack_read_failed() {
+        struct NoMemory;
+
+        impl StackMemory for NoMemory {
+            fn read_u64(&mut self, _addr: u64) -> Result<u64, ()> {
+                Err(())
+            }
+        }
+
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache).with_memory(NoMemory);
+        assert!(matches!(
+            iter.try_next(),
+            Err(UnwindError::StackReadFailed(_))
+        ));
+    }
+
+    #[test]
+    fn remove_and_refresh_modules() {
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let expected = unwinder.backtrace(&mut cache);
+
+        let this_fn = remove_and_refresh_modules as *const () as usize;
+        let obj = *crate::get_objects()
+            .iter()
+            .find(|obj| obj.text_avma().contains(&this_fn))
+            .unwrap();
+        unwinder.remove_module(obj.text_avma().start as u64);
+        assert_ne!(unwinder.backtrace(&mut cache)[1..], expected[1..]);
+
+        unwinder.refresh();
+        assert_eq!(unwinder.backtrace(&mut cache)[1..], expected[1..]);
+    }
+
+    #[test]
+    fn plt_stub() {
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        // Pretend that this function starts with a PLT stub.
+        let stub = plt_stub as *const () as u64;
+        unwinder.stub_avmas.push(stub..stub + 16);
+        let return_addr = stub + 0x100;
+        let stack = [return_addr, 0];
+        #[cfg(target_arch = "x86_64")]
+        let regs = UnwindRegsNative::new(stub, stack.as_ptr() as u64, 0);
+        #[cfg(target_arch = "aarch64")]
+        let regs = UnwindRegsNative::new(return_addr, stack.as_ptr() as u64, 0);
+
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames_with_regs(stub as usize, regs, &mut cache);
+        assert_eq!(iter.try_next().unwrap(), Some(return_addr as usize));
+    }
+
+    #[test]
+    fn modules() {
+        let mut unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let this_fn = modules as *const () as u64;
+        let module = unwinder
+            .modules()
+            .find(|module| module.avma_range.contains(&this_fn))
+            .unwrap()
+            .clone();
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(module.name, exe.to_string_lossy());
+        assert!(module.base_avma <= module.avma_range.start);
+
+        // Replace the module with one without unwind information.
+        unwinder.remove_module(module.avma_range.start);
+        assert!(!unwinder.modules().any(|m| *m == module));
+        unwinder.add_module(
+            module.clone(),
+            ModuleSvmaInfo {
+                base_svma: 0,
+                text: None,
+                text_env: None,
+                stubs: None,
+                stub_helper: None,
+                eh_frame: None,
+                eh_frame_hdr: None,
+                got: None,
+            },
+            ModuleUnwindData::None,
+            None,
+        );
+        assert_eq!(unwinder.modules().last(), Some(&module));
+    }
+
+    #[test]
+    fn resolved_frames() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache);
+        let frame = iter.try_next_resolved().unwrap().unwrap();
+
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(
+            frame.module_name.as_deref(),
+            exe.file_name().unwrap().to_str()
+        );
+        assert!(frame.svma.unwrap() < frame.avma);
+    }
+
+    #[test]
+    #[cfg(feature = "symbolize")]
+    fn symbolicate() {
+        #[inline(never)]
+        fn capture(unwinder: &Unwinder<MayAllocateDuringUnwind>) -> Vec<usize> {
+            let mut cache = CacheNative::new();
+            unwinder.backtrace(&mut cache)
+        }
+
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let frames = capture(&unwinder);
+        let names: Vec<_> = frames
+            .iter()
+            .flat_map(|addr| Unwinder::<MayAllocateDuringUnwind>::symbolicate(*addr))
+            .filter_map(|frame| frame.function)
+            .collect();
+        assert!(names[0].ends_with("symbolicate::capture"), "{names:?}");
+    }
+
+    #[test]
+    #[cfg(feature = "symbolize")]
+    fn nested_functions() {
+        use std::hint::black_box;
+
+        use crate::Symbolizer;
+
+        // `black_box` keeps the calls from becoming tail calls, which would
+        // leave no frame behind.
+        #[inline(never)]
+        fn a<T>(f: impl FnOnce() -> T) -> T {
+            black_box(b(f))
+        }
+        #[inline(never)]
+        fn b<T>(f: impl FnOnce() -> T) -> T {
+            black_box(c(f))
+        }
+        #[inline(never)]
+        fn c<T>(f: impl FnOnce() -> T) -> T {
+            black_box(d(f))
+        }
+        #[inline(never)]
+        fn d<T>(f: impl FnOnce() -> T) -> T {
+            black_box(f())
+        }
+
+        fn assert_nested(names: &[String]) {
+            let position = |name: &str| {
+                let suffix = format!("nested_functions::{name}");
+                names
+                    .iter()
+                    .position(|function| function.ends_with(&suffix))
+                    .unwrap_or_else(|| panic!("{name} not found in {names:?}"))
+            };
+            let positions = ["d", "c", "b", "a"].map(position);
+            assert!(
+                positions.windows(2).all(|pair| pair[0] + 1 == pair[1]),
+                "{names:?}"
+            );
+        }
+
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let (frames, backtrace) = a(|| (unwinder.capture(), unwinder.capture_serializable()));
+
+        let names: Vec<_> = frames
+            .iter()
+            .flat_map(|addr| Unwinder::<MayAllocateDuringUnwind>::symbolicate(*addr))
+            .filter_map(|frame| frame.function)
+            .collect();
+        assert_nested(&names);
+
+        // Symbolicated as in another process.
+        let symbolizer = Symbolizer::new(&backtrace.modules, &[]);
+        let names: Vec<_> = backtrace
+            .frames
+            .iter()
+            .filter_map(|addr| symbolizer.resolve(*addr))
+            .flat_map(|resolved| resolved.frames)
+            .filter_map(|frame| frame.function)
+            .collect();
+        assert_nested(&names);
+    }
+
+    #[test]
+    fn max_depth() {
+        let unwinder = Unwinder::<MayAllocateDuringUnwind>::new();
+        let mut cache = CacheNative::new();
+        let mut iter = unwinder.iter_frames(&mut cache).with_max_depth(2);
+        assert!(iter.try_next().unwrap().is_some());
+        assert!(iter.try_next().unwrap().is_some());
+        assert_eq!(iter.try_next().unwrap(), None);
     }
 }